@@ -0,0 +1,47 @@
+#!/usr/bin/env python3
+# Compare the dispatch modes of bf-optimized: a `match` on each instruction, and
+# threaded code, that calls a closure built for each instruction, with and
+# without superinstructions. The binary of a previous commit can be given as a
+# baseline, like `./bench-dispatch.py 10 path/to/bf-optimized`.
+import math
+import os
+import statistics
+import subprocess
+import sys
+import time
+
+programs = [
+    ("factor", "./programs/factor.bf", b"179424691\n"),
+    ("mandelbrot", "./programs/mandelbrot.bf", b""),
+]
+
+def run(command, program, input):
+    start = time.perf_counter()
+    subprocess.run(command + [program], input=input, stdout=subprocess.DEVNULL, check=True)
+    return time.perf_counter() - start
+
+def main(iterations, baseline):
+    if os.system("cargo build -p bf-optimized --release") != 0:
+        raise Exception("cargo build failed")
+
+    commands = [
+        ("match", ["./target/release/bf-optimized"]),
+        ("match, superinstructions", ["./target/release/bf-optimized", "--superinstructions"]),
+        ("threaded", ["./target/release/bf-optimized", "--threaded"]),
+        ("threaded, superinstructions", ["./target/release/bf-optimized", "--threaded", "--superinstructions"]),
+    ]
+    if baseline is not None:
+        commands.insert(0, ("baseline", [baseline]))
+
+    print("| dispatch ^| factor.bf (s) ^| mandelbrot.bf (s)")
+    for name, command in commands:
+        row = f"| {name}"
+        for _, program, input in programs:
+            times = [run(command, program, input) for _ in range(iterations)]
+            mean = statistics.mean(times)
+            stderr = statistics.stdev(times) / math.sqrt(len(times))
+            row += f" | {mean:.3f}±{stderr:.3f}"
+        print(row, flush=True)
+
+if __name__ == "__main__":
+    main(int(sys.argv[1]) if len(sys.argv) > 1 else 20, sys.argv[2] if len(sys.argv) > 2 else None)
@@ -0,0 +1,42 @@
+#!/usr/bin/env python3
+# Compare the `wrap` boundary, that wraps the pointer with a `cmov` on each
+# move, with the `guard` boundary, that leaves the moves unchecked and catches
+# the accesses out of the tape with guard pages.
+import math
+import os
+import statistics
+import subprocess
+import sys
+import time
+
+packages = ["bf-singlepass-jit", "bf-optimized-jit", "bf-cranelift-jit"]
+boundaries = ["wrap", "guard"]
+programs = [
+    ("factor", "./programs/factor.bf", b"179424691\n"),
+    ("mandelbrot", "./programs/mandelbrot.bf", b""),
+]
+
+def run(package, boundary, program, input):
+    start = time.perf_counter()
+    subprocess.run([f"./target/release/{package}", "--boundary", boundary, program],
+        input=input, stdout=subprocess.DEVNULL, check=True)
+    return time.perf_counter() - start
+
+def main(iterations):
+    for package in packages:
+        if os.system(f"cargo build -p {package} --release") != 0:
+            raise Exception("cargo build failed")
+
+    print("| backend | boundary ^| factor.bf (s) ^| mandelbrot.bf (s)")
+    for package in packages:
+        for boundary in boundaries:
+            row = f"| {package} | {boundary}"
+            for _, program, input in programs:
+                times = [run(package, boundary, program, input) for _ in range(iterations)]
+                mean = statistics.mean(times)
+                stderr = statistics.stdev(times) / math.sqrt(len(times))
+                row += f" | {mean:.3f}±{stderr:.3f}"
+            print(row, flush=True)
+
+if __name__ == "__main__":
+    main(int(sys.argv[1]) if len(sys.argv) > 1 else 20)
@@ -0,0 +1,211 @@
+use crate::{Boundary, Config, Instruction, Ir, GUARD_SIZE};
+
+/// A range of cells, from `start` to `end` inclusive.
+#[derive(Clone, Copy, Debug)]
+struct Range {
+    start: isize,
+    end: isize,
+}
+impl Range {
+    fn offset(self, n: isize) -> Range {
+        Range {
+            start: self.start.saturating_add(n),
+            end: self.end.saturating_add(n),
+        }
+    }
+
+    fn within(self, len: isize) -> bool {
+        self.start >= 0 && self.end < len
+    }
+
+    /// Return the part of the range in `tape`, after a checked move to it,
+    /// that stops the program out of the tape. If there is none, the code
+    /// after the move never runs, and `tape` is returned.
+    fn clamp(self, tape: Range) -> Range {
+        let start = self.start.max(tape.start);
+        let end = self.end.min(tape.end);
+        if start <= end {
+            Range { start, end }
+        } else {
+            tape
+        }
+    }
+}
+
+/// Return, for each instruction of `ir`, if the moves of the pointer and the
+/// accesses to cells it does are proven to stay in the tape of `config`, so
+/// that they don't need to be checked.
+///
+/// The range of the pointer is tracked from the start of the program. It is
+/// kept across the loops that don't move the pointer in total, like
+/// `[->>+<<]`, and is only known to be in the tape after any other loop. A
+/// checked access to a cell, or a `Check`, narrows it too.
+///
+/// With `Boundary::Guard`, the moves and accesses don't need to be checked if
+/// the pointer can't get further than `GUARD_SIZE` from the tape, see
+/// `guarded`.
+pub fn in_bounds(ir: &Ir, config: &Config) -> Vec<bool> {
+    use Instruction::*;
+
+    if config.boundary == Boundary::Guard {
+        return guarded(ir, config);
+    }
+
+    let len = config.tape_size as isize;
+    // the range after a checked move. A growing tape is never smaller than
+    // `len`, but its end is not known.
+    let tape = Range {
+        start: 0,
+        end: match config.boundary {
+            Boundary::Grow => isize::MAX,
+            _ => len - 1,
+        },
+    };
+
+    let balanced = balanced_loops(ir);
+
+    let mut in_bounds = vec![true; ir.instructions.len()];
+    let mut range = Range { start: 0, end: 0 };
+    // the range at the start of each open loop.
+    let mut loop_stack = Vec::new();
+
+    for (i, &instr) in ir.instructions.iter().enumerate() {
+        match instr {
+            Move(n) => {
+                let to = range.offset(n);
+                in_bounds[i] = to.within(len);
+                range = if in_bounds[i] {
+                    to
+                } else if config.boundary == Boundary::Wrap {
+                    tape
+                } else {
+                    // the program stops if the pointer moves out of the tape.
+                    to.clamp(tape)
+                };
+            }
+            Add(offset, _)
+            | Set(offset, _)
+            | Clear(offset)
+            | Input(offset)
+            | Output(offset)
+            | Check(offset) => {
+                let to = range.offset(offset);
+                in_bounds[i] = to.within(len);
+                if !in_bounds[i] && config.boundary != Boundary::Wrap {
+                    range = to.clamp(tape).offset(-offset);
+                }
+            }
+            // the cell is only accessed if the current one is not zero.
+            MulAdd { offset, .. } => in_bounds[i] = range.offset(offset).within(len),
+            JumpRight(_) => {
+                loop_stack.push(range);
+                if !balanced[i] {
+                    range = tape;
+                }
+            }
+            JumpLeft(pair_address) => {
+                let start = loop_stack.pop().expect("unbalanced brackets");
+                range = if balanced[pair_address] { start } else { tape };
+            }
+            MoveUntil(_) => {
+                in_bounds[i] = false;
+                range = tape;
+            }
+        }
+    }
+
+    in_bounds
+}
+
+/// Return, for each instruction of `ir`, if its moves and accesses are close
+/// enough to the tape to hit a guard page of a `GuardedTape` when out of it.
+///
+/// The pointer is known to be in the tape after an access to the current cell,
+/// since it would have faulted otherwise. Each unchecked move takes it further
+/// from the last one.
+fn guarded(ir: &Ir, config: &Config) -> Vec<bool> {
+    use Instruction::*;
+
+    let bytes = config.cell_width.bytes();
+    // how far out of the tape the pointer may be, in bytes.
+    let mut drift = 0usize;
+    // the distance from the tape of the last byte of the cell at `n` cells
+    // from the pointer, if it can't skip over a guard region.
+    let reach = |drift: usize, n: isize| {
+        let reach = n
+            .unsigned_abs()
+            .saturating_mul(bytes)
+            .saturating_add(drift + bytes);
+        (reach <= GUARD_SIZE).then_some(reach - bytes)
+    };
+
+    let mut guarded = vec![true; ir.instructions.len()];
+    for (i, &instr) in ir.instructions.iter().enumerate() {
+        match instr {
+            Move(n) => match reach(drift, n) {
+                Some(reach) => drift = reach,
+                // a checked move leaves the pointer in the tape.
+                None => {
+                    guarded[i] = false;
+                    drift = 0;
+                }
+            },
+            Add(offset, _)
+            | Set(offset, _)
+            | Clear(offset)
+            | Input(offset)
+            | Output(offset)
+            | Check(offset)
+            | MulAdd { offset, .. } => {
+                guarded[i] = reach(drift, offset).is_some();
+                if offset == 0 || matches!(instr, MulAdd { .. }) {
+                    drift = 0;
+                }
+            }
+            JumpRight(_) | JumpLeft(_) => drift = 0,
+            MoveUntil(_) => {
+                guarded[i] = false;
+                drift = 0;
+            }
+        }
+    }
+
+    guarded
+}
+
+/// Return, for each `JumpRight` of `ir`, if each iteration of its loop ends
+/// with the pointer where it started.
+fn balanced_loops(ir: &Ir) -> Vec<bool> {
+    use Instruction::*;
+
+    let mut balanced = vec![false; ir.instructions.len()];
+    // the address of each open loop, the sum of its moves, and if it has no
+    // unbalanced inner loop.
+    let mut loop_stack: Vec<(usize, isize, bool)> = Vec::new();
+
+    for (i, &instr) in ir.instructions.iter().enumerate() {
+        match instr {
+            Move(n) => {
+                if let Some((_, moves, _)) = loop_stack.last_mut() {
+                    *moves += n;
+                }
+            }
+            MoveUntil(_) => {
+                if let Some((_, _, inner)) = loop_stack.last_mut() {
+                    *inner = false;
+                }
+            }
+            JumpRight(_) => loop_stack.push((i, 0, true)),
+            JumpLeft(_) => {
+                let (start, moves, inner) = loop_stack.pop().expect("unbalanced brackets");
+                balanced[start] = inner && moves == 0;
+                if let Some((_, _, outer)) = loop_stack.last_mut() {
+                    *outer &= balanced[start];
+                }
+            }
+            _ => {}
+        }
+    }
+
+    balanced
+}
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::RuntimeError;
+
+/// The number of steps between two checks of the deadline.
+const CHECK_INTERVAL: u64 = 1 << 16;
+
+/// A handle to cancel a running program from another thread.
+///
+/// The cancellation is checked with the deadline of the `Budget`, so it stops
+/// the program after a few thousand steps.
+#[derive(Clone, Default, Debug)]
+pub struct CancelHandle(Arc<AtomicBool>);
+impl CancelHandle {
+    /// Stop the program, or the next run of the program if it is not running.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Consume the cancellation, so that the next run is not cancelled.
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Limits the number of steps and the running time of a program, and allows
+/// cancelling it.
+///
+/// A step is the execution of a back-edge: the end of a loop, or an iteration
+/// of an optimized loop like `MoveUntil`. The deadline is only checked every
+/// few thousand steps, and can't interrupt a blocking read of the input.
+///
+/// The code generated by the JIT backends decrements `steps` itself, and calls
+/// `refill` when it was already 0, so it is `repr(C)` with `steps` first.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Budget {
+    /// Steps left before the next call to `refill`.
+    steps: u64,
+    /// Steps left after `steps`, if limited.
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    cancel: Option<CancelHandle>,
+}
+impl Budget {
+    /// Create a budget of `fuel` steps, that expires after `timeout`, starting
+    /// from now, or when `cancel` is triggered.
+    pub fn new(
+        fuel: Option<u64>,
+        timeout: Option<Duration>,
+        cancel: Option<CancelHandle>,
+    ) -> Budget {
+        Budget {
+            steps: 0,
+            fuel,
+            // a timeout too large to represent never expires
+            deadline: timeout.and_then(|timeout| Instant::now().checked_add(timeout)),
+            cancel,
+        }
+    }
+
+    /// Consume a step.
+    #[inline]
+    pub fn step(&mut self) -> Result<(), RuntimeError> {
+        match self.steps.checked_sub(1) {
+            Some(steps) => {
+                self.steps = steps;
+                Ok(())
+            }
+            None => self.refill(),
+        }
+    }
+
+    /// Consume a step when `steps` is 0: check the deadline and the
+    /// cancellation, and move the next steps from `fuel` to `steps`.
+    #[cold]
+    pub fn refill(&mut self) -> Result<(), RuntimeError> {
+        if let Some(cancel) = &self.cancel {
+            if cancel.take() {
+                return Err(RuntimeError::Cancelled);
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(RuntimeError::Timeout);
+            }
+        }
+
+        let steps = match &mut self.fuel {
+            Some(fuel) => {
+                let steps = (*fuel).min(CHECK_INTERVAL);
+                *fuel -= steps;
+                steps
+            }
+            None => CHECK_INTERVAL,
+        };
+        if steps == 0 {
+            return Err(RuntimeError::OutOfFuel);
+        }
+
+        self.steps = steps - 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+/// The integer types used for the cells of the tape, by the backends that
+/// are generic over the cell width.
+pub trait Cell: Copy + Default + Eq + Into<u32> {
+    /// Add `n` to the cell, wrapping around on overflow.
+    fn add_wrapping(self, n: u32) -> Self;
+    /// The value of the cell after reading `byte` from the input.
+    fn from_byte(byte: u8) -> Self;
+    /// The byte written to the output, the lower 8 bits of the cell.
+    fn to_byte(self) -> u8;
+
+    /// The index of the first zero cell in `cells`, if any.
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().position(|&cell| cell == Self::default())
+    }
+    /// The index of the last zero cell in `cells`, if any.
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().rposition(|&cell| cell == Self::default())
+    }
+}
+
+macro_rules! impl_cell {
+    ($t:ty $(, $method:item)*) => {
+        impl Cell for $t {
+            fn add_wrapping(self, n: u32) -> Self {
+                self.wrapping_add(n as $t)
+            }
+            fn from_byte(byte: u8) -> Self {
+                <$t>::from(byte)
+            }
+            fn to_byte(self) -> u8 {
+                self.to_le_bytes()[0]
+            }
+            $($method)*
+        }
+    };
+}
+
+// the searches of byte cells are vectorized by `memchr`.
+impl_cell!(
+    u8,
+    fn find_zero(cells: &[u8]) -> Option<usize> {
+        memchr::memchr(0, cells)
+    },
+    fn rfind_zero(cells: &[u8]) -> Option<usize> {
+        memchr::memrchr(0, cells)
+    }
+);
+impl_cell!(u16);
+impl_cell!(u32);
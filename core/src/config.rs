@@ -0,0 +1,227 @@
+use std::time::Duration;
+
+use crate::Cell;
+
+/// What happens when the pointer moves out of the tape.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Boundary {
+    /// Wrap around to the other end of the tape.
+    Wrap,
+    /// Stop the program with an error.
+    Error,
+    /// Grow the tape when moving right of the last cell. Moving left of the
+    /// first cell is an error.
+    Grow,
+    /// Stop the program with an error when it accesses a cell out of the
+    /// tape, caught by the guard pages of a `GuardedTape` in the JIT backends,
+    /// instead of checking each move. The tape is rounded up to a whole number
+    /// of pages, see `round_to_pages`. The other backends handle it as
+    /// `Error`.
+    Guard,
+}
+
+/// What `,` does to the current cell when the input reached its end.
+///
+/// It is `repr(C)` because it is passed to `bf_read` of the runtime library
+/// linked with the AOT compiled programs.
+#[repr(C)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Eof {
+    /// Set the cell to 0.
+    Zero,
+    /// Set the cell to -1, the maximum value of the cell.
+    MinusOne,
+    /// Leave the cell unchanged.
+    Unchanged,
+}
+impl Eof {
+    /// Update `cell` as `,` does on the end of input.
+    pub fn apply<T: Cell>(self, cell: &mut T) {
+        match self {
+            Eof::Zero => *cell = T::default(),
+            Eof::MinusOne => *cell = T::default().add_wrapping(u32::MAX),
+            Eof::Unchanged => {}
+        }
+    }
+}
+
+/// The size of each cell of the tape.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+impl CellWidth {
+    /// The size of a cell in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            CellWidth::U8 => 1,
+            CellWidth::U16 => 2,
+            CellWidth::U32 => 4,
+        }
+    }
+}
+
+/// Runtime options shared by all backends.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Number of cells in the tape. With `Boundary::Grow`, this is only the
+    /// initial size.
+    pub tape_size: usize,
+    pub boundary: Boundary,
+    pub cell_width: CellWidth,
+    pub eof: Eof,
+    /// Write each output byte immediately, instead of buffering the output.
+    /// Only the JIT and AOT backends buffer their output.
+    pub unbuffered: bool,
+    /// The maximum number of steps executed, see `Budget`.
+    pub fuel: Option<u64>,
+    /// The maximum running time of the program.
+    pub timeout: Option<Duration>,
+    /// Count the steps in the JIT backends even without `fuel` or `timeout`,
+    /// so that the program can be stopped by its `CancelHandle`. It is not
+    /// set by `from_args`.
+    pub cancellable: bool,
+    /// Print the statistics of the optimizer to stderr. Only the backends that
+    /// optimize the program print them.
+    pub stats: bool,
+    /// The maximum number of instructions run at compile time by the
+    /// compilers, before the first read of the input, see `Prefix`. 0
+    /// disables it.
+    pub prefix_steps: u64,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            tape_size: 30_000,
+            boundary: Boundary::Wrap,
+            cell_width: CellWidth::U8,
+            eof: Eof::Zero,
+            unbuffered: false,
+            fuel: None,
+            timeout: None,
+            cancellable: false,
+            stats: false,
+            prefix_steps: 1 << 20,
+        }
+    }
+}
+impl Config {
+    /// Parse the options of `Config` from the command line arguments, and
+    /// return the remaining arguments, for the caller to handle.
+    pub fn from_args(
+        mut args: impl Iterator<Item = String>,
+    ) -> Result<(Config, Vec<String>), String> {
+        let mut config = Config::default();
+        let mut rest = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tape-size" => {
+                    let value = args.next().ok_or("expected a value after --tape-size")?;
+                    // the JIT backends encode the tape size as a 32-bit immediate.
+                    config.tape_size = match value.parse::<usize>() {
+                        Ok(x) if x > 0 && x <= i32::MAX as usize => x,
+                        _ => {
+                            return Err(format!(
+                                "invalid tape size '{}', expected an integer between 1 and {}",
+                                value,
+                                i32::MAX
+                            ))
+                        }
+                    };
+                }
+                "--boundary" => {
+                    let value = args.next().ok_or("expected a value after --boundary")?;
+                    config.boundary = match value.as_str() {
+                        "wrap" => Boundary::Wrap,
+                        "error" => Boundary::Error,
+                        "grow" => Boundary::Grow,
+                        "guard" => Boundary::Guard,
+                        _ => {
+                            return Err(format!(
+                            "invalid boundary '{}', expected 'wrap', 'error', 'grow' or 'guard'",
+                            value
+                        ))
+                        }
+                    };
+                }
+                "--cell-width" => {
+                    let value = args.next().ok_or("expected a value after --cell-width")?;
+                    config.cell_width = match value.as_str() {
+                        "8" => CellWidth::U8,
+                        "16" => CellWidth::U16,
+                        "32" => CellWidth::U32,
+                        _ => {
+                            return Err(format!(
+                                "invalid cell width '{}', expected '8', '16' or '32'",
+                                value
+                            ))
+                        }
+                    };
+                }
+                "--eof" => {
+                    let value = args.next().ok_or("expected a value after --eof")?;
+                    config.eof = match value.as_str() {
+                        "zero" => Eof::Zero,
+                        "minus-one" => Eof::MinusOne,
+                        "unchanged" => Eof::Unchanged,
+                        _ => {
+                            return Err(format!(
+                                "invalid eof '{}', expected 'zero', 'minus-one' or 'unchanged'",
+                                value
+                            ))
+                        }
+                    };
+                }
+                "--unbuffered" => config.unbuffered = true,
+                "--stats" => config.stats = true,
+                "--prefix-steps" => {
+                    let value = args.next().ok_or("expected a value after --prefix-steps")?;
+                    config.prefix_steps = match value.parse::<u64>() {
+                        Ok(x) => x,
+                        _ => {
+                            return Err(format!(
+                                "invalid prefix steps '{}', expected an integer",
+                                value
+                            ))
+                        }
+                    };
+                }
+                "--fuel" => {
+                    let value = args.next().ok_or("expected a value after --fuel")?;
+                    config.fuel = match value.parse::<u64>() {
+                        Ok(x) => Some(x),
+                        _ => return Err(format!("invalid fuel '{}', expected an integer", value)),
+                    };
+                }
+                "--timeout" => {
+                    let value = args.next().ok_or("expected a value after --timeout")?;
+                    config.timeout = match value.parse::<f64>().map(Duration::try_from_secs_f64) {
+                        Ok(Ok(x)) => Some(x),
+                        _ => {
+                            return Err(format!(
+                                "invalid timeout '{}', expected a number of seconds",
+                                value
+                            ))
+                        }
+                    };
+                }
+                _ => rest.push(arg),
+            }
+        }
+
+        // the JIT backends also encode the tape size in bytes as a 32-bit
+        // immediate.
+        if config.tape_size * config.cell_width.bytes() > i32::MAX as usize {
+            return Err(format!(
+                "tape size {} is too large for {}-bit cells",
+                config.tape_size,
+                config.cell_width.bytes() * 8
+            ));
+        }
+
+        Ok((config, rest))
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt::Write;
+
+/// The location of a byte in the source. `line` and `column` start at 1, and
+/// columns are counted in characters.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct SourceLocation {
+    pub index: usize,
+    pub line: usize,
+    pub column: usize,
+}
+impl SourceLocation {
+    /// Compute the location of the byte at `index` in `source`.
+    pub fn new(source: &[u8], index: usize) -> SourceLocation {
+        let line_start = line_start(source, index);
+        SourceLocation {
+            index,
+            line: source[..line_start].iter().filter(|&&b| b == b'\n').count() + 1,
+            column: source[line_start..index]
+                .iter()
+                .filter(|&&b| !is_continuation_byte(b))
+                .count()
+                + 1,
+        }
+    }
+}
+
+fn is_continuation_byte(b: u8) -> bool {
+    b & 0xC0 == 0x80
+}
+
+fn line_start(source: &[u8], index: usize) -> usize {
+    source[..index]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |x| x + 1)
+}
+
+/// Lines longer than this are truncated in the reported snippet.
+const MAX_LINE_WIDTH: usize = 80;
+
+/// Format an error message pointing at `location`, followed by the line of
+/// source that contains it and a caret under the pointed character:
+///
+/// ```text
+/// error: unmatched `]`
+///  --> hello.bf:2:4
+///   |
+/// 2 | +++]
+///   |    ^
+/// ```
+pub fn report(file_name: &str, source: &[u8], location: SourceLocation, message: &str) -> String {
+    let line_start = line_start(source, location.index);
+    let line_end = source[line_start..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .map_or(source.len(), |x| line_start + x);
+    let line = String::from_utf8_lossy(&source[line_start..line_end]);
+    let line: Vec<char> = line.trim_end_matches('\r').chars().collect();
+
+    // show only part of long lines, around the pointed character.
+    let start = (location.column - 1).saturating_sub(MAX_LINE_WIDTH / 2);
+    let end = (start + MAX_LINE_WIDTH).min(line.len());
+    let prefix = if start > 0 { "..." } else { "" };
+    let suffix = if end < line.len() { "..." } else { "" };
+    let snippet: String = line[start..end].iter().collect();
+
+    // keep tabs in the padding, so the caret is aligned with the line above.
+    let padding: String = prefix
+        .chars()
+        .chain(line[start..location.column - 1].iter().copied())
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let line_number = location.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+
+    let mut out = String::new();
+    writeln!(out, "error: {}", message).unwrap();
+    writeln!(
+        out,
+        "{}--> {}:{}:{}",
+        gutter, file_name, location.line, location.column
+    )
+    .unwrap();
+    writeln!(out, "{} |", gutter).unwrap();
+    writeln!(out, "{} | {}{}{}", line_number, prefix, snippet, suffix).unwrap();
+    writeln!(out, "{} | {}^", gutter, padding).unwrap();
+    out
+}
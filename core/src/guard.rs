@@ -0,0 +1,276 @@
+use std::io;
+
+use memmap2::MmapMut;
+
+use crate::{Boundary, Config, RuntimeError};
+
+/// The size of the inaccessible region on each side of a `GuardedTape`, in
+/// bytes. The moves and accesses that may reach further from the tape are
+/// still checked, see `in_bounds`.
+pub const GUARD_SIZE: usize = 1 << 20;
+
+/// A tape surrounded by inaccessible guard pages, for `Boundary::Guard`.
+///
+/// The generated code doesn't check the moves of the pointer: an access to a
+/// cell out of the tape hits a guard page instead, and the SIGSEGV it raises
+/// is turned into a `RuntimeError::TapeOutOfBounds` by `GuardedTape::call`.
+///
+/// Only supported on x86-64 Linux.
+pub struct GuardedTape {
+    map: MmapMut,
+    len: usize,
+}
+impl GuardedTape {
+    /// Allocate a zeroed tape of `len` bytes, a whole number of pages, see
+    /// `round_to_pages`.
+    pub fn new(len: usize) -> io::Result<GuardedTape> {
+        let mut map = MmapMut::map_anon(GUARD_SIZE + len + GUARD_SIZE)?;
+        let start = map.as_mut_ptr();
+        unsafe {
+            sys::protect(start, GUARD_SIZE)?;
+            sys::protect(start.add(GUARD_SIZE + len), GUARD_SIZE)?;
+        }
+        Ok(GuardedTape { map, len })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.map[GUARD_SIZE..GUARD_SIZE + self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.map[GUARD_SIZE..GUARD_SIZE + self.len]
+    }
+
+    /// Call the generated `code` with the address and length of the tape, and
+    /// `context`, and return its error. If it accessed a guard page, it is
+    /// stopped and the error is a `RuntimeError::TapeOutOfBounds`.
+    ///
+    /// # Safety
+    ///
+    /// `code` must be a function with the signature `extern "sysv64" fn(*mut
+    /// u8, usize, *mut C) -> *mut RuntimeError`, that only accesses the guard
+    /// pages from its own code, and not from the functions it calls.
+    pub unsafe fn call<C>(&mut self, code: *const u8, context: *mut C) -> *mut RuntimeError {
+        let start = self.map.as_mut_ptr();
+        let end = start as usize + self.map.len();
+        sys::call(
+            start as usize..end,
+            code,
+            start.add(GUARD_SIZE),
+            self.len,
+            context.cast(),
+        )
+    }
+}
+
+/// Return `config`, with its tape rounded up to a whole number of pages if it
+/// uses `Boundary::Guard`, since a guard page can't protect a part of a page.
+pub fn round_to_pages(config: &Config) -> Config {
+    let mut config = config.clone();
+    if config.boundary == Boundary::Guard {
+        let bytes = config.cell_width.bytes();
+        let page = sys::page_size();
+        let mut len = (config.tape_size * bytes).div_ceil(page) * page;
+        // the JIT backends encode the tape size in bytes as a 32-bit immediate.
+        if len > i32::MAX as usize {
+            len -= page;
+        }
+        config.tape_size = len / bytes;
+    }
+    config
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod sys {
+    use std::arch::global_asm;
+    use std::cell::Cell;
+    use std::ffi::{c_int, c_void};
+    use std::io;
+    use std::ops::Range;
+    use std::sync::{Once, OnceLock};
+
+    use crate::RuntimeError;
+
+    // `bf_guard_enter(code, tape, len, context, stack)` calls `code(tape, len,
+    // context)`, after saving the callee-saved registers and storing the stack
+    // pointer to `stack`. `handle_segv` resumes a faulting `code` at
+    // `bf_guard_recover`, with that stack pointer, to return the error of
+    // `tape_out_of_bounds` instead.
+    global_asm!(
+        ".globl bf_guard_enter",
+        "bf_guard_enter:",
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [r8], rsp",
+        "sub rsp, 8",
+        "mov rax, rdi",
+        "mov rdi, rsi",
+        "mov rsi, rdx",
+        "mov rdx, rcx",
+        "call rax",
+        "jmp 2f",
+        ".globl bf_guard_recover",
+        "bf_guard_recover:",
+        "sub rsp, 8",
+        "call {tape_out_of_bounds}",
+        "2:",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        tape_out_of_bounds = sym tape_out_of_bounds,
+    );
+
+    extern "sysv64" fn tape_out_of_bounds() -> *mut RuntimeError {
+        Box::into_raw(Box::new(RuntimeError::TapeOutOfBounds))
+    }
+
+    extern "sysv64" {
+        fn bf_guard_enter(
+            code: *const u8,
+            tape: *mut u8,
+            len: usize,
+            context: *mut c_void,
+            stack: *mut usize,
+        ) -> *mut c_void;
+        fn bf_guard_recover();
+    }
+
+    /// The mapping of the `GuardedTape` used by the running code of the
+    /// current thread, and the stack pointer saved by `bf_guard_enter`.
+    #[derive(Clone, Copy)]
+    struct Active {
+        start: usize,
+        end: usize,
+        stack: *const usize,
+    }
+
+    thread_local! {
+        static ACTIVE: Cell<Option<Active>> = const { Cell::new(None) };
+    }
+
+    /// The SIGSEGV handler replaced by `handle_segv`.
+    static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+    pub unsafe fn protect(start: *mut u8, len: usize) -> io::Result<()> {
+        match libc::mprotect(start.cast(), len, libc::PROT_NONE) {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    pub unsafe fn call(
+        map: Range<usize>,
+        code: *const u8,
+        tape: *mut u8,
+        len: usize,
+        context: *mut c_void,
+    ) -> *mut RuntimeError {
+        install_handler();
+
+        let mut stack = 0;
+        ACTIVE.set(Some(Active {
+            start: map.start,
+            end: map.end,
+            stack: &stack,
+        }));
+        let error = bf_guard_enter(code, tape, len, context, &mut stack);
+        ACTIVE.set(None);
+        error.cast()
+    }
+
+    fn install_handler() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| unsafe {
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGSEGV, std::ptr::null(), &mut previous);
+            PREVIOUS.get_or_init(|| previous);
+
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_segv as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(libc::SIGSEGV, &action, std::ptr::null_mut());
+        });
+    }
+
+    unsafe extern "C" fn handle_segv(
+        signal: c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut c_void,
+    ) {
+        let address = (*info).si_addr() as usize;
+        match ACTIVE.get() {
+            Some(active) if (active.start..active.end).contains(&address) => {
+                let context = &mut *(context as *mut libc::ucontext_t);
+                let registers = &mut context.uc_mcontext.gregs;
+                registers[libc::REG_RSP as usize] = *active.stack as i64;
+                registers[libc::REG_RIP as usize] = bf_guard_recover as *const () as i64;
+            }
+            // not a fault of the generated code: forward it to the previous
+            // handler, without reinstalling it, so that the later faults of the
+            // tapes are still handled.
+            _ => {
+                let previous = PREVIOUS.get().expect("installed with the handler");
+                match previous.sa_sigaction {
+                    // the instruction faults again when the handler returns,
+                    // and stops the process.
+                    libc::SIG_DFL | libc::SIG_IGN => {
+                        libc::sigaction(signal, previous, std::ptr::null_mut());
+                    }
+                    handler if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+                        let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                            std::mem::transmute(handler);
+                        handler(signal, info, context);
+                    }
+                    handler => {
+                        let handler: extern "C" fn(c_int) = std::mem::transmute(handler);
+                        handler(signal);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+mod sys {
+    use std::ffi::c_void;
+    use std::io;
+    use std::ops::Range;
+
+    use crate::RuntimeError;
+
+    pub unsafe fn protect(_start: *mut u8, _len: usize) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "guard pages are only supported on x86-64 Linux",
+        ))
+    }
+
+    pub fn page_size() -> usize {
+        4096
+    }
+
+    pub unsafe fn call(
+        _map: Range<usize>,
+        _code: *const u8,
+        _tape: *mut u8,
+        _len: usize,
+        _context: *mut c_void,
+    ) -> *mut RuntimeError {
+        unreachable!("a GuardedTape can't be created")
+    }
+}
@@ -0,0 +1,79 @@
+//! Parser, intermediate representation and optimization passes shared by all
+//! the brainfuck implementations in this repository.
+
+mod bounds;
+mod budget;
+mod cell;
+mod config;
+mod diagnostic;
+mod guard;
+mod optimize;
+mod output;
+mod parse;
+mod prefix;
+mod runtime;
+
+pub use bounds::in_bounds;
+pub use budget::{Budget, CancelHandle};
+pub use cell::Cell;
+pub use config::{Boundary, CellWidth, Config, Eof};
+pub use diagnostic::{report, SourceLocation};
+pub use guard::{round_to_pages, GuardedTape, GUARD_SIZE};
+pub use optimize::{optimize, Stats};
+pub use output::Output;
+pub use parse::{parse, UnbalancedBrackets};
+pub use prefix::Prefix;
+pub use runtime::{
+    find_zero_left, find_zero_right, grow_tape, pointer_overflow, pointer_underflow, refill_budget,
+    RuntimeError, TapeSlice, Trap,
+};
+
+/// A list of instructions, and the byte index in the source where each one
+/// starts, used for reporting runtime errors.
+#[derive(Clone, Debug, Default)]
+pub struct Ir {
+    pub instructions: Vec<Instruction>,
+    pub positions: Vec<usize>,
+}
+impl Ir {
+    fn push(&mut self, instr: Instruction, position: usize) {
+        self.instructions.push(instr);
+        self.positions.push(position);
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Instruction {
+    /// Add a value to the cell at the given offset from the pointer, wrapping
+    /// around on overflow. Cells smaller than 32 bits only use the lower bits
+    /// of the value.
+    Add(isize, u32),
+    /// Move the pointer by the given amount, wrapping around the tape.
+    Move(isize),
+    /// Read a byte into the cell at the given offset from the pointer.
+    Input(isize),
+    /// Write the cell at the given offset from the pointer.
+    Output(isize),
+    /// Jump to the paired `JumpLeft` if the current cell is zero.
+    JumpRight(usize),
+    /// Jump to the paired `JumpRight` if the current cell is not zero.
+    JumpLeft(usize),
+    /// `[-]+++`: set the cell at the given offset from the pointer to a value.
+    /// Cells smaller than 32 bits only use the lower bits of the value.
+    Set(isize, u32),
+    /// `[-]`: set the cell at the given offset from the pointer to zero.
+    Clear(isize),
+    /// `[->+++<]`: add the current cell times `factor` to the cell at
+    /// `offset`, wrapping around on overflow. It is followed by a `Clear` of
+    /// the current cell.
+    ///
+    /// Like the loop it replaces, it does nothing if the current cell is zero,
+    /// even if the cell at `offset` is out of the tape. With a `factor` of 0,
+    /// it only checks that the cell is in the tape.
+    MulAdd { offset: isize, factor: u32 },
+    /// `[>]`: move the pointer by the given amount until a zero cell is found.
+    MoveUntil(isize),
+    /// `>><<`: check that the cell at the given offset from the pointer is in
+    /// the tape, as a move of the pointer to it would, without moving it.
+    Check(isize),
+}
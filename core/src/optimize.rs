@@ -0,0 +1,592 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{Boundary, Config, Instruction, Ir};
+
+/// Counts of the instructions before and after `optimize`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// The number of instructions given to `optimize`.
+    pub input: usize,
+    /// The number of instructions returned by `optimize`.
+    pub output: usize,
+    /// The number of input instructions removed because they had no effect,
+    /// or could never run.
+    pub removed: usize,
+}
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "optimizer stats:")?;
+        writeln!(f, "  input instructions: {}", self.input)?;
+        writeln!(f, "  output instructions: {}", self.output)?;
+        writeln!(f, "  removed instructions: {}", self.removed)
+    }
+}
+
+/// Merge runs of `Add` and `Move`, and replace common loop patterns by
+/// specialized instructions.
+///
+/// The moves of the pointer are sunk to the end of each basic block, and the
+/// instructions before them act on the cell at an offset from the pointer
+/// instead. Unless the tape wraps around, a merged move that may leave the
+/// tape is still checked, by the access to its cell or by a `Check`, in the
+/// order of the moves, so that the error is reported at the same position.
+///
+/// The values of the cells are tracked through straight-line code, starting
+/// from the zeroed tape: an `Add` to a known cell becomes a `Set`, a `MulAdd`
+/// from a known cell becomes an `Add`, and the loops whose tests are known
+/// statically are removed, or replaced by their body if it runs once. Adds
+/// that cancel out, and the code after a loop that never exits, are removed
+/// too. Only the cells known to be in the tape are known to be zero.
+///
+/// Expects the brackets in `ir` to be balanced, as returned by
+/// [`crate::parse`]. The values are tracked for the tape of `config`.
+pub fn optimize(ir: &Ir, config: &Config) -> (Ir, Stats) {
+    use Instruction::*;
+
+    let mut output = Ir::default();
+    let mut removed = 0;
+
+    // the address of each open loop, and the cells known at its entry.
+    let mut bracket_stack: Vec<(usize, Cells)> = Vec::new();
+
+    // the sum of the moves not emitted yet, and the position of the first one.
+    let mut offset = 0;
+    let mut move_position = 0;
+    // the offset and position of the moves not emitted yet that may leave the
+    // tape, see `emit_checks`.
+    let mut checks: Vec<(isize, usize)> = Vec::new();
+
+    // the number of input instructions merged in the last `Add` or `Set`,
+    // the value of its cell before it, and if it checks a move.
+    let mut add_len = 0;
+    let mut prior = Value::Unknown;
+    let mut add_check = false;
+
+    let mut cells = Cells::zeroed(config);
+    let guard = config.boundary == Boundary::Guard;
+
+    let mut i = 0;
+    while i < ir.instructions.len() {
+        let (instr, position) = (ir.instructions[i], ir.positions[i]);
+        i += 1;
+
+        let instr = match instr {
+            Add(_, inc) => {
+                let value = cells.get(offset);
+                cells.add(offset, inc);
+                // if merged with the last instruction, if the cell is back to
+                // its value before it.
+                let no_op = match output.instructions.last_mut() {
+                    Some(Add(to, n)) if *to == offset => {
+                        *n = n.wrapping_add(inc);
+                        Some(*n == 0)
+                    }
+                    Some(Set(to, n)) if *to == offset => {
+                        *n = n.wrapping_add(inc);
+                        Some(prior == Value::Const(*n))
+                    }
+                    // `[-]+++`: the clear and the add are fused in a `Set`.
+                    Some(last) if *last == Clear(offset) => {
+                        *last = Set(offset, inc);
+                        // the value before the clear is not known.
+                        prior = Value::Unknown;
+                        add_len = 0;
+                        add_check = false;
+                        Some(false)
+                    }
+                    _ => None,
+                };
+                if let Some(no_op) = no_op {
+                    add_len += 1;
+                    if no_op && add_check {
+                        // the move to the cell is still checked.
+                        *output.instructions.last_mut().unwrap() = Check(offset);
+                        add_check = false;
+                        removed += add_len;
+                    } else if no_op {
+                        output.instructions.pop();
+                        output.positions.pop();
+                        removed += add_len;
+                    }
+                    continue;
+                }
+                add_len = 1;
+                prior = value;
+                match value {
+                    Value::Const(c) => Set(offset, c.wrapping_add(inc)),
+                    _ => Add(offset, inc),
+                }
+            }
+            Move(inc) => {
+                if offset == 0 {
+                    move_position = position;
+                }
+                offset += inc;
+                if cells.visit(offset) {
+                    checks.push((offset, position));
+                }
+                continue;
+            }
+            Input(_) => {
+                cells.set(offset, Value::Unknown);
+                Input(offset)
+            }
+            Output(_) => Output(offset),
+            JumpRight(pair) => {
+                // the test of the loop reads the cell, so a move out of the
+                // tape before it is still reported.
+                emit_move(
+                    &mut output,
+                    &mut offset,
+                    move_position,
+                    &mut cells,
+                    &mut checks,
+                    guard,
+                );
+                if cells.is_zero(0) {
+                    // the loop is never entered.
+                    removed += pair + 2 - i;
+                    i = pair + 1;
+                    continue;
+                }
+
+                bracket_stack.push((output.instructions.len(), cells.clone()));
+                // the body may run any number of times.
+                cells.forget();
+                cells.set(0, Value::NonZero);
+                // will be fixup at the pair ']'.
+                JumpRight(0)
+            }
+            JumpLeft(_) => {
+                emit_move(
+                    &mut output,
+                    &mut offset,
+                    move_position,
+                    &mut cells,
+                    &mut checks,
+                    guard,
+                );
+                let curr_address = output.instructions.len();
+                let (pair_address, mut entry) = bracket_stack.pop().expect("unbalanced brackets");
+                output.instructions[pair_address] = JumpRight(curr_address);
+
+                if let Some((len, instrs)) = peephole(&output, &cells) {
+                    // the loop is replaced by the instructions.
+                    let start = output.instructions.len() - len;
+                    output.instructions.truncate(start);
+                    output.positions.truncate(start);
+
+                    // a clear doesn't end the basic block, so the move before
+                    // the loop can be sunk after it. The clear checks it
+                    // instead.
+                    if let ([(Clear(0), _)], Some(&Move(n))) =
+                        (instrs.as_slice(), output.instructions.last())
+                    {
+                        output.instructions.pop();
+                        move_position = output.positions.pop().unwrap();
+                        offset = n;
+                        cells = entry;
+                        cells.shift(-n);
+                        cells.set(offset, Value::Const(0));
+                        output.push(Clear(offset), move_position);
+                        continue;
+                    }
+
+                    cells = entry;
+                    for (instr, position) in instrs {
+                        if let Some(instr) = cells.apply(instr) {
+                            output.push(instr, position);
+                        }
+                    }
+                    continue;
+                }
+
+                let body = &output.instructions[pair_address + 1..];
+                if entry.is_non_zero(0) && never_exits(body, &cells) {
+                    output.push(JumpLeft(pair_address), position);
+
+                    // the rest of the enclosing loop body never runs, but the
+                    // code after it runs if the enclosing loop is skipped.
+                    let skipped = loop_end(&ir.instructions[i..]);
+                    removed += skipped;
+                    i += skipped;
+                    cells.forget();
+                    continue;
+                }
+
+                if entry.is_non_zero(0) && cells.is_zero(0) {
+                    // the body runs once, and the cells known at its end are
+                    // still valid.
+                    output.instructions.remove(pair_address);
+                    output.positions.remove(pair_address);
+                    for instr in &mut output.instructions[pair_address..] {
+                        if let JumpRight(address) | JumpLeft(address) = instr {
+                            *address -= 1;
+                        }
+                    }
+                    removed += 2;
+                    continue;
+                }
+
+                // without moves in the body, only the cells it writes to are
+                // changed.
+                if body
+                    .iter()
+                    .any(|instr| matches!(instr, Move(_) | MoveUntil(_)))
+                {
+                    entry.forget();
+                }
+                for instr in body {
+                    match *instr {
+                        Add(offset, _) | Set(offset, _) | Input(offset) | Clear(offset) => {
+                            entry.set(offset, Value::Unknown)
+                        }
+                        MulAdd { offset, .. } => entry.set(offset, Value::Unknown),
+                        _ => {}
+                    }
+                }
+                cells = entry;
+                cells.set(0, Value::Const(0));
+
+                JumpLeft(pair_address)
+            }
+            instr => instr,
+        };
+        let access = match instr {
+            Add(offset, _) | Set(offset, _) | Input(offset) | Output(offset) => offset,
+            _ => 0,
+        };
+        // an access to a cell out of the tape fails at the move to it.
+        let check = emit_checks(&mut output, &mut checks, access);
+        add_check = check.is_some();
+        output.push(instr, check.unwrap_or(position));
+    }
+    emit_move(
+        &mut output,
+        &mut offset,
+        move_position,
+        &mut cells,
+        &mut checks,
+        guard,
+    );
+
+    let stats = Stats {
+        input: ir.instructions.len(),
+        output: output.instructions.len(),
+        removed,
+    };
+    (output, stats)
+}
+
+/// What is known about the value of a cell.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Value {
+    /// The value is known. Cells smaller than 32 bits only use the lower bits.
+    Const(u32),
+    NonZero,
+    Unknown,
+}
+
+/// What is known about the values of the cells, at offsets from the pointer of
+/// the emitted instructions.
+#[derive(Clone, Debug)]
+struct Cells {
+    /// If the cells missing from `values` are zero, as at the start of the
+    /// program, instead of unknown.
+    zeroed: bool,
+    values: HashMap<isize, Value>,
+    /// The number of cells of the tape, if the offsets wrap around it.
+    wrap: Option<isize>,
+    /// The lowest and highest offsets of the cells known to be in the tape,
+    /// or `None` if there is no limit, when the tape wraps around or grows.
+    low: Option<isize>,
+    high: Option<isize>,
+}
+impl Cells {
+    /// The cells at the start of the program, with the pointer on the first
+    /// cell of the tape of `config`.
+    fn zeroed(config: &Config) -> Cells {
+        let len = config.tape_size as isize;
+        let (low, high) = match config.boundary {
+            Boundary::Wrap => (None, None),
+            Boundary::Grow => (Some(0), None),
+            Boundary::Error | Boundary::Guard => (Some(0), Some(len - 1)),
+        };
+        Cells {
+            zeroed: true,
+            values: HashMap::new(),
+            // the offsets wrap around the tape, so different offsets can be
+            // the same cell.
+            wrap: (config.boundary == Boundary::Wrap).then_some(len),
+            low,
+            high,
+        }
+    }
+
+    /// Forget all the values, after code that may change any cell, and where
+    /// the tape is, after code that may move the pointer.
+    fn forget(&mut self) {
+        self.zeroed = false;
+        self.values.clear();
+        self.low = self.low.map(|_| 0);
+        self.high = self.high.map(|_| 0);
+    }
+
+    /// The key in `values` of the cell at `offset`.
+    fn key(&self, offset: isize) -> isize {
+        match self.wrap {
+            Some(len) => offset.rem_euclid(len),
+            None => offset,
+        }
+    }
+
+    /// Return true if the cell at `offset` is known to be in the tape.
+    fn in_tape(&self, offset: isize) -> bool {
+        self.low.is_none_or(|low| low <= offset) && self.high.is_none_or(|high| offset <= high)
+    }
+
+    /// Update after the pointer moved to the cell at `offset`, and return true
+    /// if the move may leave the tape, so that it must be checked.
+    fn visit(&mut self, offset: isize) -> bool {
+        if self.in_tape(offset) {
+            return false;
+        }
+        if offset < 0 {
+            self.low = Some(offset);
+        } else {
+            self.high = Some(offset);
+        }
+        true
+    }
+
+    fn get(&self, offset: isize) -> Value {
+        match self.values.get(&self.key(offset)) {
+            Some(&value) => value,
+            None if self.zeroed && self.in_tape(offset) => Value::Const(0),
+            None => Value::Unknown,
+        }
+    }
+
+    fn set(&mut self, offset: isize, value: Value) {
+        self.values.insert(self.key(offset), value);
+    }
+
+    /// Return true if the cell is zero, for any cell width.
+    fn is_zero(&self, offset: isize) -> bool {
+        self.get(offset) == Value::Const(0)
+    }
+
+    /// Return true if the cell is not zero, for any cell width.
+    fn is_non_zero(&self, offset: isize) -> bool {
+        match self.get(offset) {
+            Value::Const(c) => c as u8 != 0,
+            Value::NonZero => true,
+            Value::Unknown => false,
+        }
+    }
+
+    /// Update after adding `n` to the cell at `offset`.
+    fn add(&mut self, offset: isize, n: u32) {
+        let value = match self.get(offset) {
+            Value::Const(c) => Value::Const(c.wrapping_add(n)),
+            _ => Value::Unknown,
+        };
+        self.set(offset, value);
+    }
+
+    /// Update after moving the pointer by `n`.
+    fn shift(&mut self, n: isize) {
+        let values = std::mem::take(&mut self.values);
+        self.values = values
+            .into_iter()
+            .map(|(k, v)| (self.key(k - n), v))
+            .collect();
+        self.low = self.low.map(|low| low - n);
+        self.high = self.high.map(|high| high - n);
+    }
+
+    /// Update after an instruction replacing a loop, and return the
+    /// instruction simplified with the known values, if it is still needed.
+    fn apply(&mut self, instr: Instruction) -> Option<Instruction> {
+        use Instruction::*;
+
+        match instr {
+            MulAdd { offset, factor } => {
+                let n = match self.get(0) {
+                    // the loop runs, since the cell is not zero in any width.
+                    Value::Const(c) if c as u8 != 0 => c.wrapping_mul(factor),
+                    _ if factor == 0 => return (!self.in_tape(offset)).then_some(instr),
+                    _ => {
+                        self.set(offset, Value::Unknown);
+                        return Some(instr);
+                    }
+                };
+                let value = self.get(offset);
+                let check = self.visit(offset);
+                match value {
+                    _ if n == 0 => check.then_some(Check(offset)),
+                    Value::Const(d) => {
+                        self.set(offset, Value::Const(d.wrapping_add(n)));
+                        Some(Set(offset, d.wrapping_add(n)))
+                    }
+                    _ => {
+                        self.set(offset, Value::Unknown);
+                        Some(Add(offset, n))
+                    }
+                }
+            }
+            Clear(offset) => {
+                self.set(offset, Value::Const(0));
+                Some(instr)
+            }
+            MoveUntil(_) => {
+                self.forget();
+                self.set(0, Value::Const(0));
+                Some(instr)
+            }
+            _ => unreachable!("not emitted by `peephole`"),
+        }
+    }
+}
+
+/// Push the checks and the moves not emitted yet to `output`, if any.
+fn emit_move(
+    output: &mut Ir,
+    offset: &mut isize,
+    position: usize,
+    cells: &mut Cells,
+    checks: &mut Vec<(isize, usize)>,
+    guard: bool,
+) {
+    // out of a guarded tape, only the accesses are checked, and not the moves.
+    let to = if guard { 0 } else { *offset };
+    let position = emit_checks(output, checks, to).unwrap_or(position);
+    if *offset != 0 {
+        output.push(Instruction::Move(*offset), position);
+        cells.shift(*offset);
+        *offset = 0;
+    }
+}
+
+/// Push a `Check` for each move in `checks`, before an instruction that
+/// accesses the cell at `offset`. If the last move is to that cell, the
+/// instruction checks it instead, and the position of the move is returned.
+fn emit_checks(output: &mut Ir, checks: &mut Vec<(isize, usize)>, offset: isize) -> Option<usize> {
+    let last = match checks.last() {
+        Some(&(to, position)) if to == offset => {
+            checks.pop();
+            Some(position)
+        }
+        _ => None,
+    };
+    for (to, position) in checks.drain(..) {
+        output.push(Instruction::Check(to), position);
+    }
+    last
+}
+
+/// Return true if a loop with this body never changes the current cell, so
+/// that it never exits once entered. The offsets are compared as the keys of
+/// `cells`, since they may wrap around to the current cell.
+fn never_exits(body: &[Instruction], cells: &Cells) -> bool {
+    use Instruction::*;
+
+    body.iter().all(|instr| match *instr {
+        Add(offset, _) | Set(offset, _) | Input(offset) | Clear(offset) => cells.key(offset) != 0,
+        Output(_) | Check(_) => true,
+        _ => false,
+    })
+}
+
+/// Return the number of instructions before the `JumpLeft` that closes the
+/// enclosing loop, or before the end of the program if there is none.
+fn loop_end(instructions: &[Instruction]) -> usize {
+    use Instruction::*;
+
+    let mut depth = 0;
+    for (i, instr) in instructions.iter().enumerate() {
+        match instr {
+            JumpRight(_) => depth += 1,
+            JumpLeft(_) if depth == 0 => return i,
+            JumpLeft(_) => depth -= 1,
+            _ => {}
+        }
+    }
+    instructions.len()
+}
+
+/// Try to replace the loop at the end of `ir`, whose `JumpLeft` was not pushed
+/// yet. Return how many instructions should be removed, and the instructions
+/// that replace them, with their positions.
+fn peephole(ir: &Ir, cells: &Cells) -> Option<(usize, Vec<(Instruction, usize)>)> {
+    use Instruction::*;
+
+    let position = |back: usize| ir.positions[ir.positions.len() - back];
+    match ir.instructions[..] {
+        // could enter a infinite loop if n is even.
+        [.., JumpRight(_), Add(0, n)] if n % 2 == 1 => Some((2, vec![(Clear(0), position(2))])),
+        [.., JumpRight(_), Move(n)] => Some((2, vec![(MoveUntil(n), position(1))])),
+        _ => multiply_loop(ir, cells),
+    }
+}
+
+/// Replace a loop that only adds to cells, without moving the pointer, and
+/// decrements the current cell once per iteration, like `[->++>+<<]`, by a
+/// `MulAdd` for each other cell and a `Clear`.
+///
+/// The `MulAdd`s are in the order of the first access to their cell in the
+/// loop, at its position, so that they check the moves out of the tape like
+/// the loop. Unless the tape wraps around, the ones that add nothing are kept
+/// for that.
+///
+/// The loop is kept if an offset wraps around to the current cell, or to the
+/// same cell as another offset, as given by the keys of `cells`.
+fn multiply_loop(ir: &Ir, cells: &Cells) -> Option<(usize, Vec<(Instruction, usize)>)> {
+    use Instruction::*;
+
+    let start = ir
+        .instructions
+        .iter()
+        .rposition(|instr| matches!(instr, JumpRight(_)))?;
+
+    let mut step = 0u32;
+    let mut factors: Vec<(isize, u32, usize)> = Vec::new();
+    let body = ir.instructions.iter().zip(&ir.positions).skip(start + 1);
+    for (&instr, &position) in body {
+        let (offset, n) = match instr {
+            Add(0, n) => {
+                step = step.wrapping_add(n);
+                continue;
+            }
+            Add(offset, n) => (offset, n),
+            Check(offset) => (offset, 0),
+            // moves, I/O or nested loops.
+            _ => return None,
+        };
+        let key = cells.key(offset);
+        if key == 0
+            || factors
+                .iter()
+                .any(|&(to, ..)| to != offset && cells.key(to) == key)
+        {
+            return None;
+        }
+        match factors.iter_mut().find(|(to, ..)| *to == offset) {
+            Some((_, factor, _)) => *factor = factor.wrapping_add(n),
+            None => factors.push((offset, n, position)),
+        }
+    }
+    if step != u32::MAX {
+        return None;
+    }
+
+    let mut instrs: Vec<_> = factors
+        .into_iter()
+        .filter(|&(_, factor, _)| factor != 0 || cells.wrap.is_none())
+        .map(|(offset, factor, position)| (MulAdd { offset, factor }, position))
+        .collect();
+    instrs.push((Clear(0), ir.positions[start]));
+
+    Some((ir.instructions.len() - start, instrs))
+}
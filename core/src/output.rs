@@ -0,0 +1,49 @@
+use std::io::{self, Write};
+
+/// How many bytes are buffered before they are written to the inner writer.
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Buffers the output of a program, used by the JIT backends.
+///
+/// The buffer is flushed when full, after each newline if `flush_on_newline`
+/// is set, after each byte if `unbuffered` is set, and when [`Output::flush`]
+/// is called, which must happen before reading the input and when the program
+/// ends.
+pub struct Output<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    flush_on_newline: bool,
+    unbuffered: bool,
+}
+impl<W: Write> Output<W> {
+    pub fn new(inner: W, unbuffered: bool, flush_on_newline: bool) -> Self {
+        Output {
+            inner,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            flush_on_newline,
+            unbuffered,
+        }
+    }
+
+    /// Write a byte to the buffer, flushing it when needed.
+    pub fn write(&mut self, value: u8) -> io::Result<()> {
+        self.buffer.push(value);
+        if self.unbuffered
+            || self.buffer.len() >= BUFFER_SIZE
+            || (self.flush_on_newline && value == b'\n')
+        {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write all buffered bytes to the inner writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let result = self
+            .inner
+            .write_all(&self.buffer)
+            .and_then(|_| self.inner.flush());
+        self.buffer.clear();
+        result
+    }
+}
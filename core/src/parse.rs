@@ -0,0 +1,73 @@
+use crate::{diagnostic, Instruction, Ir, SourceLocation};
+
+/// The brackets without a pair, in the order they appear in the source.
+#[derive(Debug)]
+pub struct UnbalancedBrackets(pub Vec<(char, SourceLocation)>);
+impl UnbalancedBrackets {
+    /// Format a diagnostic for each unpaired bracket, pointing at its line and
+    /// column in `source`.
+    pub fn report(&self, file_name: &str, source: &[u8]) -> String {
+        self.0
+            .iter()
+            .map(|&(c, location)| {
+                let message = format!("unmatched `{}`", c);
+                diagnostic::report(file_name, source, location, &message)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Parse the source into a list of instructions, one for each brainfuck
+/// command, with the address of each bracket pair resolved. All the
+/// instructions act on the current cell, at offset 0.
+pub fn parse(source: &[u8]) -> Result<Ir, UnbalancedBrackets> {
+    let mut ir = Ir::default();
+    let mut bracket_stack = Vec::new();
+    let mut unpaired = Vec::new();
+
+    for (i, b) in source.iter().enumerate() {
+        let instr = match b {
+            b'+' => Instruction::Add(0, 1),
+            b'-' => Instruction::Add(0, 1u32.wrapping_neg()),
+            b'.' => Instruction::Output(0),
+            b',' => Instruction::Input(0),
+            b'>' => Instruction::Move(1),
+            b'<' => Instruction::Move(-1),
+            b'[' => {
+                bracket_stack.push((ir.instructions.len(), i));
+                // will be fixup at the pair ']'.
+                Instruction::JumpRight(0)
+            }
+            b']' => {
+                let curr_address = ir.instructions.len();
+                match bracket_stack.pop() {
+                    Some((pair_address, _)) => {
+                        ir.instructions[pair_address] = Instruction::JumpRight(curr_address);
+                        Instruction::JumpLeft(pair_address)
+                    }
+                    None => {
+                        // keep parsing, to report all unpaired brackets at once.
+                        unpaired.push((']', i));
+                        continue;
+                    }
+                }
+            }
+            _ => continue,
+        };
+        ir.push(instr, i);
+    }
+
+    unpaired.extend(bracket_stack.into_iter().map(|(_, i)| ('[', i)));
+
+    if !unpaired.is_empty() {
+        unpaired.sort_by_key(|&(_, i)| i);
+        let unpaired = unpaired
+            .into_iter()
+            .map(|(c, i)| (c, SourceLocation::new(source, i)))
+            .collect();
+        return Err(UnbalancedBrackets(unpaired));
+    }
+
+    Ok(ir)
+}
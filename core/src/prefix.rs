@@ -0,0 +1,148 @@
+use crate::{Cell, CellWidth, Config, Instruction, Ir};
+
+/// The state of a program after running its start at compile time, up to the
+/// first read of the input.
+///
+/// The code generated for the program starts at `resume`, with the tape and
+/// the pointer of the prefix, after writing its output. The instruction at
+/// `resume` may be inside a loop.
+#[derive(Clone, Debug, Default)]
+pub struct Prefix {
+    /// The index of the first instruction not run.
+    pub resume: usize,
+    /// The index of the current cell.
+    pub pointer: usize,
+    /// The start of the tape, in bytes, with cells larger than a byte stored
+    /// in little-endian. The cells after it are zero.
+    pub tape: Vec<u8>,
+    /// The bytes written by the prefix.
+    pub output: Vec<u8>,
+}
+impl Prefix {
+    /// Run `ir` until it reads the input, ends, or ran `config.prefix_steps`
+    /// instructions. It also stops before moving out of the tape, so that the
+    /// error, or the growth of the tape, happens at runtime.
+    ///
+    /// Nothing is run when `config.fuel` is set, since the steps of the prefix
+    /// would not be counted.
+    pub fn evaluate(ir: &Ir, config: &Config) -> Prefix {
+        match config.cell_width {
+            CellWidth::U8 => evaluate::<u8>(ir, config),
+            CellWidth::U16 => evaluate::<u16>(ir, config),
+            CellWidth::U32 => evaluate::<u32>(ir, config),
+        }
+    }
+}
+
+fn evaluate<T: Cell>(ir: &Ir, config: &Config) -> Prefix {
+    use Instruction::*;
+
+    let mut steps = match config.fuel {
+        Some(_) => 0,
+        None => config.prefix_steps,
+    };
+
+    let mut memory = vec![T::default(); config.tape_size];
+    let mut pointer = 0;
+    let mut output = Vec::new();
+
+    // the index of the cell at `offset` from `pointer`, if in the tape.
+    let at = |pointer: usize, offset: isize| -> Option<usize> {
+        let to = pointer.checked_add_signed(offset)?;
+        (to < config.tape_size).then_some(to)
+    };
+
+    let mut pc = 0;
+    while pc < ir.instructions.len() && steps > 0 {
+        steps -= 1;
+        match ir.instructions[pc] {
+            Add(offset, n) => match at(pointer, offset) {
+                Some(cell) => memory[cell] = memory[cell].add_wrapping(n),
+                None => break,
+            },
+            Set(offset, n) => match at(pointer, offset) {
+                Some(cell) => memory[cell] = T::default().add_wrapping(n),
+                None => break,
+            },
+            Clear(offset) => match at(pointer, offset) {
+                Some(cell) => memory[cell] = T::default(),
+                None => break,
+            },
+            // the loop replaced by `MulAdd` doesn't access the cell at
+            // `offset` if the current cell is zero.
+            MulAdd { .. } if memory[pointer] == T::default() => {}
+            MulAdd { offset, factor } => match at(pointer, offset) {
+                Some(cell) => {
+                    let value: u32 = memory[pointer].into();
+                    memory[cell] = memory[cell].add_wrapping(value.wrapping_mul(factor));
+                }
+                None => break,
+            },
+            Move(n) => match at(pointer, n) {
+                Some(cell) => pointer = cell,
+                None => break,
+            },
+            Output(offset) => match at(pointer, offset) {
+                Some(cell) => output.push(memory[cell].to_byte()),
+                None => break,
+            },
+            Check(offset) => {
+                if at(pointer, offset).is_none() {
+                    break;
+                }
+            }
+            Input(_) => break,
+            JumpRight(pair_address) => {
+                if memory[pointer] == T::default() {
+                    pc = pair_address;
+                }
+            }
+            JumpLeft(pair_address) => {
+                if memory[pointer] != T::default() {
+                    pc = pair_address;
+                }
+            }
+            MoveUntil(n) => {
+                // an instruction is run entirely, or not at all.
+                let mut to = Some(pointer);
+                while let Some(cell) = to.filter(|&cell| memory[cell] != T::default()) {
+                    to = at(cell, n);
+                    steps = steps.saturating_sub(1);
+                }
+                match to {
+                    Some(cell) if steps > 0 => pointer = cell,
+                    _ => break,
+                }
+            }
+        }
+        pc += 1;
+    }
+
+    // only store the start of the tape, that may be much smaller.
+    let len = memory
+        .iter()
+        .rposition(|&cell| cell != T::default())
+        .map_or(0, |i| i + 1);
+    let mut tape: Vec<u8> = memory[..len]
+        .iter()
+        .flat_map(|&cell| {
+            let value: u32 = cell.into();
+            value
+                .to_le_bytes()
+                .into_iter()
+                .take(config.cell_width.bytes())
+        })
+        .collect();
+    let len = tape
+        .iter()
+        .rposition(|&byte| byte != 0)
+        .map_or(0, |i| i + 1);
+    tape.truncate(len);
+
+    Prefix {
+        resume: pc,
+        pointer,
+        tape,
+        output,
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{diagnostic, Budget, Cell, SourceLocation};
+
+/// An error that stops the execution of a program.
+#[derive(Debug)]
+pub enum RuntimeError {
+    Io(std::io::Error),
+    /// The pointer moved left of the first cell, by the instruction at the
+    /// given byte index in the source.
+    PointerUnderflow(usize),
+    /// The pointer moved right of the last cell, by the instruction at the
+    /// given byte index in the source.
+    PointerOverflow(usize),
+    /// The program executed all the steps of its `Budget`.
+    OutOfFuel,
+    /// The program ran longer than the timeout of its `Budget`.
+    Timeout,
+    /// The program was stopped by its `CancelHandle`.
+    Cancelled,
+    /// The program accessed a cell out of a `GuardedTape`. Where it happened is
+    /// not known.
+    TapeOutOfBounds,
+}
+impl From<std::io::Error> for RuntimeError {
+    fn from(err: std::io::Error) -> Self {
+        RuntimeError::Io(err)
+    }
+}
+impl RuntimeError {
+    /// Format the error, pointing at the instruction that caused it in
+    /// `source`, if any.
+    pub fn report(&self, file_name: &str, source: &[u8]) -> String {
+        let (message, position) = match self {
+            RuntimeError::Io(err) => return format!("IO error: {}\n", err),
+            RuntimeError::OutOfFuel => return "error: program ran out of fuel\n".to_string(),
+            RuntimeError::Timeout => return "error: program timed out\n".to_string(),
+            RuntimeError::Cancelled => return "error: program was cancelled\n".to_string(),
+            RuntimeError::TapeOutOfBounds => return "error: tape out of bounds\n".to_string(),
+            RuntimeError::PointerUnderflow(position) => ("pointer moved left of cell 0", position),
+            RuntimeError::PointerOverflow(position) => {
+                ("pointer moved right of the last cell", position)
+            }
+        };
+        let location = SourceLocation::new(source, *position);
+        let message = format!("{} at {}:{}", message, location.line, location.column);
+        diagnostic::report(file_name, source, location, &message)
+    }
+}
+
+/// Define functions called by the code generated by the JIT backends, with
+/// the `sysv64` calling convention on x86-64, even on Windows, and the C one
+/// on the other architectures.
+#[macro_export]
+macro_rules! jit_fn {
+    () => {};
+    (
+        $(#[$attr:meta])*
+        $vis:vis unsafe fn $name:ident $(<$($param:ident: $bound:path),*>)? ($($args:tt)*) $(-> $ret:ty)?
+        $body:block
+        $($rest:tt)*
+    ) => {
+        #[cfg(target_arch = "x86_64")]
+        $(#[$attr])*
+        $vis unsafe extern "sysv64" fn $name $(<$($param: $bound),*>)? ($($args)*) $(-> $ret)? $body
+        #[cfg(not(target_arch = "x86_64"))]
+        $(#[$attr])*
+        $vis unsafe extern "C" fn $name $(<$($param: $bound),*>)? ($($args)*) $(-> $ret)? $body
+        $crate::jit_fn! { $($rest)* }
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis fn $name:ident $(<$($param:ident: $bound:path),*>)? ($($args:tt)*) $(-> $ret:ty)?
+        $body:block
+        $($rest:tt)*
+    ) => {
+        #[cfg(target_arch = "x86_64")]
+        $(#[$attr])*
+        $vis extern "sysv64" fn $name $(<$($param: $bound),*>)? ($($args)*) $(-> $ret)? $body
+        #[cfg(not(target_arch = "x86_64"))]
+        $(#[$attr])*
+        $vis extern "C" fn $name $(<$($param: $bound),*>)? ($($args)*) $(-> $ret)? $body
+        $crate::jit_fn! { $($rest)* }
+    };
+}
+
+/// The type of `pointer_underflow` and `pointer_overflow`.
+#[cfg(target_arch = "x86_64")]
+pub type Trap = extern "sysv64" fn(usize) -> *mut RuntimeError;
+#[cfg(not(target_arch = "x86_64"))]
+pub type Trap = extern "C" fn(usize) -> *mut RuntimeError;
+
+/// The address and length of a tape, returned in `rax` and `rdx`, or `x0`
+/// and `x1`.
+#[repr(C)]
+pub struct TapeSlice {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+// The functions below are called by the code generated by the JIT backends,
+// which return the error to `Program::run`.
+jit_fn! {
+    pub fn pointer_underflow(position: usize) -> *mut RuntimeError {
+        Box::into_raw(Box::new(RuntimeError::PointerUnderflow(position)))
+    }
+
+    pub fn pointer_overflow(position: usize) -> *mut RuntimeError {
+        Box::into_raw(Box::new(RuntimeError::PointerOverflow(position)))
+    }
+
+    /// Return the index of the first zero byte of the tape at or right of
+    /// `index`, or `len` if there is none.
+    ///
+    /// # Safety
+    ///
+    /// `tape` must be valid for reads of `len` bytes, and `index` less than `len`.
+    pub unsafe fn find_zero_right(tape: *const u8, len: usize, index: usize) -> usize {
+        let tape = std::slice::from_raw_parts(tape, len);
+        u8::find_zero(&tape[index..]).map_or(len, |i| index + i)
+    }
+
+    /// Return the index of the last zero byte of the tape at or left of `index`,
+    /// or `len` if there is none.
+    ///
+    /// # Safety
+    ///
+    /// `tape` must be valid for reads of `len` bytes, and `index` less than `len`.
+    pub unsafe fn find_zero_left(tape: *const u8, len: usize, index: usize) -> usize {
+        let tape = std::slice::from_raw_parts(tape, len);
+        u8::rfind_zero(&tape[..=index]).unwrap_or(len)
+    }
+
+    /// Call `Budget::refill` on `budget`.
+    ///
+    /// # Safety
+    ///
+    /// `budget` must be a valid pointer, not aliased by any reference.
+    pub unsafe fn refill_budget(budget: *mut Budget) -> *mut RuntimeError {
+        match (*budget).refill() {
+            Ok(()) => std::ptr::null_mut(),
+            Err(err) => Box::into_raw(Box::new(err)),
+        }
+    }
+
+    /// Grow `tape` until the byte at `index` exists, and return its new address
+    /// and length in bytes.
+    ///
+    /// # Safety
+    ///
+    /// `tape` must be a valid pointer, not aliased by any reference.
+    pub unsafe fn grow_tape(tape: *mut Vec<u8>, index: usize) -> TapeSlice {
+        let tape = &mut *tape;
+        let len = (index + 1).max(tape.len() * 2);
+        tape.resize(len, 0);
+        TapeSlice {
+            ptr: tape.as_mut_ptr(),
+            len: tape.len(),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! The reports of the unbalanced brackets, with their line, column and line
+//! of source.
+
+use bf_core::{SourceLocation, UnbalancedBrackets};
+
+fn unbalanced(source: &[u8]) -> UnbalancedBrackets {
+    bf_core::parse(source).unwrap_err()
+}
+
+#[test]
+fn unmatched_close() {
+    let source = b"+++\n+++]\n";
+    let err = unbalanced(source);
+    assert_eq!(
+        err.0,
+        [(
+            ']',
+            SourceLocation {
+                index: 7,
+                line: 2,
+                column: 4
+            }
+        )]
+    );
+    assert_eq!(
+        err.report("hello.bf", source),
+        concat!(
+            "error: unmatched `]`\n",
+            " --> hello.bf:2:4\n",
+            "  |\n",
+            "2 | +++]\n",
+            "  |    ^\n",
+        )
+    );
+}
+
+/// All the unpaired brackets are reported, in the order they appear.
+#[test]
+fn several_brackets() {
+    let source = b"[\n]]\n\n[+[-]\n";
+    let err = unbalanced(source);
+    let brackets: Vec<_> = err
+        .0
+        .iter()
+        .map(|(c, location)| (*c, location.line, location.column))
+        .collect();
+    assert_eq!(brackets, [(']', 2, 2), ('[', 4, 1)]);
+    assert_eq!(
+        err.report("a.bf", source),
+        concat!(
+            "error: unmatched `]`\n",
+            " --> a.bf:2:2\n",
+            "  |\n",
+            "2 | ]]\n",
+            "  |  ^\n",
+            "\n",
+            "error: unmatched `[`\n",
+            " --> a.bf:4:1\n",
+            "  |\n",
+            "4 | [+[-]\n",
+            "  | ^\n",
+        )
+    );
+}
+
+/// The columns count characters, and not bytes, and the tabs are kept in
+/// front of the caret to align it.
+#[test]
+fn utf8_and_tabs() {
+    let source = "\tçé [\r\n".as_bytes();
+    let err = unbalanced(source);
+    assert_eq!((err.0[0].1.line, err.0[0].1.column), (1, 5));
+    assert_eq!(
+        err.report("b.bf", source),
+        concat!(
+            "error: unmatched `[`\n",
+            " --> b.bf:1:5\n",
+            "  |\n",
+            "1 | \tçé [\n",
+            "  | \t   ^\n",
+        )
+    );
+}
+
+/// The lines longer than 80 characters are cut around the bracket.
+#[test]
+fn long_line() {
+    let mut source = vec![b'+'; 200];
+    source[150] = b']';
+    let err = unbalanced(&source);
+    assert_eq!(err.0[0].1.column, 151);
+
+    let report = err.report("c.bf", &source);
+    let lines: Vec<&str> = report.lines().collect();
+    let snippet = format!("...{}]{}...", "+".repeat(40), "+".repeat(39));
+    assert_eq!(lines[1], " --> c.bf:1:151");
+    assert_eq!(lines[3], format!("1 | {}", snippet));
+    assert_eq!(lines[4], format!("  | {}^", " ".repeat(43)));
+}
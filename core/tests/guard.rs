@@ -0,0 +1,78 @@
+//! The SIGSEGV handler of the guarded tapes forwards the other faults to the
+//! handler installed before it, and keeps handling the faults of the tapes.
+//!
+//! The handlers are global to the process, so this file has a single test.
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use std::ffi::{c_int, c_void};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bf_core::{GuardedTape, RuntimeError};
+
+/// The page that `handle_segv` makes accessible on a fault.
+static PAGE: AtomicUsize = AtomicUsize::new(0);
+/// The number of faults that `handle_segv` recovered.
+static RECOVERED: AtomicUsize = AtomicUsize::new(0);
+
+/// A handler that recovers the faults on `PAGE`, and stops the process on the
+/// other ones.
+unsafe extern "C" fn handle_segv(signal: c_int, info: *mut libc::siginfo_t, _: *mut c_void) {
+    let page = PAGE.load(Ordering::Relaxed);
+    let address = (*info).si_addr() as usize;
+    if (page..page + 4096).contains(&address) {
+        libc::mprotect(page as *mut c_void, 4096, libc::PROT_READ);
+        RECOVERED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+}
+
+/// Run code that reads the byte before the tape, and return its error.
+fn read_before_tape() -> Option<RuntimeError> {
+    // mov al, [rdi - 1]; xor eax, eax; ret
+    let mut code = memmap2::MmapMut::map_anon(4096).unwrap();
+    code[..6].copy_from_slice(&[0x8a, 0x47, 0xff, 0x31, 0xc0, 0xc3]);
+    let code = code.make_exec().unwrap();
+
+    let mut tape = GuardedTape::new(4096).unwrap();
+    let error = unsafe { tape.call(code.as_ptr(), std::ptr::null_mut::<()>()) };
+    (!error.is_null()).then(|| unsafe { *Box::from_raw(error) })
+}
+
+#[test]
+fn unrelated_faults() {
+    unsafe {
+        let page = libc::mmap(
+            std::ptr::null_mut(),
+            4096,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        assert_ne!(page, libc::MAP_FAILED);
+        PAGE.store(page as usize, Ordering::Relaxed);
+
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_segv as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGSEGV, &action, std::ptr::null_mut());
+    }
+
+    // installs the handler of the guarded tapes
+    assert!(matches!(
+        read_before_tape(),
+        Some(RuntimeError::TapeOutOfBounds)
+    ));
+
+    let page = PAGE.load(Ordering::Relaxed) as *const u8;
+    assert_eq!(unsafe { std::ptr::read_volatile(page) }, 0);
+    assert_eq!(RECOVERED.load(Ordering::Relaxed), 1);
+
+    // the handler of the guarded tapes is still installed
+    assert!(matches!(
+        read_before_tape(),
+        Some(RuntimeError::TapeOutOfBounds)
+    ));
+}
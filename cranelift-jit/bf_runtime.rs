@@ -0,0 +1,280 @@
+//! The runtime of the programs compiled ahead of time by `bf-cranelift-jit`,
+//! built by `build.rs` as a static library, and linked with the object of the
+//! program. It defines the functions that the generated code imports by name,
+//! with the same signatures as the ones that the JIT calls by address, and the
+//! `main` function that runs the program.
+
+use std::io::{IsTerminal, Read, Write};
+use std::ptr::{addr_of, null_mut};
+
+/// How many bytes are buffered before they are written to stdout.
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// What `,` does to the current cell when the input reached its end. Must
+/// match `bf_core::Eof`.
+#[repr(u64)]
+#[derive(Clone, Copy)]
+pub enum Eof {
+    Zero,
+    MinusOne,
+    Unchanged,
+}
+
+/// The configuration of the program, defined by its object. Must match
+/// `aot::compile_object`.
+#[repr(C)]
+pub struct Header {
+    /// The size of the tape in bytes.
+    tape_size: usize,
+    eof: Eof,
+    unbuffered: u64,
+    prefix_tape_len: usize,
+    prefix_output_len: usize,
+}
+
+extern "C" {
+    static bf_header: Header;
+    /// The tape left by the part of the program run at compile time.
+    static bf_prefix_tape: u8;
+    /// The output of the part of the program run at compile time.
+    static bf_prefix_output: u8;
+
+    /// The compiled program. Receives the address and the length of the tape,
+    /// and returns null, or the error that stopped the program.
+    fn bf_main(memory: *mut u8, len: usize, context: *mut Context) -> *mut Error;
+}
+
+/// Define functions with the calling convention that the generated code uses
+/// for the functions of `bf_core::runtime`: `sysv64` on x86-64, and the C one
+/// elsewhere, like `bf_core::jit_fn!`.
+macro_rules! runtime_fn {
+    () => {};
+    (
+        $(#[$attr:meta])*
+        pub unsafe fn $name:ident ($($args:tt)*) -> $ret:ty $body:block
+        $($rest:tt)*
+    ) => {
+        #[cfg(target_arch = "x86_64")]
+        $(#[$attr])*
+        pub unsafe extern "sysv64" fn $name ($($args)*) -> $ret $body
+        #[cfg(not(target_arch = "x86_64"))]
+        $(#[$attr])*
+        pub unsafe extern "C" fn $name ($($args)*) -> $ret $body
+        runtime_fn! { $($rest)* }
+    };
+    (
+        $(#[$attr:meta])*
+        pub fn $name:ident ($($args:tt)*) -> $ret:ty $body:block
+        $($rest:tt)*
+    ) => {
+        #[cfg(target_arch = "x86_64")]
+        $(#[$attr])*
+        pub extern "sysv64" fn $name ($($args)*) -> $ret $body
+        #[cfg(not(target_arch = "x86_64"))]
+        $(#[$attr])*
+        pub extern "C" fn $name ($($args)*) -> $ret $body
+        runtime_fn! { $($rest)* }
+    };
+}
+
+/// An error that stops the execution of a program.
+pub enum Error {
+    Io(std::io::Error),
+    /// The pointer moved left of the first cell, at the line and column
+    /// packed in the high and low 32 bits.
+    PointerUnderflow(usize),
+    /// The pointer moved right of the last cell, at the line and column
+    /// packed in the high and low 32 bits.
+    PointerOverflow(usize),
+}
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (message, location) = match self {
+            Error::Io(err) => return writeln!(f, "IO error: {}", err),
+            Error::PointerUnderflow(location) => ("pointer moved left of cell 0", location),
+            Error::PointerOverflow(location) => ("pointer moved right of the last cell", location),
+        };
+        let (line, column) = (location >> 32, location & 0xffff_ffff);
+        writeln!(f, "error: {} at {}:{}", message, line, column)
+    }
+}
+
+fn io_error(err: std::io::Error) -> *mut Error {
+    Box::into_raw(Box::new(Error::Io(err)))
+}
+
+/// The state of the running program, passed to the runtime functions.
+#[repr(C)]
+pub struct Context {
+    /// Read by the generated code to grow the tape, so it must be the first
+    /// field.
+    memory: *mut Vec<u8>,
+    /// The output of the program not yet written to stdout.
+    output: Vec<u8>,
+    /// Flush after each byte.
+    unbuffered: bool,
+    /// Flush after each newline, if stdout is a terminal.
+    interactive: bool,
+    eof: Eof,
+}
+impl Context {
+    fn flush(&mut self) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        let result = stdout.write_all(&self.output).and_then(|_| stdout.flush());
+        self.output.clear();
+        result
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bf_write(context: *mut Context, value: u8) -> *mut Error {
+    let context = &mut *context;
+    context.output.push(value);
+
+    if context.unbuffered
+        || context.output.len() >= BUFFER_SIZE
+        || (value == b'\n' && context.interactive)
+    {
+        if let Err(err) = context.flush() {
+            return io_error(err);
+        }
+    }
+    null_mut()
+}
+
+/// Read a byte into the cell at `cell`, of `cell_bytes` bytes, that may be
+/// unaligned.
+unsafe fn read(context: *mut Context, cell: *mut u8, cell_bytes: usize) -> *mut Error {
+    let context = &mut *context;
+    if let Err(err) = context.flush() {
+        return io_error(err);
+    }
+
+    let mut value = 0;
+    match std::io::stdin().read_exact(std::slice::from_mut(&mut value)) {
+        Ok(()) => {
+            // the cell is little endian
+            std::ptr::write_bytes(cell, 0, cell_bytes);
+            *cell = value;
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => match context.eof {
+            Eof::Zero => std::ptr::write_bytes(cell, 0, cell_bytes),
+            Eof::MinusOne => std::ptr::write_bytes(cell, 0xff, cell_bytes),
+            Eof::Unchanged => {}
+        },
+        Err(err) => return io_error(err),
+    }
+    null_mut()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bf_read_u8(context: *mut Context, cell: *mut u8) -> *mut Error {
+    read(context, cell, 1)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bf_read_u16(context: *mut Context, cell: *mut u8) -> *mut Error {
+    read(context, cell, 2)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn bf_read_u32(context: *mut Context, cell: *mut u8) -> *mut Error {
+    read(context, cell, 4)
+}
+
+/// The address and length of a tape, returned in `rax` and `rdx`, or `x0` and
+/// `x1`.
+#[repr(C)]
+pub struct TapeSlice {
+    ptr: *mut u8,
+    len: usize,
+}
+
+runtime_fn! {
+    #[no_mangle]
+    pub fn bf_pointer_underflow(location: usize) -> *mut Error {
+        Box::into_raw(Box::new(Error::PointerUnderflow(location)))
+    }
+
+    #[no_mangle]
+    pub fn bf_pointer_overflow(location: usize) -> *mut Error {
+        Box::into_raw(Box::new(Error::PointerOverflow(location)))
+    }
+
+    /// Return the index of the first zero byte of the tape at or right of
+    /// `index`, or `len` if there is none.
+    #[no_mangle]
+    pub unsafe fn bf_find_zero_right(tape: *const u8, len: usize, index: usize) -> usize {
+        let tape = std::slice::from_raw_parts(tape, len);
+        tape[index..]
+            .iter()
+            .position(|&b| b == 0)
+            .map_or(len, |i| index + i)
+    }
+
+    /// Return the index of the last zero byte of the tape at or left of
+    /// `index`, or `len` if there is none.
+    #[no_mangle]
+    pub unsafe fn bf_find_zero_left(tape: *const u8, len: usize, index: usize) -> usize {
+        let tape = std::slice::from_raw_parts(tape, len);
+        tape[..=index].iter().rposition(|&b| b == 0).unwrap_or(len)
+    }
+
+    /// Grow `tape` until the byte at `index` exists, and return its new
+    /// address and length in bytes.
+    #[no_mangle]
+    pub unsafe fn bf_grow_tape(tape: *mut Vec<u8>, index: usize) -> TapeSlice {
+        let tape = &mut *tape;
+        let len = (index + 1).max(tape.len() * 2);
+        tape.resize(len, 0);
+        TapeSlice {
+            ptr: tape.as_mut_ptr(),
+            len: tape.len(),
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn main() -> i32 {
+    let header = &bf_header;
+    let prefix_tape = std::slice::from_raw_parts(addr_of!(bf_prefix_tape), header.prefix_tape_len);
+    let prefix_output =
+        std::slice::from_raw_parts(addr_of!(bf_prefix_output), header.prefix_output_len);
+
+    // start from the tape left by the prefix
+    let mut memory = vec![0; header.tape_size];
+    memory[..prefix_tape.len()].copy_from_slice(prefix_tape);
+
+    let mut context = Context {
+        memory: &mut memory,
+        output: Vec::with_capacity(BUFFER_SIZE),
+        unbuffered: header.unbuffered != 0,
+        interactive: std::io::stdout().is_terminal(),
+        eof: header.eof,
+    };
+
+    let mut error = null_mut();
+    for &value in prefix_output {
+        error = bf_write(&mut context, value);
+        if !error.is_null() {
+            break;
+        }
+    }
+    if error.is_null() {
+        let memory = &mut *context.memory;
+        error = bf_main(memory.as_mut_ptr(), memory.len(), &mut context);
+    }
+
+    // flush the output even if the program failed
+    let flushed = context.flush();
+
+    if !error.is_null() {
+        eprint!("{}", Box::from_raw(error));
+        return 5;
+    }
+    if let Err(err) = flushed {
+        eprint!("{}", Error::Io(err));
+        return 5;
+    }
+    0
+}
@@ -0,0 +1,44 @@
+//! Build the runtime of the programs compiled ahead of time, `bf_runtime.rs`,
+//! as a static library that is bundled in the binary, and save the native
+//! libraries it must be linked with.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=bf_runtime.rs");
+
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    let rustc = std::env::var_os("RUSTC").unwrap();
+    let target = std::env::var("TARGET").unwrap();
+
+    let output = Command::new(rustc)
+        .args(["--crate-type=staticlib", "--edition=2021"])
+        .args(["-Copt-level=3", "-Cpanic=abort"])
+        .args(["--print=native-static-libs", "--target", &target])
+        .arg("bf_runtime.rs")
+        .arg("-o")
+        .arg(out_dir.join("libbf_runtime.a"))
+        .output();
+    let output = match output {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("error running rustc: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // cargo shows the stderr of the build script when it fails
+    if !output.status.success() {
+        eprintln!("error building bf_runtime.rs:");
+        eprint!("{}", stderr);
+        std::process::exit(1);
+    }
+
+    // printed as "note: native-static-libs: -lgcc_s -lc ..."
+    let libs = stderr
+        .lines()
+        .find_map(|line| line.split("native-static-libs:").nth(1))
+        .unwrap_or("");
+    std::fs::write(out_dir.join("native-static-libs"), libs.trim()).unwrap();
+}
@@ -0,0 +1,134 @@
+//! Ahead-of-time compilation: the same function built for the JIT, but
+//! lowered by `cranelift-object` into a relocatable object that imports the
+//! runtime functions by name, and linked with the bundled `bf_runtime.rs`.
+
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cranelift::codegen::Context;
+use cranelift_module::{default_libcall_names, DataContext, Linkage, Module};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+
+use bf_core::{Boundary, Config, Eof, Prefix, SourceLocation};
+
+use crate::{build_function, isa, Callee, CompileError};
+
+/// The runtime of the compiled programs, built from `bf_runtime.rs`.
+const RUNTIME: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libbf_runtime.a"));
+
+/// The native libraries that the runtime must be linked with.
+const NATIVE_LIBS: &str = include_str!(concat!(env!("OUT_DIR"), "/native-static-libs"));
+
+/// Compile the program to a relocatable object, that defines the function
+/// `bf_main` and the data read by the `main` of the runtime.
+///
+/// The program can't have a `Budget`, and `Boundary::Guard` is not supported,
+/// since the tape is allocated by the runtime.
+pub fn compile_object(source: &[u8], config: &Config, clir: bool) -> Result<Vec<u8>, CompileError> {
+    assert!(config.fuel.is_none() && config.timeout.is_none() && !config.cancellable);
+    assert!(config.boundary != Boundary::Guard);
+
+    let ir = bf_core::parse(source)?;
+    let (mut ir, _) = bf_core::optimize(&ir, config);
+    let prefix = Prefix::evaluate(&ir, config);
+    let in_bounds = bf_core::in_bounds(&ir, config);
+
+    // the runtime doesn't have the source, so the traps receive the line and
+    // column of the instruction instead of its position.
+    for position in &mut ir.positions {
+        let location = SourceLocation::new(source, *position);
+        *position = location.line << 32 | location.column;
+    }
+
+    let builder = ObjectBuilder::new(isa(true), "bf", default_libcall_names()).unwrap();
+    let mut module = ObjectModule::new(builder);
+
+    let func = build_function(
+        ir,
+        config,
+        &prefix,
+        &in_bounds,
+        &*isa(true),
+        clir,
+        &mut |func, name, signature| {
+            let id = module
+                .declare_function(name, Linkage::Import, &signature)
+                .unwrap();
+            Callee::Import(module.declare_func_in_func(id, func))
+        },
+    );
+
+    let main = module
+        .declare_function("bf_main", Linkage::Export, &func.signature)
+        .unwrap();
+    let mut ctx = Context::for_function(func);
+    if let Err(err) = module.define_function(main, &mut ctx) {
+        return Err(CompileError::Cranelift(format!("{:?}", err)));
+    }
+
+    // the `Header` of `bf_runtime.rs`
+    let eof = match config.eof {
+        Eof::Zero => 0u64,
+        Eof::MinusOne => 1,
+        Eof::Unchanged => 2,
+    };
+    let header = [
+        (config.tape_size * config.cell_width.bytes()) as u64,
+        eof,
+        config.unbuffered as u64,
+        prefix.tape.len() as u64,
+        prefix.output.len() as u64,
+    ];
+    let header: Vec<u8> = header.iter().flat_map(|x| x.to_le_bytes()).collect();
+
+    let data = [
+        ("bf_header", header),
+        ("bf_prefix_tape", prefix.tape),
+        ("bf_prefix_output", prefix.output),
+    ];
+    for (name, bytes) in data {
+        let id = module
+            .declare_data(name, Linkage::Export, false, false)
+            .unwrap();
+        let mut data = DataContext::new();
+        data.define(bytes.into_boxed_slice());
+        module.define_data(id, &data).unwrap();
+    }
+
+    Ok(module.finish().emit().unwrap())
+}
+
+/// Link an object built by `compile_object` with the runtime into the
+/// executable `output`, using the system C compiler, `cc`.
+pub fn link(object: &[u8], output: &Path) -> std::io::Result<()> {
+    // a directory for each call, since they can run in parallel
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "bf-cranelift-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&dir)?;
+
+    let object_path = dir.join("program.o");
+    let runtime_path = dir.join("libbf_runtime.a");
+    let status = std::fs::write(&object_path, object)
+        .and_then(|_| std::fs::write(&runtime_path, RUNTIME))
+        .and_then(|_| {
+            Command::new("cc")
+                .arg("-o")
+                .arg(output)
+                .arg(&object_path)
+                .arg(&runtime_path)
+                .args(NATIVE_LIBS.split_whitespace())
+                .status()
+        });
+
+    std::fs::remove_dir_all(&dir)?;
+
+    if !status?.success() {
+        return Err(std::io::Error::other("the linker `cc` failed"));
+    }
+    Ok(())
+}
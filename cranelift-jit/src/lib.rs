@@ -0,0 +1,1068 @@
+//! A brainfuck JIT compiler, that translates the instructions optimized by
+//! `bf_core::optimize` to machine code, using Cranelift.
+
+use cranelift::{
+    codegen::{
+        entity::EntityRef,
+        ir::{
+            condcodes::IntCC,
+            types::{I16, I32, I64, I8},
+            AbiParam, Block, FuncRef, Function, Inst, InstBuilder, MemFlags, SigRef, Signature,
+            Type, UserFuncName, Value,
+        },
+        isa::{self, CallConv, TargetIsa},
+        settings::{self, Configurable},
+        verify_function, Context,
+    },
+    frontend::{FunctionBuilder, FunctionBuilderContext, Variable},
+};
+use std::io::{IsTerminal, Read, Write};
+use std::time::Duration;
+use target_lexicon::Triple;
+
+use bf_core::{
+    Boundary, Budget, CancelHandle, Cell, CellWidth, Config, Eof, GuardedTape, Instruction, Ir,
+    Output, Prefix, RuntimeError, Stats, UnbalancedBrackets,
+};
+
+mod aot;
+
+pub use aot::{compile_object, link};
+
+/// An error that prevents compiling a program.
+#[derive(Debug)]
+pub enum CompileError {
+    UnbalancedBrackets(UnbalancedBrackets),
+    /// Cranelift failed to compile the generated function, for the given
+    /// reason.
+    Cranelift(String),
+}
+impl From<UnbalancedBrackets> for CompileError {
+    fn from(err: UnbalancedBrackets) -> Self {
+        CompileError::UnbalancedBrackets(err)
+    }
+}
+impl CompileError {
+    /// Format the error, pointing at the unbalanced brackets in `source`, if
+    /// any.
+    pub fn report(&self, file_name: &str, source: &[u8]) -> String {
+        match self {
+            CompileError::UnbalancedBrackets(err) => err.report(file_name, source),
+            CompileError::Cranelift(err) => format!("error compiling: {}\n", err),
+        }
+    }
+}
+
+pub struct Program {
+    code: Vec<u8>,
+    memory: Vec<u8>,
+    tape_size: usize,
+    eof: Eof,
+    unbuffered: bool,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    cancel: CancelHandle,
+    stats: Stats,
+    prefix: Prefix,
+    guard: bool,
+    /// The tape of the last run with `Boundary::Guard`.
+    guarded: Option<GuardedTape>,
+}
+impl Program {
+    pub fn new(source: &[u8], config: &Config, clir: bool) -> Result<Program, CompileError> {
+        let config = &bf_core::round_to_pages(config);
+        let ir = bf_core::parse(source)?;
+        let (ir, stats) = bf_core::optimize(&ir, config);
+        let prefix = Prefix::evaluate(&ir, config);
+        let in_bounds = bf_core::in_bounds(&ir, config);
+
+        let isa = isa(false);
+
+        let func = build_function(
+            ir,
+            config,
+            &prefix,
+            &in_bounds,
+            &*isa,
+            clir,
+            &mut |func, name, signature| {
+                let address = match name {
+                    "bf_write" => write as *const (),
+                    "bf_read_u8" => read::<u8> as *const (),
+                    "bf_read_u16" => read::<u16> as *const (),
+                    "bf_read_u32" => read::<u32> as *const (),
+                    "bf_pointer_underflow" => bf_core::pointer_underflow as *const (),
+                    "bf_pointer_overflow" => bf_core::pointer_overflow as *const (),
+                    "bf_grow_tape" => bf_core::grow_tape as *const (),
+                    "bf_find_zero_right" => bf_core::find_zero_right as *const (),
+                    "bf_find_zero_left" => bf_core::find_zero_left as *const (),
+                    "bf_refill_budget" => bf_core::refill_budget as *const (),
+                    _ => unreachable!("unknown runtime function {}", name),
+                };
+                Callee::Address(func.import_signature(signature), address as i64)
+            },
+        );
+
+        let mut ctx = Context::for_function(func);
+        let code = match ctx.compile(&*isa) {
+            Ok(x) => x,
+            Err(err) => {
+                let err = CompileError::Cranelift(format!("{:?}", err));
+                if clir {
+                    println!("{}", ctx.func.display());
+                }
+                return Err(err);
+            }
+        };
+
+        let code = code.code_buffer().to_vec();
+
+        if clir {
+            println!("{}", ctx.func.display());
+        }
+
+        Ok(Program {
+            code,
+            memory: vec![0; config.tape_size * config.cell_width.bytes()],
+            tape_size: config.tape_size * config.cell_width.bytes(),
+            eof: config.eof,
+            unbuffered: config.unbuffered,
+            fuel: config.fuel,
+            timeout: config.timeout,
+            cancel: CancelHandle::default(),
+            stats,
+            prefix,
+            guard: config.boundary == Boundary::Guard,
+            guarded: None,
+        })
+    }
+
+    /// The generated machine code.
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// The statistics of the optimization of the program.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// A handle to stop the running program from another thread, with a
+    /// `RuntimeError::Cancelled`. It only works if the program was created
+    /// with `Config::cancellable`, `fuel` or `timeout`.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// The tape of the last run, in bytes. Cells larger than a byte are
+    /// stored in little-endian.
+    pub fn tape(&self) -> &[u8] {
+        match &self.guarded {
+            Some(tape) => tape.as_slice(),
+            None => &self.memory,
+        }
+    }
+
+    /// Run the program, reading from stdin and writing to stdout.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        // flush each line when the user may be reading it
+        let flush_on_newline = std::io::stdout().is_terminal();
+        self.run_inner(
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+            flush_on_newline,
+        )
+    }
+
+    /// Run the program, reading from `input` and writing to `output`.
+    pub fn run_with_io(
+        &mut self,
+        mut input: impl Read,
+        mut output: impl Write,
+    ) -> Result<(), RuntimeError> {
+        self.run_inner(&mut input, &mut output, false)
+    }
+
+    fn run_inner(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        flush_on_newline: bool,
+    ) -> Result<(), RuntimeError> {
+        // start each run from the tape left by the prefix
+        if self.guard {
+            let tape = self.guarded.insert(GuardedTape::new(self.tape_size)?);
+            tape.as_mut_slice()[..self.prefix.tape.len()].copy_from_slice(&self.prefix.tape);
+        } else {
+            self.memory.clear();
+            self.memory.extend_from_slice(&self.prefix.tape);
+            self.memory.resize(self.tape_size, 0);
+        }
+
+        let mut buffer = memmap2::MmapOptions::new()
+            .len(self.code.len())
+            .map_anon()
+            .unwrap();
+
+        buffer.copy_from_slice(self.code.as_slice());
+
+        let buffer = buffer.make_exec().unwrap();
+
+        unsafe {
+            let code_fn: unsafe extern "C" fn(
+                *mut u8,
+                usize,
+                *mut RunContext,
+            ) -> *mut RuntimeError = std::mem::transmute(buffer.as_ptr());
+
+            let memory = self.memory.as_mut_ptr();
+            let memory_len = self.memory.len();
+            let mut context = RunContext {
+                memory: &mut self.memory,
+                budget: Budget::new(self.fuel, self.timeout, Some(self.cancel.clone())),
+                input,
+                output: Output::new(output, self.unbuffered, flush_on_newline),
+                eof: self.eof,
+            };
+
+            let mut error = std::ptr::null_mut();
+            for &value in &self.prefix.output {
+                error = write(&mut context, value);
+                if !error.is_null() {
+                    break;
+                }
+            }
+            if error.is_null() {
+                error = match &mut self.guarded {
+                    Some(tape) => tape.call(buffer.as_ptr(), &mut context),
+                    None => code_fn(memory, memory_len, &mut context),
+                };
+            }
+
+            // flush the output even if the program failed
+            let flushed = context.output.flush();
+
+            if !error.is_null() {
+                return Err(*Box::from_raw(error));
+            }
+            flushed?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Build the settings of the target ISA of the host. The code is position
+/// independent if `pic`, as required by an object linked into an executable.
+fn isa(pic: bool) -> Box<dyn TargetIsa> {
+    // possible settings: https://docs.rs/cranelift-codegen/latest/src/cranelift_codegen/opt/rustwide/target/x86_64-unknown-linux-gnu/debug/build/cranelift-codegen-b5deaeb0cd154533/out/settings.rs.html#490-664
+    let mut builder = settings::builder();
+    builder.set("opt_level", "speed").unwrap();
+    // issue: https://github.com/bytecodealliance/wasmtime/issues/1148
+    builder.set("preserve_frame_pointers", "false").unwrap();
+    // builder.set("use_egraphs", "true").unwrap();
+    if pic {
+        builder.set("is_pic", "true").unwrap();
+    }
+
+    let flags = settings::Flags::new(builder);
+
+    match isa::lookup(Triple::host()) {
+        Err(_) => panic!("x86_64 ISA is not avaliable"),
+        Ok(isa_builder) => isa_builder.finish(flags).unwrap(),
+    }
+}
+
+/// Return how the generated code calls the runtime function of the given name
+/// and signature.
+type Resolve<'a> = dyn FnMut(&mut Function, &str, Signature) -> Callee + 'a;
+
+/// Build the function that runs the program, calling the runtime functions
+/// given by `resolve`. It receives the address and length of the tape and the
+/// `RunContext`, and returns a pointer to a `RuntimeError`, or null.
+fn build_function(
+    ir: Ir,
+    config: &Config,
+    prefix: &Prefix,
+    in_bounds: &[bool],
+    isa: &dyn TargetIsa,
+    clir: bool,
+    resolve: &mut Resolve,
+) -> Function {
+    let pointer_type = isa.pointer_type();
+
+    let call_conv = CallConv::triple_default(isa.triple());
+
+    // get memory address, memory length and memory Vec address parameters,
+    // and return pointer to RuntimeError
+    let mut sig = Signature::new(call_conv);
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.params.push(AbiParam::new(pointer_type));
+    sig.returns.push(AbiParam::new(pointer_type));
+
+    let mut func = Function::with_name_signature(UserFuncName::user(0, 0), sig);
+
+    let mut func_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut func, &mut func_ctx);
+
+    let pointer = Variable::new(0);
+    builder.declare_var(pointer, pointer_type);
+    let memory = Variable::new(1);
+    builder.declare_var(memory, pointer_type);
+    let memory_len = Variable::new(2);
+    builder.declare_var(memory_len, pointer_type);
+
+    let exit_block = builder.create_block();
+    builder.append_block_param(exit_block, pointer_type);
+
+    let block = builder.create_block();
+    builder.seal_block(block);
+
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+
+    let params = builder.block_params(block).to_vec();
+    let context = params[2];
+    builder.def_var(memory, params[0]);
+    builder.def_var(memory_len, params[1]);
+
+    let cell_type = match config.cell_width {
+        CellWidth::U8 => I8,
+        CellWidth::U16 => I16,
+        CellWidth::U32 => I32,
+    };
+    let zero_cell = builder.ins().iconst(cell_type, 0);
+    let zero = builder.ins().iconst(pointer_type, 0);
+    let start = (prefix.pointer * config.cell_width.bytes()) as i64;
+    let start = builder.ins().iconst(pointer_type, start);
+    builder.def_var(pointer, start);
+
+    let mem_flags = MemFlags::new(); //.with_notrap().with_heap();
+
+    let runtime = Runtime::new(&mut builder, resolve, config, call_conv, pointer_type);
+
+    // only count the steps when they are limited
+    let steps = (config.fuel.is_some() || config.timeout.is_some() || config.cancellable)
+        .then(|| Steps::new(&mut builder, resolve, pointer_type, context, exit_block));
+
+    let tape = Tape::new(
+        runtime,
+        config,
+        pointer_type,
+        pointer,
+        memory,
+        memory_len,
+        context,
+        exit_block,
+    );
+
+    // skip the instructions run by the prefix. Their code is unreachable,
+    // but still emitted, since the first instruction run may be inside a
+    // loop.
+    let resume_block = builder.create_block();
+    builder.ins().jump(resume_block, &[]);
+    let skipped_block = builder.create_block();
+    builder.seal_block(skipped_block);
+    builder.switch_to_block(skipped_block);
+
+    let mut stack = Vec::new();
+
+    let len = ir.instructions.len();
+    for (index, (instr, position)) in ir.instructions.into_iter().zip(ir.positions).enumerate() {
+        if index == prefix.resume {
+            builder.ins().jump(resume_block, &[]);
+            builder.switch_to_block(resume_block);
+            builder.seal_block(resume_block);
+        }
+        match instr {
+            Instruction::Add(offset, n) => {
+                // sign extend `n` from the cell width
+                let n = match config.cell_width {
+                    CellWidth::U8 => n as i8 as i64,
+                    CellWidth::U16 => n as i16 as i64,
+                    CellWidth::U32 => n as i32 as i64,
+                };
+                let index = tape.offset_pointer(&mut builder, offset, position, in_bounds[index]);
+                let memory_address = builder.use_var(memory);
+                let cell_address = builder.ins().iadd(memory_address, index);
+                let cell_value = builder.ins().load(cell_type, mem_flags, cell_address, 0);
+                let cell_value = builder.ins().iadd_imm(cell_value, n);
+                builder.ins().store(mem_flags, cell_value, cell_address, 0);
+            }
+            Instruction::Move(n) => {
+                let pointer_value =
+                    tape.offset_pointer(&mut builder, n, position, in_bounds[index]);
+                builder.def_var(pointer, pointer_value);
+            }
+            Instruction::Output(offset) => {
+                let index = tape.offset_pointer(&mut builder, offset, position, in_bounds[index]);
+                let memory_address = builder.use_var(memory);
+                let cell_address = builder.ins().iadd(memory_address, index);
+                // only the lower byte of the cell is written
+                let cell_value = builder.ins().load(I8, mem_flags, cell_address, 0);
+
+                let inst = runtime
+                    .write
+                    .call(&mut builder, pointer_type, &[context, cell_value]);
+                let result = builder.inst_results(inst)[0];
+
+                let after_block = builder.create_block();
+
+                builder.ins().brnz(result, exit_block, &[result]);
+                builder.ins().jump(after_block, &[]);
+
+                builder.seal_block(after_block);
+                builder.switch_to_block(after_block);
+            }
+            Instruction::Input(offset) => {
+                let index = tape.offset_pointer(&mut builder, offset, position, in_bounds[index]);
+                let memory_address = builder.use_var(memory);
+                let cell_address = builder.ins().iadd(memory_address, index);
+                // fault here, and not in `read`, out of a guarded tape
+                builder.ins().load(I8, mem_flags, cell_address, 0);
+
+                let inst = runtime
+                    .read
+                    .call(&mut builder, pointer_type, &[context, cell_address]);
+                let result = builder.inst_results(inst)[0];
+
+                let after_block = builder.create_block();
+
+                builder.ins().brnz(result, exit_block, &[result]);
+                builder.ins().jump(after_block, &[]);
+
+                builder.seal_block(after_block);
+                builder.switch_to_block(after_block);
+            }
+            Instruction::JumpRight(_) => {
+                let inner_block = builder.create_block();
+                let after_block = builder.create_block();
+
+                let pointer_value = builder.use_var(pointer);
+                let memory_address = builder.use_var(memory);
+                let cell_address = builder.ins().iadd(memory_address, pointer_value);
+                let cell_value = builder.ins().load(cell_type, mem_flags, cell_address, 0);
+
+                builder.ins().brz(cell_value, after_block, &[]);
+                builder.ins().jump(inner_block, &[]);
+
+                builder.switch_to_block(inner_block);
+
+                stack.push((inner_block, after_block));
+            }
+            Instruction::JumpLeft(_) => {
+                let (inner_block, after_block) = stack.pop().unwrap();
+
+                if let Some(steps) = &steps {
+                    steps.emit_step(&mut builder);
+                }
+
+                let pointer_value = builder.use_var(pointer);
+                let memory_address = builder.use_var(memory);
+                let cell_address = builder.ins().iadd(memory_address, pointer_value);
+                let cell_value = builder.ins().load(cell_type, mem_flags, cell_address, 0);
+
+                builder.ins().brnz(cell_value, inner_block, &[]);
+                builder.ins().jump(after_block, &[]);
+
+                builder.seal_block(inner_block);
+                builder.seal_block(after_block);
+
+                builder.switch_to_block(after_block);
+            }
+            Instruction::Set(offset, n) => {
+                // sign extend `n` from the cell width
+                let n = match config.cell_width {
+                    CellWidth::U8 => n as i8 as i64,
+                    CellWidth::U16 => n as i16 as i64,
+                    CellWidth::U32 => n as i32 as i64,
+                };
+                let index = tape.offset_pointer(&mut builder, offset, position, in_bounds[index]);
+                let memory_address = builder.use_var(memory);
+                let cell_address = builder.ins().iadd(memory_address, index);
+                let cell_value = builder.ins().iconst(cell_type, n);
+                builder.ins().store(mem_flags, cell_value, cell_address, 0);
+            }
+            Instruction::Clear(offset) => {
+                let index = tape.offset_pointer(&mut builder, offset, position, in_bounds[index]);
+                let memory_address = builder.use_var(memory);
+                let cell_address = builder.ins().iadd(memory_address, index);
+                builder.ins().store(mem_flags, zero_cell, cell_address, 0);
+            }
+            Instruction::MulAdd { offset, factor } => {
+                // sign extend `factor` from the cell width
+                let factor = match config.cell_width {
+                    CellWidth::U8 => factor as i8 as i64,
+                    CellWidth::U16 => factor as i16 as i64,
+                    CellWidth::U32 => factor as i32 as i64,
+                };
+
+                // the loop replaced by the instruction doesn't access the
+                // cell at `offset` if the current cell is zero, and it may
+                // be out of the tape, or of a guarded tape.
+                let skip = config.boundary == Boundary::Guard || !in_bounds[index];
+                let skip_block = skip.then(|| {
+                    let add_block = builder.create_block();
+                    let skip_block = builder.create_block();
+
+                    let pointer_value = builder.use_var(pointer);
+                    let memory_address = builder.use_var(memory);
+                    let cell_address = builder.ins().iadd(memory_address, pointer_value);
+                    let cell_value = builder.ins().load(cell_type, mem_flags, cell_address, 0);
+
+                    builder.ins().brz(cell_value, skip_block, &[]);
+                    builder.ins().jump(add_block, &[]);
+
+                    builder.seal_block(add_block);
+                    builder.switch_to_block(add_block);
+                    skip_block
+                });
+
+                let to_add = tape.offset_pointer(&mut builder, offset, position, in_bounds[index]);
+
+                let pointer_value = builder.use_var(pointer);
+                let memory_address = builder.use_var(memory);
+                let from_address = builder.ins().iadd(memory_address, pointer_value);
+                let to_address = builder.ins().iadd(memory_address, to_add);
+
+                let from_value = builder.ins().load(cell_type, mem_flags, from_address, 0);
+                let to_value = builder.ins().load(cell_type, mem_flags, to_address, 0);
+
+                let product = builder.ins().imul_imm(from_value, factor);
+                let sum = builder.ins().iadd(to_value, product);
+
+                builder.ins().store(mem_flags, sum, to_address, 0);
+
+                if let Some(skip_block) = skip_block {
+                    builder.ins().jump(skip_block, &[]);
+                    builder.seal_block(skip_block);
+                    builder.switch_to_block(skip_block);
+                }
+            }
+            Instruction::MoveUntil(n) => {
+                if config.cell_width == CellWidth::U8 && (n == 1 || n == -1) {
+                    tape.scan(&mut builder, n);
+                }
+
+                let check_block = builder.create_block();
+                let inner_block = builder.create_block();
+                let after_block = builder.create_block();
+
+                builder.ins().jump(check_block, &[]);
+                builder.switch_to_block(check_block);
+
+                let pointer_value = builder.use_var(pointer);
+                let memory_address = builder.use_var(memory);
+                let cell_address = builder.ins().iadd(memory_address, pointer_value);
+                let cell_value = builder.ins().load(cell_type, mem_flags, cell_address, 0);
+
+                builder.ins().brz(cell_value, after_block, &[]);
+                builder.ins().jump(inner_block, &[]);
+
+                builder.seal_block(inner_block);
+                builder.switch_to_block(inner_block);
+
+                let pointer_value = tape.offset_pointer(&mut builder, n, position, false);
+                builder.def_var(pointer, pointer_value);
+
+                if let Some(steps) = &steps {
+                    steps.emit_step(&mut builder);
+                }
+                builder.ins().jump(check_block, &[]);
+
+                builder.seal_block(check_block);
+                builder.seal_block(after_block);
+
+                builder.switch_to_block(after_block);
+            }
+            Instruction::Check(offset) => {
+                let index = tape.offset_pointer(&mut builder, offset, position, in_bounds[index]);
+                if config.boundary == Boundary::Guard {
+                    // fault here if the cell is out of the guarded tape
+                    let memory_address = builder.use_var(memory);
+                    let cell_address = builder.ins().iadd(memory_address, index);
+                    builder.ins().load(I8, mem_flags, cell_address, 0);
+                }
+            }
+        }
+    }
+
+    if prefix.resume == len {
+        builder.ins().jump(resume_block, &[]);
+        builder.switch_to_block(resume_block);
+        builder.seal_block(resume_block);
+    }
+
+    builder.ins().return_(&[zero]);
+
+    builder.switch_to_block(exit_block);
+    builder.seal_block(exit_block);
+
+    let result = builder.block_params(exit_block)[0];
+    builder.ins().return_(&[result]);
+
+    builder.finalize();
+
+    let res = verify_function(&func, isa);
+
+    if clir {
+        println!("{}", func.display());
+    }
+
+    if let Err(errors) = res {
+        panic!("{}", errors);
+    }
+
+    func
+}
+
+/// The state needed to emit moves of the pointer, handling moves out of the
+/// tape according to the configured `Boundary`.
+struct Tape {
+    boundary: Boundary,
+    tape_size: i64,
+    /// The size of a cell. The pointer is a index in bytes.
+    bytes: i64,
+    pointer_type: Type,
+    pointer: Variable,
+    memory: Variable,
+    memory_len: Variable,
+    /// The `RunContext` of the program, that contains the `Vec` of the tape.
+    context: Value,
+    exit_block: Block,
+    runtime: Runtime,
+}
+impl Tape {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        runtime: Runtime,
+        config: &Config,
+        pointer_type: Type,
+        pointer: Variable,
+        memory: Variable,
+        memory_len: Variable,
+        context: Value,
+        exit_block: Block,
+    ) -> Tape {
+        Tape {
+            boundary: config.boundary,
+            tape_size: config.tape_size as i64,
+            bytes: config.cell_width.bytes() as i64,
+            pointer_type,
+            pointer,
+            memory,
+            memory_len,
+            context,
+            exit_block,
+            runtime,
+        }
+    }
+
+    /// Emit code that moves the pointer to the first zero byte in `direction`,
+    /// 1 or -1, using `bf_core::find_zero_right` or `find_zero_left`. If there
+    /// is none, the pointer is left on the last byte before leaving the tape,
+    /// or unchanged when wrapping around, for the following `MoveUntil` loop
+    /// to handle.
+    fn scan(&self, builder: &mut FunctionBuilder, direction: isize) {
+        let find = if direction > 0 {
+            self.runtime.find_zero_right
+        } else {
+            self.runtime.find_zero_left
+        };
+
+        let scan_block = builder.create_block();
+        let not_found_block = builder.create_block();
+        let found_block = builder.create_block();
+        builder.append_block_param(found_block, self.pointer_type);
+        let after_block = builder.create_block();
+
+        let pointer_value = builder.use_var(self.pointer);
+        let memory_address = builder.use_var(self.memory);
+        let memory_len = builder.use_var(self.memory_len);
+        let cell_address = builder.ins().iadd(memory_address, pointer_value);
+        let cell_value = builder.ins().load(I8, MemFlags::new(), cell_address, 0);
+        builder.ins().brz(cell_value, after_block, &[]);
+        builder.ins().jump(scan_block, &[]);
+
+        builder.seal_block(scan_block);
+        builder.switch_to_block(scan_block);
+
+        let inst = find.call(
+            builder,
+            self.pointer_type,
+            &[memory_address, memory_len, pointer_value],
+        );
+        let index = builder.inst_results(inst)[0];
+        let found = builder
+            .ins()
+            .icmp(IntCC::UnsignedLessThan, index, memory_len);
+        builder.ins().brnz(found, found_block, &[index]);
+        builder.ins().jump(not_found_block, &[]);
+
+        builder.seal_block(not_found_block);
+        builder.switch_to_block(not_found_block);
+
+        if self.boundary == Boundary::Wrap {
+            // search the other side of the tape
+            let start = if direction > 0 {
+                builder.ins().iconst(self.pointer_type, 0)
+            } else {
+                builder.ins().iadd_imm(memory_len, -1)
+            };
+            let inst = find.call(
+                builder,
+                self.pointer_type,
+                &[memory_address, memory_len, start],
+            );
+            let index = builder.inst_results(inst)[0];
+            let found = builder
+                .ins()
+                .icmp(IntCC::UnsignedLessThan, index, memory_len);
+            builder.ins().brnz(found, found_block, &[index]);
+            builder.ins().jump(after_block, &[]);
+        } else {
+            let edge = if direction > 0 {
+                builder.ins().iadd_imm(memory_len, -1)
+            } else {
+                builder.ins().iconst(self.pointer_type, 0)
+            };
+            builder.ins().jump(found_block, &[edge]);
+        }
+
+        builder.seal_block(found_block);
+        builder.switch_to_block(found_block);
+
+        let index = builder.block_params(found_block)[0];
+        builder.def_var(self.pointer, index);
+        builder.ins().jump(after_block, &[]);
+
+        builder.seal_block(after_block);
+        builder.switch_to_block(after_block);
+    }
+
+    /// Emit code that computes the byte index of the cell at `n` cells from
+    /// the pointer. The index is not checked if it is known to be
+    /// `in_bounds`.
+    fn offset_pointer(
+        &self,
+        builder: &mut FunctionBuilder,
+        n: isize,
+        position: usize,
+        in_bounds: bool,
+    ) -> Value {
+        let tape_size = self.tape_size * self.bytes;
+        let pointer_value = builder.use_var(self.pointer);
+        if n == 0 {
+            return pointer_value;
+        }
+        if in_bounds {
+            return builder.ins().iadd_imm(pointer_value, n as i64 * self.bytes);
+        }
+
+        if self.boundary == Boundary::Wrap {
+            let n = n as i64 % self.tape_size * self.bytes;
+            let pointer_plus = builder.ins().iadd_imm(pointer_value, n);
+            return if n > 0 {
+                let wrapped = builder.ins().iadd_imm(pointer_value, n - tape_size);
+                let cmp = builder
+                    .ins()
+                    .icmp_imm(IntCC::SignedLessThan, pointer_plus, tape_size);
+                builder.ins().select(cmp, pointer_plus, wrapped)
+            } else {
+                let wrapped = builder.ins().iadd_imm(pointer_value, n + tape_size);
+                let cmp = builder
+                    .ins()
+                    .icmp_imm(IntCC::SignedLessThan, pointer_plus, 0);
+                builder.ins().select(cmp, wrapped, pointer_plus)
+            };
+        }
+
+        let pointer_plus = builder.ins().iadd_imm(pointer_value, n as i64 * self.bytes);
+
+        let out_block = builder.create_block();
+        let ok_block = builder.create_block();
+
+        let out =
+            match self.boundary {
+                Boundary::Error | Boundary::Grow | Boundary::Guard if n < 0 => builder
+                    .ins()
+                    .icmp_imm(IntCC::SignedLessThan, pointer_plus, 0),
+                Boundary::Error | Boundary::Guard => builder.ins().icmp_imm(
+                    IntCC::UnsignedGreaterThanOrEqual,
+                    pointer_plus,
+                    tape_size,
+                ),
+                _ => {
+                    let memory_len = builder.use_var(self.memory_len);
+                    builder
+                        .ins()
+                        .icmp(IntCC::UnsignedGreaterThanOrEqual, pointer_plus, memory_len)
+                }
+            };
+        builder.ins().brnz(out, out_block, &[]);
+        builder.ins().jump(ok_block, &[]);
+
+        builder.seal_block(out_block);
+        builder.switch_to_block(out_block);
+
+        if self.boundary == Boundary::Grow && n >= 0 {
+            let memory_vec =
+                builder
+                    .ins()
+                    .load(self.pointer_type, MemFlags::new(), self.context, 0);
+            let last_byte = builder.ins().iadd_imm(pointer_plus, self.bytes - 1);
+            let inst =
+                self.runtime
+                    .grow_tape
+                    .call(builder, self.pointer_type, &[memory_vec, last_byte]);
+            let (memory_address, memory_len) = match builder.inst_results(inst) {
+                &[a, b] => (a, b),
+                _ => unreachable!(),
+            };
+            builder.def_var(self.memory, memory_address);
+            builder.def_var(self.memory_len, memory_len);
+            builder.ins().jump(ok_block, &[]);
+        } else {
+            let trap = if n < 0 {
+                self.runtime.pointer_underflow
+            } else {
+                self.runtime.pointer_overflow
+            };
+            let position = builder.ins().iconst(self.pointer_type, position as i64);
+            let inst = trap.call(builder, self.pointer_type, &[position]);
+            let result = builder.inst_results(inst)[0];
+            builder.ins().jump(self.exit_block, &[result]);
+        }
+
+        builder.seal_block(ok_block);
+        builder.switch_to_block(ok_block);
+
+        pointer_plus
+    }
+}
+
+/// The functions of the runtime called by the generated code, besides
+/// `bf_core::refill_budget`, that is only called when counting steps.
+#[derive(Clone, Copy)]
+struct Runtime {
+    write: Callee,
+    /// Reads into a cell of the configured width.
+    read: Callee,
+    pointer_underflow: Callee,
+    pointer_overflow: Callee,
+    grow_tape: Callee,
+    find_zero_right: Callee,
+    find_zero_left: Callee,
+}
+impl Runtime {
+    fn new(
+        builder: &mut FunctionBuilder,
+        resolve: &mut Resolve,
+        config: &Config,
+        call_conv: CallConv,
+        pointer_type: Type,
+    ) -> Runtime {
+        let ptr = pointer_type;
+        let read = match config.cell_width {
+            CellWidth::U8 => "bf_read_u8",
+            CellWidth::U16 => "bf_read_u16",
+            CellWidth::U32 => "bf_read_u32",
+        };
+        let mut import = |name: &str, call_conv, params: &[Type], returns: &[Type]| {
+            resolve(builder.func, name, signature(call_conv, params, returns))
+        };
+
+        // the runtime functions of bf_core use the sysv64 calling convention
+        let sysv = CallConv::SystemV;
+        Runtime {
+            write: import("bf_write", call_conv, &[ptr, I8], &[ptr]),
+            read: import(read, call_conv, &[ptr, ptr], &[ptr]),
+            pointer_underflow: import("bf_pointer_underflow", sysv, &[ptr], &[ptr]),
+            pointer_overflow: import("bf_pointer_overflow", sysv, &[ptr], &[ptr]),
+            grow_tape: import("bf_grow_tape", sysv, &[ptr, ptr], &[ptr, ptr]),
+            find_zero_right: import("bf_find_zero_right", sysv, &[ptr, ptr, ptr], &[ptr]),
+            find_zero_left: import("bf_find_zero_left", sysv, &[ptr, ptr, ptr], &[ptr]),
+        }
+    }
+}
+
+/// A function called by the generated code.
+#[derive(Clone, Copy)]
+enum Callee {
+    /// A function at a fixed address, called indirectly, when compiling to
+    /// memory.
+    Address(SigRef, i64),
+    /// A function imported by name, resolved by the linker, when compiling to
+    /// an object.
+    Import(FuncRef),
+}
+impl Callee {
+    fn call(self, builder: &mut FunctionBuilder, pointer_type: Type, args: &[Value]) -> Inst {
+        match self {
+            Callee::Address(sig, address) => {
+                let address = builder.ins().iconst(pointer_type, address);
+                builder.ins().call_indirect(sig, address, args)
+            }
+            Callee::Import(func_ref) => builder.ins().call(func_ref, args),
+        }
+    }
+}
+
+fn signature(call_conv: CallConv, params: &[Type], returns: &[Type]) -> Signature {
+    let mut signature = Signature::new(call_conv);
+    signature
+        .params
+        .extend(params.iter().map(|&t| AbiParam::new(t)));
+    signature
+        .returns
+        .extend(returns.iter().map(|&t| AbiParam::new(t)));
+    signature
+}
+
+/// The state needed to emit the counting of steps of the `Budget` in the
+/// `RunContext`.
+struct Steps {
+    /// The steps left before calling `bf_core::refill_budget`. They are kept in
+    /// a variable, and only stored to the `Budget` when refilling it.
+    steps: Variable,
+    pointer_type: Type,
+    context: Value,
+    exit_block: Block,
+    refill_budget: Callee,
+}
+impl Steps {
+    fn new(
+        builder: &mut FunctionBuilder,
+        resolve: &mut Resolve,
+        pointer_type: Type,
+        context: Value,
+        exit_block: Block,
+    ) -> Steps {
+        // `bf_core::refill_budget` use the sysv64 calling convention
+        let signature = signature(CallConv::SystemV, &[pointer_type], &[pointer_type]);
+        let refill_budget = resolve(builder.func, "bf_refill_budget", signature);
+
+        let steps = Variable::new(3);
+        builder.declare_var(steps, I64);
+        let steps_value = builder
+            .ins()
+            .load(I64, MemFlags::new(), context, BUDGET_OFFSET);
+        builder.def_var(steps, steps_value);
+
+        Steps {
+            steps,
+            pointer_type,
+            context,
+            exit_block,
+            refill_budget,
+        }
+    }
+
+    /// Emit code that consumes a step, and jumps to `exit_block` with the
+    /// error of the `Budget` when there is none left.
+    fn emit_step(&self, builder: &mut FunctionBuilder) {
+        let decrement_block = builder.create_block();
+        let refill_block = builder.create_block();
+        let after_block = builder.create_block();
+
+        let steps_value = builder.use_var(self.steps);
+        builder.ins().brz(steps_value, refill_block, &[]);
+        builder.ins().jump(decrement_block, &[]);
+
+        builder.seal_block(decrement_block);
+        builder.switch_to_block(decrement_block);
+
+        let steps_minus = builder.ins().iadd_imm(steps_value, -1);
+        builder.def_var(self.steps, steps_minus);
+        builder.ins().jump(after_block, &[]);
+
+        builder.seal_block(refill_block);
+        builder.switch_to_block(refill_block);
+
+        let budget = builder.ins().iadd_imm(self.context, BUDGET_OFFSET as i64);
+        let inst = self
+            .refill_budget
+            .call(builder, self.pointer_type, &[budget]);
+        let result = builder.inst_results(inst)[0];
+        builder.ins().brnz(result, self.exit_block, &[result]);
+
+        let refill_ok_block = builder.create_block();
+        builder.ins().jump(refill_ok_block, &[]);
+        builder.seal_block(refill_ok_block);
+        builder.switch_to_block(refill_ok_block);
+
+        let steps_value = builder
+            .ins()
+            .load(I64, MemFlags::new(), self.context, BUDGET_OFFSET);
+        builder.def_var(self.steps, steps_value);
+        builder.ins().jump(after_block, &[]);
+
+        builder.seal_block(after_block);
+        builder.switch_to_block(after_block);
+    }
+}
+
+/// The offset of `RunContext::budget`, and of the steps left at its start.
+const BUDGET_OFFSET: i32 = std::mem::offset_of!(RunContext<'static>, budget) as i32;
+
+/// The state of a running program, that the generated code passes to the
+/// callbacks.
+#[repr(C)]
+struct RunContext<'a> {
+    /// Read by the generated code to grow the tape, so it must be the first
+    /// field.
+    memory: *mut Vec<u8>,
+    /// Its steps are decremented by the generated code on each back-edge.
+    budget: Budget,
+    input: &'a mut dyn Read,
+    output: Output<&'a mut dyn Write>,
+    eof: Eof,
+}
+
+unsafe extern "C" fn write(context: *mut RunContext, value: u8) -> *mut RuntimeError {
+    let context = &mut *context;
+
+    // Writing a non-UTF-8 byte sequence on Windows error out.
+    if cfg!(target_os = "windows") && value >= 128 {
+        return std::ptr::null_mut();
+    }
+
+    match context.output.write(value) {
+        Err(err) => Box::into_raw(Box::new(err.into())),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Read a byte into `cell`, that may be unaligned. On the end of the input,
+/// `context.eof` is applied to the cell instead.
+unsafe extern "C" fn read<T: Cell>(context: *mut RunContext, cell: *mut T) -> *mut RuntimeError {
+    let context = &mut *context;
+    if let Err(err) = context.output.flush() {
+        return Box::into_raw(Box::new(err.into()));
+    }
+
+    loop {
+        let mut value = 0;
+        let err = context.input.read_exact(std::slice::from_mut(&mut value));
+
+        if let Err(err) = err {
+            if err.kind() != std::io::ErrorKind::UnexpectedEof {
+                return Box::into_raw(Box::new(err.into()));
+            }
+            let mut cell_value = cell.read_unaligned();
+            context.eof.apply(&mut cell_value);
+            cell.write_unaligned(cell_value);
+            return std::ptr::null_mut();
+        }
+
+        // ignore CR from Window's CRLF
+        if cfg!(target_os = "windows") && value == b'\r' {
+            continue;
+        }
+
+        cell.write_unaligned(T::from_byte(value));
+
+        return std::ptr::null_mut();
+    }
+}
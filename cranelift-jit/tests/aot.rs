@@ -0,0 +1,101 @@
+//! Compile programs ahead of time to executables with `--output`, or with
+//! `compile_object` and `link`, and compare their output and errors with the
+//! ones of the simple `interpreter`.
+
+use std::process::Command;
+
+use bf_core::{Boundary, CellWidth, Config, Eof, RuntimeError};
+use bf_testing::{Backend, Outcome};
+
+/// The programs compiled to an object, and linked with the runtime.
+struct Executable;
+impl Backend for Executable {
+    fn run(&self, source: &[u8], config: &Config, input: &[u8]) -> Outcome {
+        let exe_path = bf_testing::temp_path("program");
+        let object = bf_cranelift_jit::compile_object(source, config, false).unwrap();
+        bf_cranelift_jit::link(&object, &exe_path).unwrap();
+
+        let outcome = bf_testing::run_command(&mut Command::new(&exe_path), input);
+        std::fs::remove_file(&exe_path).unwrap();
+        outcome
+    }
+
+    fn message(&self, source: &[u8], err: RuntimeError) -> String {
+        bf_testing::message(source, err, true)
+    }
+}
+
+/// Return true if `cc` can be run to link the executables, since the tests are
+/// skipped without it.
+fn has_cc() -> bool {
+    let found = Command::new("cc").arg("--version").output().is_ok();
+    if !found {
+        eprintln!("skipped, `cc` was not found");
+    }
+    found
+}
+
+#[test]
+fn missing_path() {
+    for option in ["-o", "--output", "-d", "--dump"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_bf-cranelift-jit"))
+            .arg("program.bf")
+            .arg(option)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            format!("expected a path after {}\n", option)
+        );
+    }
+}
+
+#[test]
+fn programs() {
+    if !has_cc() {
+        return;
+    }
+    bf_testing::programs(&Executable);
+}
+
+/// Random programs, that end or fail in a limited number of steps, on tapes
+/// small enough for them to leave often.
+#[test]
+fn random() {
+    if !has_cc() {
+        return;
+    }
+    bf_testing::random(&Executable, 200, |random| Config {
+        tape_size: 3 + random.below(5) as usize,
+        cell_width: *random.choose(&[CellWidth::U8, CellWidth::U16, CellWidth::U32]),
+        boundary: *random.choose(&[Boundary::Wrap, Boundary::Error, Boundary::Grow]),
+        eof: *random.choose(&[Eof::Zero, Eof::MinusOne, Eof::Unchanged]),
+        prefix_steps: *random.choose(&[0, 7, 1 << 20]),
+        ..Config::default()
+    });
+}
+
+/// The executable written by `--output` runs the program.
+#[test]
+fn output() {
+    if !has_cc() {
+        return;
+    }
+    let source_path = bf_testing::temp_path("program.bf");
+    let exe_path = bf_testing::temp_path("program");
+    std::fs::write(&source_path, b"++++++++[>++++++++<-]>+.+.+.").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bf-cranelift-jit"))
+        .arg(&source_path)
+        .arg("--output")
+        .arg(&exe_path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let outcome = bf_testing::run_command(&mut Command::new(&exe_path), b"");
+    assert_eq!(outcome, (b"ABC".to_vec(), None));
+    std::fs::remove_file(&source_path).unwrap();
+    std::fs::remove_file(&exe_path).unwrap();
+}
@@ -0,0 +1,76 @@
+//! The steps and the running time of the programs can be limited.
+
+use std::time::{Duration, Instant};
+
+use bf_core::{Config, RuntimeError};
+use bf_cranelift_jit::Program;
+
+fn run(source: &[u8], config: &Config) -> (Vec<u8>, Result<(), RuntimeError>) {
+    let mut output = Vec::new();
+    let result = Program::new(source, config, false)
+        .unwrap()
+        .run_with_io(&b""[..], &mut output);
+    (output, result)
+}
+
+/// An infinite loop stops soon after its timeout.
+#[test]
+fn timeout() {
+    let config = Config {
+        timeout: Some(Duration::from_millis(100)),
+        ..Config::default()
+    };
+    let start = Instant::now();
+    let (_, result) = run(b"+[]", &config);
+    assert!(matches!(result, Err(RuntimeError::Timeout)), "{:?}", result);
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn fuel() {
+    let config = Config {
+        fuel: Some(1000),
+        ..Config::default()
+    };
+    let (_, result) = run(b"+[]", &config);
+    assert!(
+        matches!(result, Err(RuntimeError::OutOfFuel)),
+        "{:?}",
+        result
+    );
+
+    let (output, result) = run(b"+++++[-.]", &config);
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(output, b"\x04\x03\x02\x01\x00");
+}
+
+/// A running program is stopped by cancelling it from another thread, and a
+/// program cancelled before running stops as soon as it starts.
+#[test]
+fn cancel() {
+    let config = Config {
+        cancellable: true,
+        ..Config::default()
+    };
+    let mut program = Program::new(b"+[]", &config, false).unwrap();
+    let cancel = program.cancel_handle();
+    let canceller = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        cancel.cancel();
+    });
+    let result = program.run_with_io(&b""[..], Vec::new());
+    assert!(
+        matches!(result, Err(RuntimeError::Cancelled)),
+        "{:?}",
+        result
+    );
+    canceller.join().unwrap();
+
+    program.cancel_handle().cancel();
+    let result = program.run_with_io(&b""[..], Vec::new());
+    assert!(
+        matches!(result, Err(RuntimeError::Cancelled)),
+        "{:?}",
+        result
+    );
+}
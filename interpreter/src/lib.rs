@@ -0,0 +1,164 @@
+//! A simple brainfuck interpreter, that executes the unoptimized instructions
+//! returned by `bf_core::parse`.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use bf_core::{Boundary, Budget, Cell, Config, Eof, Instruction, RuntimeError, UnbalancedBrackets};
+
+#[derive(Default, Debug)]
+#[cfg(feature = "profile")]
+pub struct Profile {
+    inc: u64,
+    dec: u64,
+    movr: u64,
+    movl: u64,
+    jr: u64,
+    jl: u64,
+    inp: u64,
+    out: u64,
+}
+
+pub struct Program<T: Cell> {
+    program_counter: usize,
+    pointer: usize,
+    instructions: Vec<Instruction>,
+    positions: Vec<usize>,
+    memory: Vec<T>,
+    boundary: Boundary,
+    eof: Eof,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    #[cfg(feature = "profile")]
+    pub profile: Profile,
+}
+impl<T: Cell> Program<T> {
+    pub fn new(source: &[u8], config: &Config) -> Result<Program<T>, UnbalancedBrackets> {
+        let ir = bf_core::parse(source)?;
+
+        Ok(Program {
+            program_counter: 0,
+            pointer: 0,
+            instructions: ir.instructions,
+            positions: ir.positions,
+            memory: vec![T::default(); config.tape_size],
+            boundary: config.boundary,
+            eof: config.eof,
+            fuel: config.fuel,
+            timeout: config.timeout,
+            #[cfg(feature = "profile")]
+            profile: Profile::default(),
+        })
+    }
+
+    /// Return the index of the cell at `offset` from the pointer, handling
+    /// moves out of the tape according to `self.boundary`.
+    fn offset_pointer(&mut self, offset: isize) -> Result<usize, RuntimeError> {
+        let len = self.memory.len() as isize;
+        let to = self.pointer as isize + offset;
+        if (0..len).contains(&to) {
+            return Ok(to as usize);
+        }
+
+        let position = self.positions[self.program_counter];
+        match self.boundary {
+            Boundary::Wrap => Ok(to.rem_euclid(len) as usize),
+            Boundary::Error | Boundary::Grow | Boundary::Guard if to < 0 => {
+                Err(RuntimeError::PointerUnderflow(position))
+            }
+            Boundary::Error | Boundary::Guard => Err(RuntimeError::PointerOverflow(position)),
+            Boundary::Grow => {
+                let len = (to as usize + 1).max(self.memory.len() * 2);
+                self.memory.resize(len, T::default());
+                Ok(to as usize)
+            }
+        }
+    }
+
+    /// Run the program, reading from stdin and writing to stdout.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.run_with_io(std::io::stdin().lock(), std::io::stdout().lock())
+    }
+
+    /// Run the program, reading from `input` and writing to `output`.
+    pub fn run_with_io(
+        &mut self,
+        mut input: impl Read,
+        mut output: impl Write,
+    ) -> Result<(), RuntimeError> {
+        let mut budget = Budget::new(self.fuel, self.timeout, None);
+
+        while self.program_counter < self.instructions.len() {
+            use Instruction::*;
+
+            #[cfg(feature = "profile")]
+            {
+                match self.instructions[self.program_counter] {
+                    Add(_, 1) => self.profile.inc += 1,
+                    Add(..) => self.profile.dec += 1,
+                    Output(_) => self.profile.out += 1,
+                    Input(_) => self.profile.inp += 1,
+                    Move(1) => self.profile.movr += 1,
+                    Move(_) => self.profile.movl += 1,
+                    JumpRight(_) => self.profile.jr += 1,
+                    JumpLeft(_) => self.profile.jl += 1,
+                    Set(..) | Clear(_) | MulAdd { .. } | MoveUntil(_) | Check(_) => unreachable!(),
+                }
+            }
+
+            match self.instructions[self.program_counter] {
+                Add(0, n) => self.memory[self.pointer] = self.memory[self.pointer].add_wrapping(n),
+                Output(0) => {
+                    let value = self.memory[self.pointer].to_byte();
+                    // Writing a non-UTF-8 byte sequence on Windows error out.
+                    if !cfg!(target_os = "windows") || value < 128 {
+                        output.write_all(&[value])?;
+                        output.flush()?;
+                    }
+                }
+                Input(0) => loop {
+                    let mut value = 0;
+                    let err = input.read_exact(std::slice::from_mut(&mut value));
+                    match err.as_ref().map_err(|e| e.kind()) {
+                        Err(std::io::ErrorKind::UnexpectedEof) => {
+                            self.eof.apply(&mut self.memory[self.pointer]);
+                            break;
+                        }
+                        _ => err?,
+                    }
+                    if cfg!(target_os = "windows") && value == b'\r' {
+                        continue;
+                    }
+                    self.memory[self.pointer] = T::from_byte(value);
+                    break;
+                },
+                Move(n) => self.pointer = self.offset_pointer(n)?,
+                JumpRight(pair_address) => {
+                    if self.memory[self.pointer] == T::default() {
+                        self.program_counter = pair_address;
+                    }
+                }
+                JumpLeft(pair_address) => {
+                    budget.step()?;
+                    if self.memory[self.pointer] != T::default() {
+                        self.program_counter = pair_address;
+                    }
+                }
+                // `bf_core::parse` only emit instructions on the current cell,
+                // and don't emit optimized instructions.
+                Add(..)
+                | Output(_)
+                | Input(_)
+                | Set(..)
+                | Clear(_)
+                | MulAdd { .. }
+                | MoveUntil(_)
+                | Check(_) => {
+                    unreachable!()
+                }
+            }
+            self.program_counter += 1;
+        }
+        Ok(())
+    }
+}
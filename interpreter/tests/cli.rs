@@ -0,0 +1,49 @@
+//! Run the `bf-interpreter` executable, and check its errors and exit codes.
+
+use std::process::{Command, Output};
+
+/// Write `source` to a file named `name`, and run the interpreter on it with
+/// the options `args`.
+fn run(name: &str, source: &[u8], args: &[&str]) -> Output {
+    let dir = std::env::temp_dir().join(format!("bf-interpreter-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, source).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_bf-interpreter"))
+        .args(args)
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn unbalanced_brackets() {
+    let output = run("unbalanced.bf", b"+[\n>+]]\n", &[]);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.starts_with("error: unmatched `]`\n"), "{}", stderr);
+    assert!(stderr.contains("unbalanced.bf:2:4\n"), "{}", stderr);
+    assert!(stderr.ends_with("2 | >+]]\n  |    ^\n"), "{}", stderr);
+}
+
+/// An infinite loop is stopped by `--timeout`, or `--fuel`, with the exit code
+/// of the runtime errors, 5.
+#[test]
+fn limits() {
+    let start = std::time::Instant::now();
+    let output = run("timeout.bf", b"+[]", &["--timeout", "0.1"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(output.stderr, b"error: program timed out\n");
+    assert!(start.elapsed() < std::time::Duration::from_secs(10));
+
+    let output = run("fuel.bf", b"+[]", &["--fuel", "1000"]);
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(output.stderr, b"error: program ran out of fuel\n");
+
+    let output = run("enough-fuel.bf", b"+++++[-.]", &["--fuel", "1000"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, b"\x04\x03\x02\x01\x00");
+}
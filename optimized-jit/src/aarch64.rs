@@ -0,0 +1,855 @@
+//! The AArch64 code generator, used on AArch64 hosts. It can also write the
+//! code as a standalone Linux executable, to test it on other hosts with
+//! `qemu-aarch64`.
+
+use bf_core::{Boundary, CellWidth, Config, Eof, Instruction, Ir, Prefix, UnbalancedBrackets};
+use dynasmrt::aarch64::Aarch64Relocation;
+use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi, VecAssembler};
+
+use crate::{read, write, BUDGET_OFFSET};
+
+type Assembler = VecAssembler<Aarch64Relocation>;
+
+/// The register of the pointer, in bytes.
+const POINTER: u32 = 20;
+/// The register of the byte index computed by `emit_offset`.
+const INDEX: u32 = 9;
+
+/// The addresses of the functions called by the generated code, with the
+/// signatures of the ones of the same name of `bf_core` and the crate root.
+struct Runtime {
+    write: u64,
+    read: u64,
+    pointer_underflow: u64,
+    pointer_overflow: u64,
+    refill_budget: u64,
+    grow_tape: u64,
+    /// `find_zero_right` and `find_zero_left`. Without them, `MoveUntil`
+    /// always moves one cell at a time.
+    find_zero: Option<(u64, u64)>,
+}
+
+/// Compile `ir` to a function with the signature of `CodeFn`, that runs it
+/// from the state left by `prefix`, and calls the runtime of the host.
+pub fn compile_for_host(ir: &Ir, config: &Config, prefix: &Prefix, in_bounds: &[bool]) -> Vec<u8> {
+    let read = match config.cell_width {
+        CellWidth::U8 => read::<u8> as *const (),
+        CellWidth::U16 => read::<u16> as *const (),
+        CellWidth::U32 => read::<u32> as *const (),
+    };
+    let runtime = Runtime {
+        write: write as *const () as u64,
+        read: read as u64,
+        pointer_underflow: bf_core::pointer_underflow as *const () as u64,
+        pointer_overflow: bf_core::pointer_overflow as *const () as u64,
+        refill_budget: bf_core::refill_budget as *const () as u64,
+        grow_tape: bf_core::grow_tape as *const () as u64,
+        find_zero: Some((
+            bf_core::find_zero_right as *const () as u64,
+            bf_core::find_zero_left as *const () as u64,
+        )),
+    };
+
+    let mut code = Assembler::new(0);
+    let start = code.new_dynamic_label();
+    compile(&mut code, start, ir, config, prefix, in_bounds, &runtime);
+    code.finalize().unwrap()
+}
+
+/// Emit the function compiled from `ir` at `start`.
+fn compile(
+    code: &mut Assembler,
+    start: dynasmrt::DynamicLabel,
+    ir: &Ir,
+    config: &Config,
+    prefix: &Prefix,
+    in_bounds: &[bool],
+    runtime: &Runtime,
+) {
+    let cell_width = config.cell_width;
+
+    // x19 will be the adress of `memory`
+    // x20 will be the value of `pointer`, times the cell width
+    // x21 will be the length of `memory`
+    // x22 will be the address of the `Context`
+    // x19, x21 and x22 are got from arguments 1, 2 and 3
+    // x20 is set to the pointer left by the prefix
+    dynasm! { code
+        ; .arch aarch64
+        ; =>start
+        ; stp x29, x30, [sp, #-48]!
+        ; mov x29, sp
+        ; stp x19, x20, [sp, #16]
+        ; stp x21, x22, [sp, #32]
+        ; mov x19, x0
+        ; mov x21, x1
+        ; mov x22, x2
+    };
+    emit_mov_imm(code, POINTER, (prefix.pointer * cell_width.bytes()) as u64);
+
+    // skip the instructions run by the prefix
+    let resume_label = code.new_dynamic_label();
+    dynasm! { code
+        ; .arch aarch64
+        ; b =>resume_label
+    };
+
+    // only count the steps when they are limited
+    let limited = config.fuel.is_some() || config.timeout.is_some() || config.cancellable;
+
+    let mut bracket_stack = Vec::new();
+
+    let len = ir.instructions.len();
+    for (index, (&instr, &position)) in ir.instructions.iter().zip(&ir.positions).enumerate() {
+        if index == prefix.resume {
+            dynasm! { code
+                ; .arch aarch64
+                ; =>resume_label
+            };
+        }
+        match instr {
+            Instruction::Add(offset, n) => {
+                let index = emit_index(code, config, offset, position, in_bounds[index], runtime);
+                emit_load(code, cell_width, 10, index);
+                emit_add_w10(code, n);
+                emit_store(code, cell_width, 10, index);
+            }
+            Instruction::Move(n) => emit_move(code, config, n, position, in_bounds[index], runtime),
+            Instruction::Input(offset) => {
+                let index = emit_index(code, config, offset, position, in_bounds[index], runtime);
+                dynasm! { code
+                    ; .arch aarch64
+                    ; add x1, x19, X(index) // cell address
+                    ; mov x0, x22
+                    ;; emit_call(code, runtime.read)
+                    ; cbnz x0, ->exit
+                }
+            }
+            Instruction::Output(offset) => {
+                let index = emit_index(code, config, offset, position, in_bounds[index], runtime);
+                dynasm! { code
+                    ; .arch aarch64
+                    ; ldrb w1, [x19, X(index)] // cell value
+                    ; mov x0, x22
+                    ;; emit_call(code, runtime.write)
+                    ; cbnz x0, ->exit
+                }
+            }
+            Instruction::JumpRight(_) => {
+                let start_label = code.new_dynamic_label();
+                let end_label = code.new_dynamic_label();
+                // `cbz` only reaches 1 MiB away, and `b` 128 MiB.
+                emit_load(code, cell_width, 10, POINTER);
+                dynasm! { code
+                    ; .arch aarch64
+                    ; cbnz w10, >enter
+                    ; b =>end_label
+                    ; enter:
+                    ; =>start_label
+                };
+
+                bracket_stack.push((start_label, end_label));
+            }
+            Instruction::JumpLeft(_) => {
+                let (start_label, end_label) = bracket_stack.pop().unwrap();
+
+                if limited {
+                    emit_step(code, runtime);
+                }
+                emit_load(code, cell_width, 10, POINTER);
+                dynasm! { code
+                    ; .arch aarch64
+                    ; cbz w10, >leave
+                    ; b =>start_label
+                    ; leave:
+                    ; =>end_label
+                };
+            }
+            Instruction::Set(offset, n) => {
+                let index = emit_index(code, config, offset, position, in_bounds[index], runtime);
+                emit_mov_imm(code, 10, n as u64);
+                emit_store(code, cell_width, 10, index);
+            }
+            Instruction::Clear(offset) => {
+                let index = emit_index(code, config, offset, position, in_bounds[index], runtime);
+                emit_store(code, cell_width, 31, index);
+            }
+            Instruction::MulAdd { offset, factor } => {
+                // the loop replaced by the instruction doesn't access the
+                // cell at `offset` if the current cell is zero, and it may
+                // be out of the tape, or of a guarded tape.
+                if config.boundary == Boundary::Guard || !in_bounds[index] {
+                    emit_load(code, cell_width, 10, POINTER);
+                    dynasm! { code
+                        ; .arch aarch64
+                        ; cbz w10, >skip
+                    }
+                }
+                emit_offset(code, config, offset, position, in_bounds[index], runtime);
+                emit_load(code, cell_width, 10, POINTER);
+                if factor != 1 {
+                    emit_mov_imm(code, 11, factor as u64);
+                    dynasm! { code
+                        ; .arch aarch64
+                        ; mul w10, w10, w11
+                    }
+                }
+                emit_load(code, cell_width, 12, INDEX);
+                dynasm! { code
+                    ; .arch aarch64
+                    ; add w12, w12, w10
+                }
+                emit_store(code, cell_width, 12, INDEX);
+                dynasm! { code
+                    ; .arch aarch64
+                    ; skip:
+                }
+            }
+            Instruction::MoveUntil(n) => {
+                if let Some((right, left)) = runtime.find_zero {
+                    if cell_width == CellWidth::U8 && (n == 1 || n == -1) {
+                        emit_scan(code, config, n, if n > 0 { right } else { left });
+                    }
+                }
+                dynasm! { code
+                    ; .arch aarch64
+                    ; repeat:
+                    ;; emit_load(code, cell_width, 10, POINTER)
+                    ; cbz w10, >exit
+                    ;; emit_move(code, config, n, position, false, runtime)
+                    ;; if limited { emit_step(code, runtime) }
+                    ; b <repeat
+                    ; exit:
+                }
+            }
+            Instruction::Check(offset) => {
+                let in_bounds = in_bounds[index];
+                if config.boundary == Boundary::Guard && in_bounds {
+                    // fault here if the cell is out of the guarded tape
+                    let index = emit_index(code, config, offset, position, in_bounds, runtime);
+                    emit_load(code, CellWidth::U8, 10, index);
+                } else if !in_bounds {
+                    emit_offset(code, config, offset, position, in_bounds, runtime);
+                }
+            }
+        }
+    }
+
+    if prefix.resume == len {
+        dynasm! { code
+            ; .arch aarch64
+            ; =>resume_label
+        };
+    }
+
+    dynasm! { code
+        ; .arch aarch64
+        ; mov x0, xzr
+        ; ->exit:
+        ; ldp x21, x22, [sp, #32]
+        ; ldp x19, x20, [sp, #16]
+        ; ldp x29, x30, [sp], #48
+        ; ret
+    }
+}
+
+/// Emit code that moves the pointer in `x20` by `n` cells. The move is not
+/// checked if it is known to stay `in_bounds`.
+fn emit_move(
+    code: &mut Assembler,
+    config: &Config,
+    n: isize,
+    position: usize,
+    in_bounds: bool,
+    runtime: &Runtime,
+) {
+    // the pointer and the tape size are in bytes, not in cells.
+    let bytes = config.cell_width.bytes() as i64;
+    let n = n as i64;
+    if in_bounds {
+        emit_add_imm(code, POINTER, POINTER, n * bytes);
+        return;
+    }
+    match config.boundary {
+        Boundary::Wrap => {
+            let n = n % config.tape_size as i64 * bytes;
+            emit_add_imm(code, 10, POINTER, n);
+            if n > 0 {
+                dynasm! { code
+                    ; .arch aarch64
+                    ; sub x11, x10, x21
+                    ; cmp x10, x21
+                    ; csel x20, x11, x10, hs
+                }
+            } else {
+                dynasm! { code
+                    ; .arch aarch64
+                    ; add x11, x10, x21
+                    ; cmp x10, #0
+                    ; csel x20, x11, x10, lt
+                }
+            }
+        }
+        Boundary::Error | Boundary::Grow | Boundary::Guard if n < 0 => dynasm! { code
+            ; .arch aarch64
+            ;; emit_add_imm(code, POINTER, POINTER, n * bytes)
+            ; tbz x20, #63, >ok
+            ;; emit_trap(code, runtime.pointer_underflow, position)
+            ; ok:
+        },
+        Boundary::Error | Boundary::Guard => dynasm! { code
+            ; .arch aarch64
+            ;; emit_add_imm(code, POINTER, POINTER, n * bytes)
+            ; cmp x20, x21
+            ; b.lo >ok
+            ;; emit_trap(code, runtime.pointer_overflow, position)
+            ; ok:
+        },
+        Boundary::Grow => dynasm! { code
+            ; .arch aarch64
+            ;; emit_add_imm(code, POINTER, POINTER, n * bytes)
+            ; cmp x20, x21
+            ; b.lo >ok
+            ;; emit_add_imm(code, 1, POINTER, bytes - 1)
+            ;; emit_grow(code, runtime)
+            ; ok:
+        },
+    }
+}
+
+/// Emit code that computes the byte index of the cell at `n` cells from the
+/// pointer, in `x9`. The index is not checked if it is known to be
+/// `in_bounds`.
+fn emit_offset(
+    code: &mut Assembler,
+    config: &Config,
+    n: isize,
+    position: usize,
+    in_bounds: bool,
+    runtime: &Runtime,
+) {
+    // the pointer and the tape size are in bytes, not in cells.
+    let bytes = config.cell_width.bytes() as i64;
+    let n = n as i64;
+    if in_bounds {
+        emit_add_imm(code, INDEX, POINTER, n * bytes);
+        return;
+    }
+    match config.boundary {
+        Boundary::Wrap => {
+            let n = n % config.tape_size as i64 * bytes;
+            emit_add_imm(code, 10, POINTER, n);
+            if n > 0 {
+                dynasm! { code
+                    ; .arch aarch64
+                    ; sub x9, x10, x21
+                    ; cmp x10, x21
+                    ; csel x9, x9, x10, hs
+                }
+            } else {
+                dynasm! { code
+                    ; .arch aarch64
+                    ; add x9, x10, x21
+                    ; cmp x10, #0
+                    ; csel x9, x9, x10, lt
+                }
+            }
+        }
+        Boundary::Error | Boundary::Grow | Boundary::Guard if n < 0 => dynasm! { code
+            ; .arch aarch64
+            ;; emit_add_imm(code, INDEX, POINTER, n * bytes)
+            ; tbz x9, #63, >ok
+            ;; emit_trap(code, runtime.pointer_underflow, position)
+            ; ok:
+        },
+        Boundary::Error | Boundary::Guard => dynasm! { code
+            ; .arch aarch64
+            ;; emit_add_imm(code, INDEX, POINTER, n * bytes)
+            ; cmp x9, x21
+            ; b.lo >ok
+            ;; emit_trap(code, runtime.pointer_overflow, position)
+            ; ok:
+        },
+        Boundary::Grow => dynasm! { code
+            ; .arch aarch64
+            ;; emit_add_imm(code, INDEX, POINTER, n * bytes)
+            ; cmp x9, x21
+            ; b.lo >ok
+            ;; emit_add_imm(code, 1, INDEX, bytes - 1)
+            ;; emit_grow(code, runtime)
+            ;; emit_add_imm(code, INDEX, POINTER, n * bytes)
+            ; ok:
+        },
+    }
+}
+
+/// Emit code that computes the byte index of the cell at `n` cells from the
+/// pointer, and return the register that contains it: `x20` itself when `n`
+/// is 0, or `x9`.
+fn emit_index(
+    code: &mut Assembler,
+    config: &Config,
+    n: isize,
+    position: usize,
+    in_bounds: bool,
+    runtime: &Runtime,
+) -> u32 {
+    if n == 0 {
+        return POINTER;
+    }
+    emit_offset(code, config, n, position, in_bounds, runtime);
+    INDEX
+}
+
+/// Emit code that moves the pointer in `x20` to the first zero byte in
+/// `direction`, 1 or -1, using `find`, `find_zero_right` or `find_zero_left`.
+/// If there is none, the pointer is left on the last byte before leaving the
+/// tape, or unchanged when wrapping around, for the following `MoveUntil`
+/// loop to handle.
+fn emit_scan(code: &mut Assembler, config: &Config, direction: isize, find: u64) {
+    dynasm! { code
+        ; .arch aarch64
+        ; ldrb w10, [x19, x20]
+        ; cbz w10, >done
+        ; mov x0, x19
+        ; mov x1, x21
+        ; mov x2, x20
+        ;; emit_call(code, find)
+        ; cmp x0, x21
+        ; b.lo >found
+    }
+    match config.boundary {
+        // search the other side of the tape
+        Boundary::Wrap if direction > 0 => dynasm! { code
+            ; .arch aarch64
+            ; mov x0, x19
+            ; mov x1, x21
+            ; mov x2, xzr
+            ;; emit_call(code, find)
+            ; cmp x0, x21
+            ; b.lo >found
+            ; b >done
+        },
+        Boundary::Wrap => dynasm! { code
+            ; .arch aarch64
+            ; mov x0, x19
+            ; mov x1, x21
+            ; sub x2, x21, #1
+            ;; emit_call(code, find)
+            ; cmp x0, x21
+            ; b.lo >found
+            ; b >done
+        },
+        _ if direction > 0 => dynasm! { code
+            ; .arch aarch64
+            ; sub x0, x21, #1
+        },
+        _ => dynasm! { code
+            ; .arch aarch64
+            ; mov x0, xzr
+        },
+    }
+    dynasm! { code
+        ; .arch aarch64
+        ; found:
+        ; mov x20, x0
+        ; done:
+    }
+}
+
+/// Emit code that loads the cell at the byte index in `x<index>` to
+/// `w<reg>`.
+fn emit_load(code: &mut Assembler, cell_width: CellWidth, reg: u32, index: u32) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch aarch64
+            ; ldrb W(reg), [x19, X(index)]
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch aarch64
+            ; ldrh W(reg), [x19, X(index)]
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch aarch64
+            ; ldr W(reg), [x19, X(index)]
+        },
+    }
+}
+
+/// Emit code that stores `w<reg>` to the cell at the byte index in
+/// `x<index>`. The register 31 is the zero register.
+fn emit_store(code: &mut Assembler, cell_width: CellWidth, reg: u32, index: u32) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch aarch64
+            ; strb W(reg), [x19, X(index)]
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch aarch64
+            ; strh W(reg), [x19, X(index)]
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch aarch64
+            ; str W(reg), [x19, X(index)]
+        },
+    }
+}
+
+/// Emit code that adds `n` to `w10`.
+fn emit_add_w10(code: &mut Assembler, n: u32) {
+    if n < 4096 {
+        dynasm! { code
+            ; .arch aarch64
+            ; add w10, w10, #n
+        }
+    } else if n.wrapping_neg() < 4096 {
+        dynasm! { code
+            ; .arch aarch64
+            ; sub w10, w10, #n.wrapping_neg()
+        }
+    } else {
+        emit_mov_imm(code, 11, n as u64);
+        dynasm! { code
+            ; .arch aarch64
+            ; add w10, w10, w11
+        }
+    }
+}
+
+/// Emit code that sets `x<dst>` to `x<src>` plus `n`. It uses `x17` when `n`
+/// doesn't fit in an immediate.
+fn emit_add_imm(code: &mut Assembler, dst: u32, src: u32, n: i64) {
+    let magnitude = n.unsigned_abs();
+    if magnitude < 4096 {
+        let magnitude = magnitude as u32;
+        if n >= 0 {
+            dynasm! { code
+                ; .arch aarch64
+                ; add X(dst), X(src), #magnitude
+            }
+        } else {
+            dynasm! { code
+                ; .arch aarch64
+                ; sub X(dst), X(src), #magnitude
+            }
+        }
+    } else {
+        emit_mov_imm(code, 17, n as u64);
+        dynasm! { code
+            ; .arch aarch64
+            ; add X(dst), X(src), x17
+        }
+    }
+}
+
+/// Emit code that sets `x<reg>` to `value`.
+fn emit_mov_imm(code: &mut Assembler, reg: u32, value: u64) {
+    let low = (value & 0xffff) as u32;
+    dynasm! { code
+        ; .arch aarch64
+        ; movz X(reg), #low
+    }
+    for shift in [16, 32, 48] {
+        let part = ((value >> shift) & 0xffff) as u32;
+        if part != 0 {
+            dynasm! { code
+                ; .arch aarch64
+                ; movk X(reg), #part, lsl #shift
+            }
+        }
+    }
+}
+
+/// Emit a call to the function at `address`.
+fn emit_call(code: &mut Assembler, address: u64) {
+    emit_mov_imm(code, 16, address);
+    dynasm! { code
+        ; .arch aarch64
+        ; blr x16
+    }
+}
+
+/// Emit a call to `trap`, and return the error it creates.
+fn emit_trap(code: &mut Assembler, trap: u64, position: usize) {
+    emit_mov_imm(code, 0, position as u64);
+    emit_call(code, trap);
+    dynasm! { code
+        ; .arch aarch64
+        ; b ->exit
+    }
+}
+
+/// Emit code that consumes a step of the `Budget` of the program, and exits
+/// with its error when there is none left.
+fn emit_step(code: &mut Assembler, runtime: &Runtime) {
+    let offset = BUDGET_OFFSET as u32;
+    dynasm! { code
+        ; .arch aarch64
+        ; ldr x10, [x22, #offset]
+        ; subs x10, x10, #1
+        ; str x10, [x22, #offset]
+        ; b.hs >ok
+        ; add x0, x22, #offset
+        ;; emit_call(code, runtime.refill_budget)
+        ; cbnz x0, ->exit
+        ; ok:
+    }
+}
+
+/// Emit a call to `bf_core::grow_tape`, to make the byte at index `x1`
+/// valid.
+fn emit_grow(code: &mut Assembler, runtime: &Runtime) {
+    dynasm! { code
+        ; .arch aarch64
+        ; ldr x0, [x22] // context.memory
+        ;; emit_call(code, runtime.grow_tape)
+        ; mov x19, x0
+        ; mov x21, x1
+    }
+}
+
+/// Make the instruction cache see the code written at `start`.
+///
+/// # Safety
+///
+/// `start` must be valid for reads of `len` bytes.
+#[cfg(target_arch = "aarch64")]
+pub unsafe fn flush_instruction_cache(start: *const u8, len: usize) {
+    use std::arch::asm;
+
+    let ctr: usize;
+    asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack, preserves_flags));
+    let data_line = 4 << ((ctr >> 16) & 0xf);
+    let instruction_line = 4 << (ctr & 0xf);
+
+    let end = start as usize + len;
+    let mut address = start as usize & !(data_line - 1);
+    while address < end {
+        asm!("dc cvau, {}", in(reg) address, options(nostack, preserves_flags));
+        address += data_line;
+    }
+    asm!("dsb ish", options(nostack, preserves_flags));
+    let mut address = start as usize & !(instruction_line - 1);
+    while address < end {
+        asm!("ic ivau, {}", in(reg) address, options(nostack, preserves_flags));
+        address += instruction_line;
+    }
+    asm!("dsb ish", "isb", options(nostack, preserves_flags));
+}
+
+/// The address where the executables load their headers, data and code.
+const BASE: u64 = 0x40_0000;
+/// The address where the executables load their tape, far enough from the
+/// code for any tape size.
+const TAPE: u64 = 0x1_0000_0000;
+/// The alignment of the segments of the executables, the largest page size
+/// of AArch64 Linux.
+const ALIGN: u64 = 0x1_0000;
+/// The size of the ELF header and of the two program headers.
+const HEADERS_SIZE: u64 = 64 + 2 * 56;
+
+/// Compile `source` to a static AArch64 Linux executable, that doesn't need
+/// the rest of the crate at runtime: it reads and writes with system calls,
+/// and exits with the code 5 on a runtime error, after printing its message
+/// without its location.
+///
+/// The steps can't be limited, and `Boundary::Grow` and `Boundary::Guard` are
+/// not supported.
+pub fn executable(source: &[u8], config: &Config) -> Result<Vec<u8>, UnbalancedBrackets> {
+    assert!(config.fuel.is_none() && config.timeout.is_none() && !config.cancellable);
+    assert!(matches!(config.boundary, Boundary::Wrap | Boundary::Error));
+
+    let ir = bf_core::parse(source)?;
+    let (ir, _) = bf_core::optimize(&ir, config);
+    let prefix = Prefix::evaluate(&ir, config);
+    let in_bounds = bf_core::in_bounds(&ir, config);
+    let cell_width = config.cell_width;
+    let tape_len = (config.tape_size * cell_width.bytes()) as u64;
+
+    // the headers are followed by the data, the code, and at the next
+    // aligned offset, the tape, that is loaded at `TAPE`.
+    let underflow_message = b"error: pointer moved left of cell 0\n";
+    let overflow_message = b"error: pointer moved right of the last cell\n";
+    let mut data = Vec::new();
+    let underflow_address = BASE + HEADERS_SIZE + data.len() as u64;
+    data.extend_from_slice(underflow_message);
+    let overflow_address = BASE + HEADERS_SIZE + data.len() as u64;
+    data.extend_from_slice(overflow_message);
+    let output_address = BASE + HEADERS_SIZE + data.len() as u64;
+    data.extend_from_slice(&prefix.output);
+    data.resize(data.len().next_multiple_of(4), 0);
+    let code_address = BASE + HEADERS_SIZE + data.len() as u64;
+
+    let mut code = Assembler::new(0);
+    let start = code.new_dynamic_label();
+    let address = |code: &Assembler| code_address + code.offset().0 as u64;
+
+    // write the output of the prefix, run the program, and exit with its
+    // result, 0, since the runtime exits by itself on the errors.
+    emit_mov_imm(&mut code, 1, output_address);
+    emit_mov_imm(&mut code, 2, prefix.output.len() as u64);
+    dynasm! { code
+        ; .arch aarch64
+        ; cbz x2, >done
+        ; write:
+        ; mov x0, #1
+        ; mov x8, #64 // write
+        ; svc #0
+        ; cmp x0, #0
+        ; b.le >done
+        ; add x1, x1, x0
+        ; subs x2, x2, x0
+        ; b.ne <write
+        ; done:
+        ;; emit_mov_imm(&mut code, 0, TAPE)
+        ;; emit_mov_imm(&mut code, 1, tape_len)
+        ; mov x2, xzr
+        ; bl =>start
+        ; mov x8, #93 // exit
+        ; svc #0
+    }
+
+    let write = address(&code);
+    dynasm! { code
+        ; .arch aarch64
+        ; sub sp, sp, #16
+        ; strb w1, [sp]
+        ; mov x0, #1
+        ; mov x1, sp
+        ; mov x2, #1
+        ; mov x8, #64 // write
+        ; svc #0
+        ; add sp, sp, #16
+        ; mov x0, xzr
+        ; ret
+    }
+
+    let read = address(&code);
+    dynasm! { code
+        ; .arch aarch64
+        ; sub sp, sp, #16
+        ; mov x9, x1
+        ; mov x0, xzr
+        ; mov x1, sp
+        ; mov x2, #1
+        ; mov x8, #63 // read
+        ; svc #0
+        ; ldrb w10, [sp]
+        ; add sp, sp, #16
+        ; cmp x0, #1
+        ; b.eq >store
+    }
+    match config.eof {
+        Eof::Zero => dynasm! { code
+            ; .arch aarch64
+            ; mov w10, wzr
+        },
+        Eof::MinusOne => dynasm! { code
+            ; .arch aarch64
+            ; movn w10, #0
+        },
+        Eof::Unchanged => dynasm! { code
+            ; .arch aarch64
+            ; b >done
+        },
+    }
+    dynasm! { code
+        ; .arch aarch64
+        ; store:
+    }
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch aarch64
+            ; strb w10, [x9]
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch aarch64
+            ; strh w10, [x9]
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch aarch64
+            ; str w10, [x9]
+        },
+    }
+    dynasm! { code
+        ; .arch aarch64
+        ; done:
+        ; mov x0, xzr
+        ; ret
+    }
+
+    let trap = |code: &mut Assembler, message: u64, len: usize| {
+        let trap = address(code);
+        dynasm! { code
+            ; .arch aarch64
+            ; mov x0, #2
+            ;; emit_mov_imm(code, 1, message)
+            ;; emit_mov_imm(code, 2, len as u64)
+            ; mov x8, #64 // write
+            ; svc #0
+            ; mov x0, #5
+            ; mov x8, #93 // exit
+            ; svc #0
+        }
+        trap
+    };
+    let pointer_underflow = trap(&mut code, underflow_address, underflow_message.len());
+    let pointer_overflow = trap(&mut code, overflow_address, overflow_message.len());
+
+    let runtime = Runtime {
+        write,
+        read,
+        pointer_underflow,
+        pointer_overflow,
+        // not called without a limit on the steps, nor a growing tape
+        refill_budget: 0,
+        grow_tape: 0,
+        find_zero: None,
+    };
+    compile(&mut code, start, &ir, config, &prefix, &in_bounds, &runtime);
+    let code = code.finalize().unwrap();
+
+    let text_len = HEADERS_SIZE + (data.len() + code.len()) as u64;
+
+    let mut elf = Vec::new();
+    // the ELF header
+    elf.extend_from_slice(b"\x7fELF");
+    elf.extend_from_slice(&[2, 1, 1, 0]); // 64-bit, little-endian, version 1, System V
+    elf.extend_from_slice(&[0; 8]);
+    elf.extend_from_slice(&2u16.to_le_bytes()); // executable
+    elf.extend_from_slice(&183u16.to_le_bytes()); // AArch64
+    elf.extend_from_slice(&1u32.to_le_bytes()); // version
+    elf.extend_from_slice(&code_address.to_le_bytes()); // entry point
+    elf.extend_from_slice(&64u64.to_le_bytes()); // program headers offset
+    elf.extend_from_slice(&0u64.to_le_bytes()); // section headers offset
+    elf.extend_from_slice(&0u32.to_le_bytes()); // flags
+    elf.extend_from_slice(&64u16.to_le_bytes()); // ELF header size
+    elf.extend_from_slice(&56u16.to_le_bytes()); // program header size
+    elf.extend_from_slice(&2u16.to_le_bytes()); // program headers count
+    elf.extend_from_slice(&64u16.to_le_bytes()); // section header size
+    elf.extend_from_slice(&0u16.to_le_bytes()); // section headers count
+    elf.extend_from_slice(&0u16.to_le_bytes()); // section names index
+
+    let mut segment = |flags: u32, offset: u64, address: u64, file_len: u64, memory_len: u64| {
+        elf.extend_from_slice(&1u32.to_le_bytes()); // loadable
+        elf.extend_from_slice(&flags.to_le_bytes());
+        elf.extend_from_slice(&offset.to_le_bytes());
+        elf.extend_from_slice(&address.to_le_bytes()); // virtual address
+        elf.extend_from_slice(&address.to_le_bytes()); // physical address
+        elf.extend_from_slice(&file_len.to_le_bytes());
+        elf.extend_from_slice(&memory_len.to_le_bytes());
+        elf.extend_from_slice(&ALIGN.to_le_bytes());
+    };
+    // the headers, data and code, readable and executable
+    segment(0b101, 0, BASE, text_len, text_len);
+    // the tape, readable and writable, starting with the tape of the prefix
+    let tape_offset = text_len.next_multiple_of(ALIGN);
+    let prefix_len = prefix.tape.len() as u64;
+    segment(0b110, tape_offset, TAPE, prefix_len, tape_len);
+
+    elf.extend_from_slice(&data);
+    elf.extend_from_slice(&code);
+    elf.resize(tape_offset as usize, 0);
+    elf.extend_from_slice(&prefix.tape);
+    Ok(elf)
+}
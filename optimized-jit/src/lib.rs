@@ -0,0 +1,241 @@
+//! A brainfuck JIT compiler, that translates the instructions optimized by
+//! `bf_core::optimize` to x86-64 or AArch64 machine code, depending on the
+//! host, using `dynasmrt`.
+
+use std::io::{IsTerminal, Read, Write};
+use std::time::Duration;
+
+use bf_core::{
+    jit_fn, Boundary, Budget, CancelHandle, Cell, Config, Eof, GuardedTape, Output, Prefix,
+    RuntimeError, Stats, UnbalancedBrackets,
+};
+use dynasmrt::mmap::MutableBuffer;
+
+mod aarch64;
+mod x64;
+
+pub use aarch64::executable as aarch64_executable;
+
+pub struct Program {
+    code: Vec<u8>,
+    memory: Vec<u8>,
+    tape_size: usize,
+    eof: Eof,
+    unbuffered: bool,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    cancel: CancelHandle,
+    stats: Stats,
+    prefix: Prefix,
+    guard: bool,
+    /// The tape of the last run with `Boundary::Guard`.
+    guarded: Option<GuardedTape>,
+}
+impl Program {
+    pub fn new(source: &[u8], config: &Config) -> Result<Program, UnbalancedBrackets> {
+        let config = &bf_core::round_to_pages(config);
+        let ir = bf_core::parse(source)?;
+        let (ir, stats) = bf_core::optimize(&ir, config);
+        let prefix = Prefix::evaluate(&ir, config);
+        let in_bounds = bf_core::in_bounds(&ir, config);
+        let code = if cfg!(target_arch = "aarch64") {
+            aarch64::compile_for_host(&ir, config, &prefix, &in_bounds)
+        } else {
+            x64::compile(ir, config, &prefix, &in_bounds)
+        };
+
+        Ok(Program {
+            code,
+            memory: vec![0; config.tape_size * config.cell_width.bytes()],
+            tape_size: config.tape_size * config.cell_width.bytes(),
+            eof: config.eof,
+            unbuffered: config.unbuffered,
+            fuel: config.fuel,
+            timeout: config.timeout,
+            cancel: CancelHandle::default(),
+            stats,
+            prefix,
+            guard: config.boundary == Boundary::Guard,
+            guarded: None,
+        })
+    }
+
+    /// The statistics of the optimization of the program.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// A handle to stop the running program from another thread, with a
+    /// `RuntimeError::Cancelled`. It only works if the program was created
+    /// with `Config::cancellable`, `fuel` or `timeout`.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// The tape of the last run, in bytes. Cells larger than a byte are
+    /// stored in little-endian.
+    pub fn tape(&self) -> &[u8] {
+        match &self.guarded {
+            Some(tape) => tape.as_slice(),
+            None => &self.memory,
+        }
+    }
+
+    /// Run the program, reading from stdin and writing to stdout.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        // flush each line when the user may be reading it
+        let flush_on_newline = std::io::stdout().is_terminal();
+        self.run_inner(
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+            flush_on_newline,
+        )
+    }
+
+    /// Run the program, reading from `input` and writing to `output`.
+    pub fn run_with_io(
+        &mut self,
+        mut input: impl Read,
+        mut output: impl Write,
+    ) -> Result<(), RuntimeError> {
+        self.run_inner(&mut input, &mut output, false)
+    }
+
+    fn run_inner(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        flush_on_newline: bool,
+    ) -> Result<(), RuntimeError> {
+        // start each run from the tape left by the prefix
+        if self.guard {
+            let tape = self.guarded.insert(GuardedTape::new(self.tape_size)?);
+            tape.as_mut_slice()[..self.prefix.tape.len()].copy_from_slice(&self.prefix.tape);
+        } else {
+            self.memory.clear();
+            self.memory.extend_from_slice(&self.prefix.tape);
+            self.memory.resize(self.tape_size, 0);
+        }
+
+        let mut buffer = MutableBuffer::new(self.code.len()).unwrap();
+        buffer.set_len(self.code.len());
+
+        buffer.copy_from_slice(&self.code);
+
+        let buffer = buffer.make_exec().unwrap();
+
+        unsafe {
+            #[cfg(target_arch = "aarch64")]
+            aarch64::flush_instruction_cache(buffer.as_ptr(), buffer.len());
+
+            let code_fn: CodeFn = std::mem::transmute(buffer.as_ptr());
+
+            let memory = self.memory.as_mut_ptr();
+            let memory_len = self.memory.len();
+            let mut context = Context {
+                memory: &mut self.memory,
+                budget: Budget::new(self.fuel, self.timeout, Some(self.cancel.clone())),
+                input,
+                output: Output::new(output, self.unbuffered, flush_on_newline),
+                eof: self.eof,
+            };
+
+            let mut error = std::ptr::null_mut();
+            for &value in &self.prefix.output {
+                error = write(&mut context, value);
+                if !error.is_null() {
+                    break;
+                }
+            }
+            if error.is_null() {
+                error = match &mut self.guarded {
+                    Some(tape) => tape.call(buffer.as_ptr(), &mut context),
+                    None => code_fn(memory, memory_len, &mut context),
+                };
+            }
+
+            // flush the output even if the program failed
+            let flushed = context.output.flush();
+
+            if !error.is_null() {
+                return Err(*Box::from_raw(error));
+            }
+            flushed?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The signature of the generated code: it runs the program on the tape at
+/// the given address and length, and returns its error, if any.
+#[cfg(target_arch = "x86_64")]
+type CodeFn = unsafe extern "sysv64" fn(*mut u8, usize, *mut Context) -> *mut RuntimeError;
+#[cfg(not(target_arch = "x86_64"))]
+type CodeFn = unsafe extern "C" fn(*mut u8, usize, *mut Context) -> *mut RuntimeError;
+
+/// The offset of `Context::budget`, and of the steps left at its start.
+const BUDGET_OFFSET: i32 = std::mem::offset_of!(Context<'static>, budget) as i32;
+
+/// The state of a running program, that the generated code passes to the
+/// callbacks.
+#[repr(C)]
+struct Context<'a> {
+    /// Read by the generated code to grow the tape, so it must be the first
+    /// field.
+    memory: *mut Vec<u8>,
+    /// Its steps are decremented by the generated code on each back-edge.
+    budget: Budget,
+    input: &'a mut dyn Read,
+    output: Output<&'a mut dyn Write>,
+    eof: Eof,
+}
+
+jit_fn! {
+    unsafe fn write(context: *mut Context, value: u8) -> *mut RuntimeError {
+        let context = &mut *context;
+
+        // Writing a non-UTF-8 byte sequence on Windows error out.
+        if cfg!(target_os = "windows") && value >= 128 {
+            return std::ptr::null_mut();
+        }
+
+        match context.output.write(value) {
+            Err(err) => Box::into_raw(Box::new(err.into())),
+            _ => std::ptr::null_mut(),
+        }
+    }
+
+    /// Read a byte into `cell`, that may be unaligned. On the end of the input,
+    /// `context.eof` is applied to the cell instead.
+    unsafe fn read<T: Cell>(context: *mut Context, cell: *mut T) -> *mut RuntimeError {
+        let context = &mut *context;
+        if let Err(err) = context.output.flush() {
+            return Box::into_raw(Box::new(err.into()));
+        }
+
+        loop {
+            let mut value = 0;
+            let err = context.input.read_exact(std::slice::from_mut(&mut value));
+
+            if let Err(err) = err {
+                if err.kind() != std::io::ErrorKind::UnexpectedEof {
+                    return Box::into_raw(Box::new(err.into()));
+                }
+                let mut cell_value = cell.read_unaligned();
+                context.eof.apply(&mut cell_value);
+                cell.write_unaligned(cell_value);
+                return std::ptr::null_mut();
+            }
+
+            // ignore CR from Window's CRLF
+            if cfg!(target_os = "windows") && value == b'\r' {
+                continue;
+            }
+
+            cell.write_unaligned(T::from_byte(value));
+
+            return std::ptr::null_mut();
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! The x86-64 code generator.
+
+use bf_core::{Boundary, CellWidth, Config, Instruction, Ir, Prefix, Trap};
+use dynasmrt::x64::{Rq, X64Relocation};
+use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi, VecAssembler};
+
+use crate::{read, write, BUDGET_OFFSET};
+
+/// Compile `ir` to a function with the signature of `CodeFn`, that runs it
+/// from the state left by `prefix`.
+pub fn compile(ir: Ir, config: &Config, prefix: &Prefix, in_bounds: &[bool]) -> Vec<u8> {
+    let mut code: VecAssembler<X64Relocation> = VecAssembler::new(0);
+    let cell_width = config.cell_width;
+
+    // r12 will be the adress of `memory`
+    // r13 will be the value of `pointer`, times the cell width
+    // r14 will be the length of `memory`
+    // r15 will be the address of the `Context`
+    // r12, r14 and r15 are got from arguments 1, 2 and 3
+    // r13 is set to the pointer left by the prefix
+    dynasm! { code
+        ; .arch x64
+        ; push rbp
+        ; mov rbp, rsp
+        ; push r12
+        ; push r13
+        ; push r14
+        ; push r15
+        ; mov r12, rdi
+        ; mov r13, (prefix.pointer * cell_width.bytes()) as i32
+        ; mov r14, rsi
+        ; mov r15, rdx
+    };
+
+    // skip the instructions run by the prefix
+    let resume_label = code.new_dynamic_label();
+    dynasm! { code
+        ; .arch x64
+        ; jmp =>resume_label
+    };
+
+    let read_address = match cell_width {
+        CellWidth::U8 => read::<u8> as *const (),
+        CellWidth::U16 => read::<u16> as *const (),
+        CellWidth::U32 => read::<u32> as *const (),
+    };
+
+    // only count the steps when they are limited
+    let limited = config.fuel.is_some() || config.timeout.is_some() || config.cancellable;
+
+    let mut bracket_stack = Vec::new();
+
+    let len = ir.instructions.len();
+    for (index, (instr, position)) in ir.instructions.into_iter().zip(ir.positions).enumerate() {
+        if index == prefix.resume {
+            dynasm! { code
+                ; .arch x64
+                ; =>resume_label
+            };
+        }
+        match instr {
+            Instruction::Add(offset, n) => {
+                let index = emit_index(&mut code, config, offset, position, in_bounds[index]);
+                emit_add(&mut code, cell_width, index, n);
+            }
+            Instruction::Move(n) => emit_move(&mut code, config, n, position, in_bounds[index]),
+            Instruction::Input(offset) => {
+                let index = emit_index(&mut code, config, offset, position, in_bounds[index]);
+                dynasm! { code
+                    ; .arch x64
+                    // fault here, and not in `read`, out of a guarded tape
+                    ; cmp BYTE [r12 + Rq(index as u8)], 0
+                    ; lea rsi, [r12 + Rq(index as u8)] // cell address
+                    ; mov rax, QWORD read_address as i64
+                    ; mov rdi, r15
+                    ; call rax
+                    ; cmp rax, 0
+                    ; jne ->exit
+                }
+            }
+            Instruction::Output(offset) => {
+                let index = emit_index(&mut code, config, offset, position, in_bounds[index]);
+                dynasm! { code
+                    ; .arch x64
+                    ; movzx esi, BYTE [r12 + Rq(index as u8)] // cell value
+                    ; mov rax, QWORD write as *const () as i64
+                    ; mov rdi, r15
+                    ; call rax
+                    ; cmp rax, 0
+                    ; jne ->exit
+                }
+            }
+            Instruction::JumpRight(_) => {
+                let start_label = code.new_dynamic_label();
+                let end_label = code.new_dynamic_label();
+                emit_cmp_zero(&mut code, cell_width);
+                dynasm! { code
+                    ; .arch x64
+                    ; je =>end_label
+                    ; =>start_label
+                };
+
+                bracket_stack.push((start_label, end_label));
+            }
+            Instruction::JumpLeft(_) => {
+                let (start_label, end_label) = bracket_stack.pop().unwrap();
+
+                if limited {
+                    emit_step(&mut code);
+                }
+                emit_cmp_zero(&mut code, cell_width);
+                dynasm! { code
+                    ; .arch x64
+                    ; jne =>start_label
+                    ; => end_label
+                };
+            }
+            Instruction::Set(offset, n) => {
+                let index = emit_index(&mut code, config, offset, position, in_bounds[index]);
+                emit_set(&mut code, cell_width, index, n);
+            }
+            Instruction::Clear(offset) => {
+                let index = emit_index(&mut code, config, offset, position, in_bounds[index]);
+                emit_set(&mut code, cell_width, index, 0);
+            }
+            Instruction::MulAdd { offset, factor } => {
+                // the loop replaced by the instruction doesn't access the
+                // cell at `offset` if the current cell is zero, and it may
+                // be out of the tape, or of a guarded tape.
+                if config.boundary == Boundary::Guard || !in_bounds[index] {
+                    emit_cmp_zero(&mut code, cell_width);
+                    dynasm! { code
+                        ; .arch x64
+                        ; je >skip
+                    }
+                }
+                emit_offset(&mut code, config, offset, position, in_bounds[index]);
+                match cell_width {
+                    CellWidth::U8 => dynasm! { code
+                        ; .arch x64
+                        ; movzx ecx, BYTE [r12 + r13]
+                    },
+                    CellWidth::U16 => dynasm! { code
+                        ; .arch x64
+                        ; movzx ecx, WORD [r12 + r13]
+                    },
+                    CellWidth::U32 => dynasm! { code
+                        ; .arch x64
+                        ; mov ecx, [r12 + r13]
+                    },
+                }
+                if factor != 1 {
+                    dynasm! { code
+                        ; .arch x64
+                        ; imul ecx, ecx, factor as i32
+                    }
+                }
+                match cell_width {
+                    CellWidth::U8 => dynasm! { code
+                        ; .arch x64
+                        ; add BYTE [r12 + rax], cl
+                    },
+                    CellWidth::U16 => dynasm! { code
+                        ; .arch x64
+                        ; add WORD [r12 + rax], cx
+                    },
+                    CellWidth::U32 => dynasm! { code
+                        ; .arch x64
+                        ; add DWORD [r12 + rax], ecx
+                    },
+                }
+                dynasm! { code
+                    ; .arch x64
+                    ; skip:
+                }
+            }
+            Instruction::MoveUntil(n) => dynasm! { code
+                ; .arch x64
+
+                ;; if cell_width == CellWidth::U8 && (n == 1 || n == -1) {
+                    emit_scan(&mut code, config, n)
+                }
+
+                ; repeat:
+
+                // check if 0
+                ;; emit_cmp_zero(&mut code, cell_width)
+                ; je >exit
+
+                ;; emit_move(&mut code, config, n, position, false)
+                ;; if limited { emit_step(&mut code) }
+
+                ; jmp <repeat
+
+                ; exit:
+            },
+            Instruction::Check(offset) => {
+                let in_bounds = in_bounds[index];
+                if config.boundary == Boundary::Guard && in_bounds {
+                    // fault here if the cell is out of the guarded tape
+                    let index = emit_index(&mut code, config, offset, position, in_bounds);
+                    dynasm! { code
+                        ; .arch x64
+                        ; cmp BYTE [r12 + Rq(index as u8)], 0
+                    }
+                } else if !in_bounds {
+                    emit_offset(&mut code, config, offset, position, in_bounds);
+                }
+            }
+        }
+    }
+
+    if prefix.resume == len {
+        dynasm! { code
+            ; .arch x64
+            ; =>resume_label
+        };
+    }
+
+    // when we push to the stack, we need to remeber
+    // to pop them in the opossite order.
+    dynasm! { code
+        ; .arch x64
+        ; xor rax, rax
+        ; ->exit:
+        ; pop r15
+        ; pop r14
+        ; pop r13
+        ; pop r12
+        ; pop rbp
+        ; ret
+    }
+
+    code.finalize().unwrap()
+}
+
+/// Emit code that moves the pointer in `r13` by `n` cells. The move is not
+/// checked if it is known to stay `in_bounds`.
+fn emit_move(
+    code: &mut VecAssembler<X64Relocation>,
+    config: &Config,
+    n: isize,
+    position: usize,
+    in_bounds: bool,
+) {
+    // the pointer and the tape size are in bytes, not in cells.
+    let bytes = config.cell_width.bytes() as isize;
+    let tape_size = (config.tape_size as isize * bytes) as i32;
+    if in_bounds {
+        dynasm! { code
+            ; .arch x64
+            ; add r13, (n * bytes) as i32
+        };
+        return;
+    }
+    match config.boundary {
+        Boundary::Wrap => {
+            let n = (n % config.tape_size as isize * bytes) as i32;
+            if n > 0 {
+                dynasm! { code
+                    ; .arch x64
+                    ; lea eax, [r13 + n]
+                    ; add r13, -(tape_size - n)
+                    ; cmp eax, tape_size
+                    ; cmovb r13d, eax
+                }
+            } else {
+                dynasm! { code
+                    ; .arch x64
+                    ; lea eax, [r13 + n]
+                    ; add r13d, tape_size + n
+                    ; test eax, eax
+                    ; cmovns r13d, eax
+                }
+            }
+        }
+        Boundary::Error | Boundary::Grow | Boundary::Guard if n < 0 => dynasm! { code
+            ; .arch x64
+            ; add r13, (n * bytes) as i32
+            ; jns >ok
+            ;; emit_trap(code, bf_core::pointer_underflow, position)
+            ; ok:
+        },
+        Boundary::Error | Boundary::Guard => dynasm! { code
+            ; .arch x64
+            ; add r13, (n * bytes) as i32
+            ; cmp r13, tape_size
+            ; jb >ok
+            ;; emit_trap(code, bf_core::pointer_overflow, position)
+            ; ok:
+        },
+        Boundary::Grow => dynasm! { code
+            ; .arch x64
+            ; add r13, (n * bytes) as i32
+            ; cmp r13, r14
+            ; jb >ok
+            ; lea rsi, [r13 + bytes as i32 - 1]
+            ;; emit_grow(code)
+            ; ok:
+        },
+    }
+}
+
+/// Emit code that computes the byte index of the cell at `n` cells from the
+/// pointer, in `rax`. The index is not checked if it is known to be
+/// `in_bounds`.
+fn emit_offset(
+    code: &mut VecAssembler<X64Relocation>,
+    config: &Config,
+    n: isize,
+    position: usize,
+    in_bounds: bool,
+) {
+    // the pointer and the tape size are in bytes, not in cells.
+    let bytes = config.cell_width.bytes() as isize;
+    let tape_size = (config.tape_size as isize * bytes) as i32;
+    if in_bounds {
+        dynasm! { code
+            ; .arch x64
+            ; lea rax, [r13 + (n * bytes) as i32]
+        };
+        return;
+    }
+    match config.boundary {
+        Boundary::Wrap => {
+            let n = (n % config.tape_size as isize * bytes) as i32;
+            if n > 0 {
+                dynasm! { code
+                    ; .arch x64
+                    ; lea ecx, [r13 + n]
+                    ; lea eax, [r13 + n - tape_size]
+                    ; cmp ecx, tape_size
+                    ; cmovb eax, ecx
+                }
+            } else {
+                dynasm! { code
+                    ; .arch x64
+                    ; lea ecx, [r13 + n]
+                    ; lea eax, [r13 + tape_size + n]
+                    ; test ecx, ecx
+                    ; cmovns eax, ecx
+                }
+            }
+        }
+        Boundary::Error | Boundary::Grow | Boundary::Guard if n < 0 => dynasm! { code
+            ; .arch x64
+            ; lea rax, [r13 + (n * bytes) as i32]
+            ; test rax, rax
+            ; jns >ok
+            ;; emit_trap(code, bf_core::pointer_underflow, position)
+            ; ok:
+        },
+        Boundary::Error | Boundary::Guard => dynasm! { code
+            ; .arch x64
+            ; lea rax, [r13 + (n * bytes) as i32]
+            ; cmp rax, tape_size
+            ; jb >ok
+            ;; emit_trap(code, bf_core::pointer_overflow, position)
+            ; ok:
+        },
+        Boundary::Grow => dynasm! { code
+            ; .arch x64
+            ; lea rax, [r13 + (n * bytes) as i32]
+            ; cmp rax, r14
+            ; jb >ok
+            ; lea rsi, [rax + bytes as i32 - 1]
+            ;; emit_grow(code)
+            ; lea rax, [r13 + (n * bytes) as i32]
+            ; ok:
+        },
+    }
+}
+
+/// Emit code that computes the byte index of the cell at `n` cells from the
+/// pointer, and return the register that contains it: `r13` itself when `n`
+/// is 0, or `rax`.
+fn emit_index(
+    code: &mut VecAssembler<X64Relocation>,
+    config: &Config,
+    n: isize,
+    position: usize,
+    in_bounds: bool,
+) -> Rq {
+    if n == 0 {
+        return Rq::R13;
+    }
+    emit_offset(code, config, n, position, in_bounds);
+    Rq::RAX
+}
+
+/// Emit code that moves the pointer in `r13` to the first zero byte in
+/// `direction`, 1 or -1, using `bf_core::find_zero_right` or
+/// `find_zero_left`. If there is none, the pointer is left on the last byte
+/// before leaving the tape, or unchanged when wrapping around, for the
+/// following `MoveUntil` loop to handle.
+fn emit_scan(code: &mut VecAssembler<X64Relocation>, config: &Config, direction: isize) {
+    let find = if direction > 0 {
+        bf_core::find_zero_right
+    } else {
+        bf_core::find_zero_left
+    };
+    dynasm! { code
+        ; .arch x64
+        ; cmp BYTE [r12 + r13], 0
+        ; je >done
+        ; mov rdi, r12
+        ; mov rsi, r14
+        ; mov rdx, r13
+        ; mov rax, QWORD find as *const () as i64
+        ; call rax
+        ; cmp rax, r14
+        ; jb >found
+    }
+    match config.boundary {
+        // search the other side of the tape
+        Boundary::Wrap if direction > 0 => dynasm! { code
+            ; .arch x64
+            ; mov rdi, r12
+            ; mov rsi, r14
+            ; xor edx, edx
+            ; mov rax, QWORD find as *const () as i64
+            ; call rax
+            ; cmp rax, r14
+            ; jb >found
+            ; jmp >done
+        },
+        Boundary::Wrap => dynasm! { code
+            ; .arch x64
+            ; mov rdi, r12
+            ; mov rsi, r14
+            ; lea rdx, [r14 - 1]
+            ; mov rax, QWORD find as *const () as i64
+            ; call rax
+            ; cmp rax, r14
+            ; jb >found
+            ; jmp >done
+        },
+        _ if direction > 0 => dynasm! { code
+            ; .arch x64
+            ; lea rax, [r14 - 1]
+        },
+        _ => dynasm! { code
+            ; .arch x64
+            ; xor eax, eax
+        },
+    }
+    dynasm! { code
+        ; .arch x64
+        ; found:
+        ; mov r13, rax
+        ; done:
+    }
+}
+
+/// Emit code that adds `n` to the cell at the byte index in `index`.
+fn emit_add(code: &mut VecAssembler<X64Relocation>, cell_width: CellWidth, index: Rq, n: u32) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch x64
+            ; add BYTE [r12 + Rq(index as u8)], n as i8
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch x64
+            ; add WORD [r12 + Rq(index as u8)], n as i16
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch x64
+            ; add DWORD [r12 + Rq(index as u8)], n as i32
+        },
+    }
+}
+
+/// Emit code that compares the current cell with zero.
+fn emit_cmp_zero(code: &mut VecAssembler<X64Relocation>, cell_width: CellWidth) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch x64
+            ; cmp BYTE [r12 + r13], 0
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch x64
+            ; cmp WORD [r12 + r13], 0
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch x64
+            ; cmp DWORD [r12 + r13], 0
+        },
+    }
+}
+
+/// Emit code that sets the cell at the byte index in `index` to `n`.
+fn emit_set(code: &mut VecAssembler<X64Relocation>, cell_width: CellWidth, index: Rq, n: u32) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch x64
+            ; mov BYTE [r12 + Rq(index as u8)], n as i8
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch x64
+            ; mov WORD [r12 + Rq(index as u8)], n as i16
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch x64
+            ; mov DWORD [r12 + Rq(index as u8)], n as i32
+        },
+    }
+}
+
+/// Emit a call to `trap`, and return the error it creates.
+fn emit_trap(code: &mut VecAssembler<X64Relocation>, trap: Trap, position: usize) {
+    dynasm! { code
+        ; .arch x64
+        ; mov rdi, QWORD position as i64
+        ; mov rax, QWORD trap as *const () as i64
+        ; call rax
+        ; jmp ->exit
+    }
+}
+
+/// Emit code that consumes a step of the `Budget` of the program, and exits
+/// with its error when there is none left.
+fn emit_step(code: &mut VecAssembler<X64Relocation>) {
+    dynasm! { code
+        ; .arch x64
+        ; sub QWORD [r15 + BUDGET_OFFSET], 1
+        ; jnc >ok
+        ; lea rdi, [r15 + BUDGET_OFFSET]
+        ; mov rax, QWORD bf_core::refill_budget as *const () as i64
+        ; call rax
+        ; cmp rax, 0
+        ; jne ->exit
+        ; ok:
+    }
+}
+
+/// Emit a call to `bf_core::grow_tape`, to make the byte at index `rsi`
+/// valid.
+fn emit_grow(code: &mut VecAssembler<X64Relocation>) {
+    dynasm! { code
+        ; .arch x64
+        ; mov rdi, [r15] // context.memory
+        ; mov rax, QWORD bf_core::grow_tape as *const () as i64
+        ; call rax
+        ; mov r12, rax
+        ; mov r14, rdx
+    }
+}
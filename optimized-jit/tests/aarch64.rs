@@ -0,0 +1,103 @@
+//! Compile programs to static AArch64 executables with `--emit-aarch64`, run
+//! them on AArch64 Linux hosts, or with `qemu-aarch64` on the other ones, and
+//! compare their output and errors with the ones of the simple `interpreter`.
+
+use std::process::Command;
+
+use bf_core::{Boundary, CellWidth, Config, Eof, RuntimeError};
+use bf_testing::{Backend, Outcome};
+
+/// The executables, that report their errors without a location.
+struct Executable {
+    /// If the executables are run with `qemu-aarch64`.
+    qemu: bool,
+}
+impl Executable {
+    /// Return how to run the executables on this host, or `None` if they can't
+    /// be, and the tests are skipped.
+    fn new() -> Option<Executable> {
+        if cfg!(all(target_os = "linux", target_arch = "aarch64")) {
+            return Some(Executable { qemu: false });
+        }
+        if Command::new("qemu-aarch64")
+            .arg("--version")
+            .output()
+            .is_ok()
+        {
+            return Some(Executable { qemu: true });
+        }
+        eprintln!("skipped, not an AArch64 Linux host, and `qemu-aarch64` was not found");
+        None
+    }
+}
+impl Backend for Executable {
+    fn run(&self, source: &[u8], config: &Config, input: &[u8]) -> Outcome {
+        let source_path = bf_testing::temp_path("program.bf");
+        let exe_path = bf_testing::temp_path("program");
+        std::fs::write(&source_path, source).unwrap();
+
+        let status = Command::new(env!("CARGO_BIN_EXE_bf-optimized-jit"))
+            .args(bf_testing::args(config))
+            .arg(&source_path)
+            .arg("--emit-aarch64")
+            .arg(&exe_path)
+            .status()
+            .unwrap();
+        assert!(
+            status.success(),
+            "compiling {}",
+            String::from_utf8_lossy(source)
+        );
+        std::fs::remove_file(&source_path).unwrap();
+
+        let mut command = match self.qemu {
+            true => Command::new("qemu-aarch64"),
+            false => Command::new(&exe_path),
+        };
+        if self.qemu {
+            command.arg(&exe_path);
+        }
+        let outcome = bf_testing::run_command(&mut command, input);
+        std::fs::remove_file(&exe_path).unwrap();
+        outcome
+    }
+
+    fn message(&self, source: &[u8], err: RuntimeError) -> String {
+        bf_testing::message(source, err, false)
+    }
+}
+
+#[test]
+fn missing_path() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bf-optimized-jit"))
+        .arg("program.bf")
+        .arg("--emit-aarch64")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stderr, b"expected a path after --emit-aarch64\n");
+}
+
+#[test]
+fn programs() {
+    if let Some(executable) = Executable::new() {
+        bf_testing::programs(&executable);
+    }
+}
+
+/// Random programs, that end or fail in a limited number of steps, on tapes
+/// small enough for them to leave often.
+#[test]
+fn random() {
+    let Some(executable) = Executable::new() else {
+        return;
+    };
+    bf_testing::random(&executable, 200, |random| Config {
+        tape_size: 3 + random.below(5) as usize,
+        cell_width: *random.choose(&[CellWidth::U8, CellWidth::U16, CellWidth::U32]),
+        boundary: *random.choose(&[Boundary::Wrap, Boundary::Error]),
+        eof: *random.choose(&[Eof::Zero, Eof::MinusOne, Eof::Unchanged]),
+        prefix_steps: *random.choose(&[0, 7, 1 << 20]),
+        ..Config::default()
+    });
+}
@@ -0,0 +1,76 @@
+//! Compare the output and the errors of the JIT with the ones of the simple
+//! `interpreter`, that runs the unoptimized instructions.
+
+use bf_core::{Boundary, Config};
+use bf_optimized_jit::Program;
+use bf_testing::{Backend, Outcome};
+
+/// The JIT, for the architecture of the host.
+struct Jit;
+impl Backend for Jit {
+    const GUARDED: bool = true;
+
+    fn run(&self, source: &[u8], config: &Config, input: &[u8]) -> Outcome {
+        let mut output = Vec::new();
+        let result = Program::new(source, config)
+            .unwrap()
+            .run_with_io(input, &mut output);
+        (output, result.err().map(|err| format!("{:?}", err)))
+    }
+}
+
+/// The accesses out of a guarded tape fault, and stop the program with a
+/// `TapeOutOfBounds` after the output written before them, on each run.
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn guard_fault() {
+    use bf_core::CellWidth;
+
+    let programs: [&[u8]; 6] = [
+        b"+.<+",
+        // the adds cancel out, but the cell is still accessed
+        b"+.<-+",
+        b"+[>+]",
+        b"+.[<<<<<<<<+]",
+        b"+>+>+.[<]",
+        b",[-<+>]",
+    ];
+    for source in programs {
+        for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+            let config = Config {
+                tape_size: 16,
+                cell_width,
+                boundary: Boundary::Guard,
+                ..Config::default()
+            };
+            let (expected, result) =
+                bf_testing::run_interpreter(source, &bf_core::round_to_pages(&config), b"\x05");
+            assert!(result.is_err());
+
+            let mut program = Program::new(source, &config).unwrap();
+            for _ in 0..2 {
+                let mut output = Vec::new();
+                let result = program.run_with_io(&b"\x05"[..], &mut output);
+                assert!(
+                    matches!(result, Err(bf_core::RuntimeError::TapeOutOfBounds)),
+                    "{} with {:?}: {:?}",
+                    String::from_utf8_lossy(source),
+                    config,
+                    result
+                );
+                assert_eq!(output, expected);
+            }
+        }
+    }
+}
+
+/// Random programs, that end or fail in a limited number of steps.
+#[test]
+fn random() {
+    let mut boundaries = vec![Boundary::Wrap, Boundary::Error, Boundary::Grow];
+    if cfg!(all(target_os = "linux", target_arch = "x86_64")) {
+        boundaries.push(Boundary::Guard);
+    }
+    let configs = bf_testing::configs(&boundaries);
+    bf_testing::random(&Jit, 1000, |random| random.choose(&configs).clone());
+}
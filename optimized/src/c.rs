@@ -0,0 +1,273 @@
+//! Transpile the optimized instructions to a C program, to run brainfuck on
+//! the platforms that none of the JITs target, and to have a reference to
+//! compare them with.
+
+use std::fmt::Write;
+
+use bf_core::{Boundary, CellWidth, Config, Eof, Instruction, SourceLocation, UnbalancedBrackets};
+
+/// Transpile the program to the source of a C program, with a single `main`
+/// function, that stops with exit code 5 if the pointer leaves the tape.
+///
+/// Only `Boundary::Wrap` and `Boundary::Error` are supported, since the tape
+/// is a static array, and the program can't have a `Budget`.
+pub fn to_c(source: &[u8], config: &Config) -> Result<String, UnbalancedBrackets> {
+    assert!(matches!(config.boundary, Boundary::Wrap | Boundary::Error));
+    assert!(config.fuel.is_none() && config.timeout.is_none() && !config.cancellable);
+
+    let ir = bf_core::parse(source)?;
+    let (ir, _) = bf_core::optimize(&ir, config);
+    let in_bounds = bf_core::in_bounds(&ir, config);
+
+    let cell_type = match config.cell_width {
+        CellWidth::U8 => "unsigned char",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    };
+
+    let mut c = C {
+        code: String::new(),
+        depth: 1,
+        source,
+        boundary: config.boundary,
+        tape_size: config.tape_size,
+        mask: match config.cell_width {
+            CellWidth::U8 => 0xff,
+            CellWidth::U16 => 0xffff,
+            CellWidth::U32 => 0xffff_ffff,
+        },
+    };
+
+    let scan = config.cell_width == CellWidth::U8
+        && ir
+            .instructions
+            .iter()
+            .any(|&instr| matches!(instr, Instruction::MoveUntil(1)));
+
+    c.code.push_str(concat!(
+        "/* Generated by `bf-optimized --emit=c`. */\n",
+        "#include <stdint.h>\n",
+        "#include <stdio.h>\n",
+        "#include <string.h>\n",
+        "\n",
+        "/* Stop the program with an error. */\n",
+        "#define FAIL(message) do { fputs(message \"\\n\", stderr); return 5; } while (0)\n",
+        "\n",
+    ));
+    writeln!(c.code, "#define TAPE_SIZE {}", config.tape_size).unwrap();
+    writeln!(c.code, "\nstatic {} tape[TAPE_SIZE];\n", cell_type).unwrap();
+    c.code.push_str("int main(void) {\n");
+    c.line("size_t p = 0;");
+    if ir
+        .instructions
+        .iter()
+        .any(|&instr| matches!(instr, Instruction::Input(_)))
+    {
+        c.line("int c;");
+    }
+    if scan {
+        c.line("unsigned char *zero;");
+    }
+    if config.unbuffered {
+        c.line("setvbuf(stdout, NULL, _IONBF, 0);");
+    }
+    c.line("");
+
+    for ((&instr, &position), &in_bounds) in
+        ir.instructions.iter().zip(&ir.positions).zip(&in_bounds)
+    {
+        match instr {
+            Instruction::Add(offset, n) => {
+                let cell = c.cell(offset, position, in_bounds);
+                let (op, n) = c.signed(n);
+                c.line(&format!("{} {}= {};", cell, op, n));
+            }
+            Instruction::Move(n) => c.move_pointer(n, position, in_bounds),
+            Instruction::Output(offset) => {
+                let cell = c.cell(offset, position, in_bounds);
+                match config.cell_width {
+                    CellWidth::U8 => c.line(&format!("putchar({});", cell)),
+                    _ => c.line(&format!("putchar((unsigned char){});", cell)),
+                }
+            }
+            Instruction::Input(offset) => {
+                let cell = c.cell(offset, position, in_bounds);
+                // let the user see any prompt before waiting for the input
+                c.line("fflush(stdout);");
+                c.line("c = getchar();");
+                match config.eof {
+                    Eof::Zero => c.line(&format!("{} = c == EOF ? 0 : c;", cell)),
+                    Eof::MinusOne => c.line(&format!("{} = c == EOF ? -1 : c;", cell)),
+                    Eof::Unchanged => c.line(&format!("if (c != EOF) {} = c;", cell)),
+                }
+            }
+            Instruction::JumpRight(_) => {
+                c.line("while (tape[p]) {");
+                c.depth += 1;
+            }
+            Instruction::JumpLeft(_) => {
+                c.depth -= 1;
+                c.line("}");
+            }
+            Instruction::Set(offset, n) => {
+                let cell = c.cell(offset, position, in_bounds);
+                c.line(&format!("{} = {};", cell, n & c.mask));
+            }
+            Instruction::Clear(offset) => {
+                let cell = c.cell(offset, position, in_bounds);
+                c.line(&format!("{} = 0;", cell));
+            }
+            Instruction::MulAdd { offset, factor } => {
+                // the loop replaced by the instruction doesn't access the
+                // cell at `offset` if the current cell is zero, and it may
+                // be out of the tape.
+                if !in_bounds {
+                    c.line("if (tape[p]) {");
+                    c.depth += 1;
+                }
+                let cell = c.cell(offset, position, in_bounds);
+                // multiply as unsigned, that wraps around instead of overflowing
+                match c.signed(factor) {
+                    (op, 1) => c.line(&format!("{} {}= tape[p];", cell, op)),
+                    (op, factor) => c.line(&format!("{} {}= tape[p] * {}u;", cell, op, factor)),
+                }
+                if !in_bounds {
+                    c.depth -= 1;
+                    c.line("}");
+                }
+            }
+            Instruction::MoveUntil(n) => {
+                if scan && n == 1 {
+                    c.line("zero = memchr(&tape[p], 0, TAPE_SIZE - p);");
+                    match c.boundary {
+                        Boundary::Wrap => {
+                            c.line("if (!zero) zero = memchr(tape, 0, p);");
+                            c.line("if (zero) p = zero - tape;");
+                        }
+                        // the loop below fails moving right of the last cell
+                        _ => c.line("p = zero ? (size_t)(zero - tape) : TAPE_SIZE - 1;"),
+                    }
+                }
+                c.line("while (tape[p]) {");
+                c.depth += 1;
+                c.move_pointer(n, position, false);
+                c.depth -= 1;
+                c.line("}");
+            }
+            Instruction::Check(offset) => {
+                c.index(offset, position, in_bounds);
+            }
+        }
+    }
+
+    c.line("");
+    c.line("if (fflush(stdout) != 0 || ferror(stdout)) FAIL(\"IO error\");");
+    c.line("return 0;");
+    c.code.push_str("}\n");
+
+    Ok(c.code)
+}
+
+/// The C program being written.
+struct C<'a> {
+    code: String,
+    /// The indentation level of the next line.
+    depth: usize,
+    source: &'a [u8],
+    boundary: Boundary,
+    tape_size: usize,
+    /// The largest value of a cell.
+    mask: u32,
+}
+impl C<'_> {
+    fn line(&mut self, line: &str) {
+        if !line.is_empty() {
+            self.code.extend(std::iter::repeat_n("    ", self.depth));
+            self.code.push_str(line);
+        }
+        self.code.push('\n');
+    }
+
+    /// Return `n` as the operator and operand of an addition to a cell, so
+    /// that `255` on a byte is written as `- 1`.
+    fn signed(&self, n: u32) -> (char, u32) {
+        let n = n & self.mask;
+        if n > self.mask / 2 {
+            ('-', self.mask - n + 1)
+        } else {
+            ('+', n)
+        }
+    }
+
+    /// Return the statement that stops the program if `condition` holds, with
+    /// the location of the instruction at `position` in the message.
+    fn fail(&self, condition: &str, message: &str, position: usize) -> String {
+        let location = SourceLocation::new(self.source, position);
+        format!(
+            "if ({}) FAIL(\"error: {} at {}:{}\");",
+            condition, message, location.line, location.column
+        )
+    }
+
+    /// Check that the cell at `offset` from the pointer is in the tape, unless
+    /// it is known to be `in_bounds`, and return the expression of the cell.
+    fn cell(&mut self, offset: isize, position: usize, in_bounds: bool) -> String {
+        match self.index(offset, position, in_bounds) {
+            Some(index) => format!("tape[{}]", index),
+            None => "tape[p]".to_string(),
+        }
+    }
+
+    /// Move the pointer by `n` cells.
+    fn move_pointer(&mut self, n: isize, position: usize, in_bounds: bool) {
+        let Some(index) = self.index(n, position, in_bounds) else {
+            return;
+        };
+        let line = match self.boundary == Boundary::Wrap && !in_bounds {
+            true => format!("p = {};", index),
+            false if n < 0 => format!("p -= {};", n.unsigned_abs()),
+            false => format!("p += {};", n),
+        };
+        self.line(&line);
+    }
+
+    /// Return the expression of the index of the cell at `offset` from the
+    /// pointer, or `None` if it is the pointer itself, handling moves out of
+    /// the tape according to `self.boundary`.
+    fn index(&mut self, offset: isize, position: usize, in_bounds: bool) -> Option<String> {
+        if self.boundary == Boundary::Wrap && !in_bounds {
+            let offset = offset.rem_euclid(self.tape_size as isize) as usize;
+            return match offset {
+                0 => None,
+                // keep the moves to the left readable
+                _ if offset > self.tape_size / 2 => Some(format!(
+                    "(p + TAPE_SIZE - {}) % TAPE_SIZE",
+                    self.tape_size - offset
+                )),
+                _ => Some(format!("(p + {}) % TAPE_SIZE", offset)),
+            };
+        }
+
+        let n = offset.unsigned_abs();
+        if offset < 0 {
+            if !in_bounds {
+                let line = self.fail(
+                    &format!("p < {}", n),
+                    "pointer moved left of cell 0",
+                    position,
+                );
+                self.line(&line);
+            }
+            Some(format!("p - {}", n))
+        } else if offset > 0 {
+            if !in_bounds {
+                let condition = format!("p + {} >= TAPE_SIZE", n);
+                let line = self.fail(&condition, "pointer moved right of the last cell", position);
+                self.line(&line);
+            }
+            Some(format!("p + {}", n))
+        } else {
+            None
+        }
+    }
+}
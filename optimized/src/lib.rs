@@ -0,0 +1,612 @@
+//! A brainfuck interpreter that executes the instructions optimized by
+//! `bf_core::optimize`.
+
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use bf_core::{
+    Boundary, Budget, Cell, Config, Eof, Instruction, RuntimeError, Stats, UnbalancedBrackets,
+};
+
+mod c;
+pub use c::to_c;
+
+#[derive(Default, Debug)]
+#[cfg(feature = "profile")]
+struct Profile {
+    add: u64,
+    mov: u64,
+    jr: u64,
+    jl: u64,
+    inp: u64,
+    out: u64,
+    set: u64,
+    clear: u64,
+    muladd: u64,
+    movuntil: u64,
+    check: u64,
+    loops: std::collections::HashMap<std::ops::Range<usize>, usize>,
+    /// The number of dispatched `Op`s.
+    dispatches: u64,
+    /// How many times each pair of instructions was executed in sequence, to
+    /// choose the superinstructions of `fuse`.
+    pairs: std::collections::HashMap<(&'static str, &'static str), u64>,
+    last: Option<&'static str>,
+}
+
+/// How `Program` dispatches its instructions.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Dispatch {
+    /// A `match` on each instruction, in a loop.
+    Match,
+    /// A call to a closure for each instruction, built before running the
+    /// program, that is specialized for the instruction and its operands.
+    Threaded,
+}
+
+/// An instruction of the interpreter: an `Instruction` of the program, or a
+/// superinstruction that runs a common sequence of them with a single
+/// dispatch, see `fuse`.
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Add(isize, u32),
+    Move(isize),
+    Input(isize),
+    Output(isize),
+    JumpRight(usize),
+    JumpLeft(usize),
+    Set(isize, u32),
+    Clear(isize),
+    MulAdd {
+        offset: isize,
+        factor: u32,
+    },
+    MoveUntil(isize),
+    Check(isize),
+    /// `Add` then `Add`, like `+>+<` after sinking the moves.
+    AddAdd(isize, u32, isize, u32),
+    /// `Add` then `Move`.
+    AddMove(isize, u32, isize),
+    /// `MulAdd` then `Clear(0)`, like `[->+<]`.
+    MulAddClear {
+        offset: isize,
+        factor: u32,
+    },
+    /// `Move` then `JumpRight`.
+    MoveJumpRight(isize, usize),
+    /// `Move` then `JumpLeft`, that ends most loops.
+    MoveJumpLeft(isize, usize),
+}
+impl Op {
+    /// The number of instructions run by the op.
+    #[cfg(feature = "profile")]
+    fn len(self) -> usize {
+        match self {
+            Op::AddAdd(..)
+            | Op::AddMove(..)
+            | Op::MulAddClear { .. }
+            | Op::MoveJumpRight(..)
+            | Op::MoveJumpLeft(..) => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl From<Instruction> for Op {
+    fn from(instr: Instruction) -> Op {
+        match instr {
+            Instruction::Add(offset, n) => Op::Add(offset, n),
+            Instruction::Move(n) => Op::Move(n),
+            Instruction::Input(offset) => Op::Input(offset),
+            Instruction::Output(offset) => Op::Output(offset),
+            Instruction::JumpRight(pair) => Op::JumpRight(pair),
+            Instruction::JumpLeft(pair) => Op::JumpLeft(pair),
+            Instruction::Set(offset, n) => Op::Set(offset, n),
+            Instruction::Clear(offset) => Op::Clear(offset),
+            Instruction::MulAdd { offset, factor } => Op::MulAdd { offset, factor },
+            Instruction::MoveUntil(n) => Op::MoveUntil(n),
+            Instruction::Check(offset) => Op::Check(offset),
+        }
+    }
+}
+
+/// Return the op that runs the start of `instructions`, a superinstruction if
+/// it starts with one of the fused pairs.
+///
+/// The superinstructions replace the pairs of instructions executed the most
+/// by the bundled programs, as counted by the `profile` feature. Each index of
+/// the program has its own op, so a jump into the middle of a
+/// superinstruction runs the ops that start there.
+fn fuse(instructions: &[Instruction]) -> Op {
+    use Instruction::*;
+
+    match *instructions {
+        [MulAdd { offset, factor }, Clear(0), ..] => Op::MulAddClear { offset, factor },
+        [Move(n), JumpLeft(pair), ..] => Op::MoveJumpLeft(n, pair),
+        [Move(n), JumpRight(pair), ..] => Op::MoveJumpRight(n, pair),
+        [Add(a, x), Add(b, y), ..] => Op::AddAdd(a, x, b, y),
+        [Add(offset, n), Move(m), ..] => Op::AddMove(offset, n, m),
+        [instr, ..] => Op::from(instr),
+        [] => unreachable!("no instruction to run"),
+    }
+}
+
+/// The input, output and budget of a running program.
+struct Io<R, W> {
+    input: R,
+    output: W,
+    budget: Budget,
+}
+
+/// The code of an op, for `Dispatch::Threaded`.
+type Handler<T, R, W> = Box<dyn Fn(&mut Program<T>, &mut Io<R, W>) -> Result<(), RuntimeError>>;
+
+pub struct Program<T: Cell> {
+    program_counter: usize,
+    pointer: usize,
+    /// The instructions of the ops, to profile them.
+    #[cfg(feature = "profile")]
+    instructions: Vec<Instruction>,
+    /// The op that starts at each instruction.
+    ops: Vec<Op>,
+    dispatch: Dispatch,
+    positions: Vec<usize>,
+    memory: Vec<T>,
+    boundary: Boundary,
+    eof: Eof,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    stats: Stats,
+    #[cfg(feature = "profile")]
+    profile: Profile,
+}
+impl<T: Cell> Program<T> {
+    /// Parse and optimize `source`. With `superinstructions`, the most common
+    /// pairs of instructions are run with a single dispatch, see `fuse`.
+    pub fn new(
+        source: &[u8],
+        config: &Config,
+        dispatch: Dispatch,
+        superinstructions: bool,
+    ) -> Result<Program<T>, UnbalancedBrackets> {
+        let ir = bf_core::parse(source)?;
+        let (ir, stats) = bf_core::optimize(&ir, config);
+        let ops = (0..ir.instructions.len())
+            .map(|i| match superinstructions {
+                true => fuse(&ir.instructions[i..]),
+                false => Op::from(ir.instructions[i]),
+            })
+            .collect();
+
+        Ok(Program {
+            program_counter: 0,
+            pointer: 0,
+            #[cfg(feature = "profile")]
+            instructions: ir.instructions,
+            ops,
+            dispatch,
+            positions: ir.positions,
+            memory: vec![T::default(); config.tape_size],
+            boundary: config.boundary,
+            eof: config.eof,
+            fuel: config.fuel,
+            timeout: config.timeout,
+            stats,
+            #[cfg(feature = "profile")]
+            profile: Profile::default(),
+        })
+    }
+
+    /// The statistics of the optimization of the program.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Return the index of the cell at `offset` from the pointer, handling
+    /// moves out of the tape according to `self.boundary`.
+    #[inline]
+    fn offset_pointer(&mut self, offset: isize) -> Result<usize, RuntimeError> {
+        let to = self.pointer as isize + offset;
+        if (0..self.memory.len() as isize).contains(&to) {
+            return Ok(to as usize);
+        }
+        self.out_of_tape(to)
+    }
+
+    /// Handle an access to the cell at index `to`, out of the tape.
+    #[cold]
+    fn out_of_tape(&mut self, to: isize) -> Result<usize, RuntimeError> {
+        let len = self.memory.len() as isize;
+        let position = self.positions[self.program_counter];
+        match self.boundary {
+            Boundary::Wrap => Ok(to.rem_euclid(len) as usize),
+            Boundary::Error | Boundary::Grow | Boundary::Guard if to < 0 => {
+                Err(RuntimeError::PointerUnderflow(position))
+            }
+            Boundary::Error | Boundary::Guard => Err(RuntimeError::PointerOverflow(position)),
+            Boundary::Grow => {
+                let len = (to as usize + 1).max(self.memory.len() * 2);
+                self.memory.resize(len, T::default());
+                Ok(to as usize)
+            }
+        }
+    }
+
+    /// Return the index of the first zero cell from the pointer in
+    /// `direction`, 1 or -1, searching the whole tape with `Boundary::Wrap`.
+    /// If there is none, return the last cell before leaving the tape, or the
+    /// pointer itself when wrapping.
+    fn scan(&self, direction: isize) -> usize {
+        let pointer = self.pointer;
+        let wrap = self.boundary == Boundary::Wrap;
+        if direction > 0 {
+            match T::find_zero(&self.memory[pointer..]) {
+                Some(i) => pointer + i,
+                None if wrap => T::find_zero(&self.memory[..pointer]).unwrap_or(pointer),
+                None => self.memory.len() - 1,
+            }
+        } else {
+            match T::rfind_zero(&self.memory[..=pointer]) {
+                Some(i) => i,
+                None if wrap => {
+                    T::rfind_zero(&self.memory[pointer + 1..]).map_or(pointer, |i| pointer + 1 + i)
+                }
+                None => 0,
+            }
+        }
+    }
+
+    /// Run the program, reading from stdin and writing to stdout.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.run_with_io(std::io::stdin().lock(), std::io::stdout().lock())
+    }
+
+    /// Run the program, reading from `input` and writing to `output`.
+    pub fn run_with_io<R: Read, W: Write>(
+        &mut self,
+        input: R,
+        output: W,
+    ) -> Result<(), RuntimeError> {
+        let mut io = Io {
+            input,
+            output,
+            budget: Budget::new(self.fuel, self.timeout, None),
+        };
+
+        // the optimizations can remove all the instructions.
+        match self.dispatch {
+            Dispatch::Match => {
+                while self.program_counter < self.ops.len() {
+                    let op = self.ops[self.program_counter];
+                    #[cfg(feature = "profile")]
+                    self.count(op.len());
+                    self.run_op(op, &mut io)?;
+                }
+            }
+            Dispatch::Threaded => {
+                let handlers: Vec<Handler<T, R, W>> =
+                    self.ops.iter().map(|&op| Self::handler(op)).collect();
+                while self.program_counter < handlers.len() {
+                    #[cfg(feature = "profile")]
+                    self.count(self.ops[self.program_counter].len());
+                    handlers[self.program_counter](self, &mut io)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `op`, and move the program counter past it. Each instruction of a
+    /// superinstruction is run with the program counter on it, for the errors
+    /// to point at it.
+    #[inline(always)]
+    fn run_op<R: Read, W: Write>(&mut self, op: Op, io: &mut Io<R, W>) -> Result<(), RuntimeError> {
+        match op {
+            Op::Add(offset, n) => self.add(offset, n)?,
+            Op::Move(n) => self.pointer = self.offset_pointer(n)?,
+            Op::Input(offset) => self.input(offset, &mut io.input)?,
+            Op::Output(offset) => self.output(offset, &mut io.output)?,
+            Op::JumpRight(pair_address) => self.jump_right(pair_address),
+            Op::JumpLeft(pair_address) => self.jump_left(pair_address, &mut io.budget)?,
+            Op::Set(offset, n) => {
+                let cell = self.offset_pointer(offset)?;
+                self.memory[cell] = T::default().add_wrapping(n);
+            }
+            Op::Clear(offset) => {
+                let cell = self.offset_pointer(offset)?;
+                self.memory[cell] = T::default();
+            }
+            Op::MulAdd { offset, factor } => self.mul_add(offset, factor)?,
+            Op::MoveUntil(n) => self.move_until(n, &mut io.budget)?,
+            Op::Check(offset) => {
+                self.offset_pointer(offset)?;
+            }
+            Op::AddAdd(a, x, b, y) => {
+                self.add(a, x)?;
+                self.program_counter += 1;
+                self.add(b, y)?;
+            }
+            Op::AddMove(offset, n, m) => {
+                self.add(offset, n)?;
+                self.program_counter += 1;
+                self.pointer = self.offset_pointer(m)?;
+            }
+            Op::MulAddClear { offset, factor } => {
+                self.mul_add(offset, factor)?;
+                self.program_counter += 1;
+                self.memory[self.pointer] = T::default();
+            }
+            Op::MoveJumpRight(n, pair_address) => {
+                self.pointer = self.offset_pointer(n)?;
+                self.program_counter += 1;
+                self.jump_right(pair_address);
+            }
+            Op::MoveJumpLeft(n, pair_address) => {
+                self.pointer = self.offset_pointer(n)?;
+                self.program_counter += 1;
+                self.jump_left(pair_address, &mut io.budget)?;
+            }
+        }
+        self.program_counter += 1;
+        Ok(())
+    }
+
+    /// Return the code of `op`, with `run_op` specialized for its variant.
+    fn handler<R: Read, W: Write>(op: Op) -> Handler<T, R, W> {
+        match op {
+            Op::Add(a, b) => Box::new(move |p, io| p.run_op(Op::Add(a, b), io)),
+            Op::Move(a) => Box::new(move |p, io| p.run_op(Op::Move(a), io)),
+            Op::Input(a) => Box::new(move |p, io| p.run_op(Op::Input(a), io)),
+            Op::Output(a) => Box::new(move |p, io| p.run_op(Op::Output(a), io)),
+            Op::JumpRight(a) => Box::new(move |p, io| p.run_op(Op::JumpRight(a), io)),
+            Op::JumpLeft(a) => Box::new(move |p, io| p.run_op(Op::JumpLeft(a), io)),
+            Op::Set(a, b) => Box::new(move |p, io| p.run_op(Op::Set(a, b), io)),
+            Op::Clear(a) => Box::new(move |p, io| p.run_op(Op::Clear(a), io)),
+            Op::MulAdd { offset, factor } => {
+                Box::new(move |p, io| p.run_op(Op::MulAdd { offset, factor }, io))
+            }
+            Op::MoveUntil(a) => Box::new(move |p, io| p.run_op(Op::MoveUntil(a), io)),
+            Op::Check(a) => Box::new(move |p, io| p.run_op(Op::Check(a), io)),
+            Op::AddAdd(a, b, c, d) => Box::new(move |p, io| p.run_op(Op::AddAdd(a, b, c, d), io)),
+            Op::AddMove(a, b, c) => Box::new(move |p, io| p.run_op(Op::AddMove(a, b, c), io)),
+            Op::MulAddClear { offset, factor } => {
+                Box::new(move |p, io| p.run_op(Op::MulAddClear { offset, factor }, io))
+            }
+            Op::MoveJumpRight(a, b) => Box::new(move |p, io| p.run_op(Op::MoveJumpRight(a, b), io)),
+            Op::MoveJumpLeft(a, b) => Box::new(move |p, io| p.run_op(Op::MoveJumpLeft(a, b), io)),
+        }
+    }
+
+    #[inline(always)]
+    fn add(&mut self, offset: isize, n: u32) -> Result<(), RuntimeError> {
+        let cell = self.offset_pointer(offset)?;
+        self.memory[cell] = self.memory[cell].add_wrapping(n);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn mul_add(&mut self, offset: isize, factor: u32) -> Result<(), RuntimeError> {
+        // the loop replaced by the instruction doesn't access the cell at
+        // `offset` if the current cell is zero, and it may be out of the tape.
+        let value: u32 = self.memory[self.pointer].into();
+        if value == 0 {
+            return Ok(());
+        }
+
+        let to = self.offset_pointer(offset)?;
+        self.memory[to] = self.memory[to].add_wrapping(value.wrapping_mul(factor));
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn jump_right(&mut self, pair_address: usize) {
+        if self.memory[self.pointer] == T::default() {
+            self.program_counter = pair_address;
+        }
+    }
+
+    #[inline(always)]
+    fn jump_left(&mut self, pair_address: usize, budget: &mut Budget) -> Result<(), RuntimeError> {
+        budget.step()?;
+        if self.memory[self.pointer] != T::default() {
+            self.program_counter = pair_address;
+        }
+        Ok(())
+    }
+
+    fn output(&mut self, offset: isize, output: &mut impl Write) -> Result<(), RuntimeError> {
+        let cell = self.offset_pointer(offset)?;
+        let value = self.memory[cell].to_byte();
+        // Writing a non-UTF-8 byte sequence on Windows error out.
+        if !cfg!(target_os = "windows") || value < 128 {
+            output.write_all(&[value])?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    fn input(&mut self, offset: isize, input: &mut impl Read) -> Result<(), RuntimeError> {
+        loop {
+            let cell = self.offset_pointer(offset)?;
+            let mut value = 0;
+            let err = input.read_exact(std::slice::from_mut(&mut value));
+            match err.as_ref().map_err(|e| e.kind()) {
+                Err(std::io::ErrorKind::UnexpectedEof) => {
+                    self.eof.apply(&mut self.memory[cell]);
+                    return Ok(());
+                }
+                _ => err?,
+            }
+            if cfg!(target_os = "windows") && value == b'\r' {
+                continue;
+            }
+            self.memory[cell] = T::from_byte(value);
+            return Ok(());
+        }
+    }
+
+    fn move_until(&mut self, n: isize, budget: &mut Budget) -> Result<(), RuntimeError> {
+        if n == 1 || n == -1 {
+            self.pointer = self.scan(n);
+        }
+        loop {
+            if self.memory[self.pointer] == T::default() {
+                return Ok(());
+            }
+
+            budget.step()?;
+            self.pointer = self.offset_pointer(n)?;
+        }
+    }
+
+    /// Count the execution of the instructions of an op of `len` instructions.
+    #[cfg(feature = "profile")]
+    fn count(&mut self, len: usize) {
+        use Instruction::*;
+
+        self.profile.dispatches += 1;
+        for program_counter in self.program_counter..self.program_counter + len {
+            let instr = self.instructions[program_counter];
+            match instr {
+                Add(..) => self.profile.add += 1,
+                Output(_) => self.profile.out += 1,
+                Input(_) => self.profile.inp += 1,
+                Move(_) => self.profile.mov += 1,
+                JumpRight(_) => self.profile.jr += 1,
+                JumpLeft(pair) => {
+                    self.profile.jl += 1;
+                    *self
+                        .profile
+                        .loops
+                        .entry(pair..program_counter + 1)
+                        .or_default() += 1;
+                }
+                Set(..) => self.profile.set += 1,
+                Clear(_) => self.profile.clear += 1,
+                MulAdd { .. } => self.profile.muladd += 1,
+                MoveUntil(_) => self.profile.movuntil += 1,
+                Check(_) => self.profile.check += 1,
+            }
+
+            let symbol = match instr {
+                Add(..) => "+",
+                Output(_) => ".",
+                Input(_) => ",",
+                Move(_) => ">",
+                JumpRight(_) => "[",
+                JumpLeft(_) => "]",
+                Set(..) => "=",
+                Clear(_) => "x",
+                MulAdd { .. } => "*",
+                MoveUntil(_) => ">>",
+                Check(_) => "?",
+            };
+            if let Some(last) = self.profile.last.replace(symbol) {
+                *self.profile.pairs.entry((last, symbol)).or_default() += 1;
+            }
+        }
+    }
+
+    /// Print how many times each instruction was executed, and the most
+    /// executed loops.
+    #[cfg(feature = "profile")]
+    pub fn print_profile(&mut self) {
+        let profile = std::mem::take(&mut self.profile);
+        println!("profile:");
+        println!(" +: {}", profile.add);
+        println!(" >: {}", profile.mov);
+        println!(" [: {}", profile.jr);
+        println!(" ]: {}", profile.jl);
+        println!(" .: {}", profile.out);
+        println!(" ,: {}", profile.inp);
+        println!(" =: {}", profile.set);
+        println!(" x: {}", profile.clear);
+        println!(" *: {}", profile.muladd);
+        println!(">>: {}", profile.movuntil);
+        println!(" ?: {}", profile.check);
+        println!("dispatches: {}", profile.dispatches);
+
+        let mut pairs: Vec<_> = profile.pairs.into_iter().collect();
+        pairs.sort_by_key(|x| x.1);
+        println!("pairs:");
+        for ((a, b), count) in pairs.into_iter().rev().take(10) {
+            println!("{:10}: {} {}", count, a, b);
+        }
+
+        println!("loops:");
+
+        // the offset of an instruction from the pointer, if any
+        let at = |offset: isize| -> String {
+            if offset == 0 {
+                String::new()
+            } else {
+                format!("@{}", offset)
+            }
+        };
+
+        let to_string = |range: std::ops::Range<usize>| -> String {
+            self.instructions[range]
+                .iter()
+                .map(|x| match x {
+                    Instruction::Add(offset, n) => {
+                        if (*n as i32) < 0 {
+                            format!("-{}{}", n.wrapping_neg(), at(*offset))
+                        } else {
+                            format!("+{}{}", n, at(*offset))
+                        }
+                    }
+                    Instruction::Move(n) => {
+                        if *n < 0 {
+                            format!("<{}", -n)
+                        } else {
+                            format!(">{}", n)
+                        }
+                    }
+                    Instruction::Input(offset) => format!(",{}", at(*offset)),
+                    Instruction::Output(offset) => format!(".{}", at(*offset)),
+                    Instruction::JumpRight(_) => "[".to_string(),
+                    Instruction::JumpLeft(_) => "]".to_string(),
+                    Instruction::Set(offset, n) => format!("={}{}", n, at(*offset)),
+                    Instruction::Clear(offset) => format!("x{}", at(*offset)),
+                    Instruction::MulAdd { offset, factor } => {
+                        format!("*{}{}", factor, at(*offset))
+                    }
+                    Instruction::MoveUntil(n) => {
+                        if *n < 0 {
+                            format!("<<{}", -n)
+                        } else {
+                            format!(">>{}", n)
+                        }
+                    }
+                    Instruction::Check(offset) => format!("?{}", at(*offset)),
+                })
+                .fold(String::new(), |a, b| a + &b)
+        };
+
+        let mut loops: Vec<_> = profile
+            .loops
+            .into_iter()
+            .map(|(range, count)| (to_string(range), count))
+            .collect();
+
+        // dedup identical code
+
+        loops.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for i in 1..loops.len() {
+            if loops[i - 1].0 == loops[i].0 {
+                loops[i].1 += loops[i - 1].1;
+                loops[i - 1].1 = 0; // mark to remove
+            }
+        }
+
+        loops.retain(|x| x.1 > 0);
+
+        // sort by count
+        loops.sort_by_key(|x| x.1);
+
+        for (code, count) in loops.into_iter().rev().take(20) {
+            println!("{:10}: {}", count, code);
+        }
+    }
+}
@@ -1,234 +1,34 @@
-use std::{
-    io::{Read, Write},
-    process::ExitCode,
-};
+use std::process::ExitCode;
 
-#[derive(PartialEq, Eq, Clone, Copy)]
-enum Instruction {
-    Add(u8),
-    Move(isize),
-    Input,
-    Output,
-    JumpRight(usize),
-    JumpLeft(usize),
-    Clear,
-    AddTo(isize),
-    MoveUntil(isize),
-}
-
-struct UnbalancedBrackets(char, usize);
-
-#[derive(Default, Debug)]
-#[cfg(feature = "profile")]
-struct Profile {
-    add: u64,
-    mov: u64,
-    jr: u64,
-    jl: u64,
-    inp: u64,
-    out: u64,
-    clear: u64,
-    addto: u64,
-    movuntil: u64,
-    loops: std::collections::HashMap<std::ops::Range<usize>, usize>,
-}
-
-struct Program {
-    program_counter: usize,
-    pointer: usize,
-    instructions: Vec<Instruction>,
-    memory: [u8; 30_000],
-    #[cfg(feature = "profile")]
-    profile: Profile,
-}
-impl Program {
-    fn new(source: &[u8]) -> Result<Program, UnbalancedBrackets> {
-        let mut instructions = Vec::new();
-        let mut bracket_stack = Vec::new();
-
-        for b in source {
-            let instr = match b {
-                b'+' | b'-' => {
-                    let inc = if *b == b'+' { 1 } else { 1u8.wrapping_neg() };
-                    if let Some(Instruction::Add(value)) = instructions.last_mut() {
-                        *value = value.wrapping_add(inc);
-                        continue;
-                    }
-                    Instruction::Add(inc)
-                }
-                b'.' => Instruction::Output,
-                b',' => Instruction::Input,
-                b'>' | b'<' => {
-                    let inc = if *b == b'>' { 1 } else { -1 };
-                    if let Some(Instruction::Move(value)) = instructions.last_mut() {
-                        *value += inc;
-                        continue;
-                    }
-                    Instruction::Move(inc)
-                }
-                b'[' => {
-                    let curr_address = instructions.len();
-                    bracket_stack.push(curr_address);
-                    // will be fixup at the pair ']'.
-                    Instruction::JumpRight(0)
-                }
-                b']' => {
-                    let curr_address = instructions.len();
-                    match bracket_stack.pop() {
-                        Some(pair_address) => {
-                            instructions[pair_address] = Instruction::JumpRight(curr_address);
-
-                            use Instruction::*;
-                            match instructions.as_slice() {
-                                // could enter a infinite loop if n is even.
-                                [.., JumpRight(_), Add(n)] if n % 2 == 1 => {
-                                    let len = instructions.len();
-                                    instructions.drain(len - 2..);
-                                    Instruction::Clear
-                                }
-                                &[.., JumpRight(_), Add(255), Move(x), Add(1), Move(y)]
-                                    if x == -y =>
-                                {
-                                    let len = instructions.len();
-                                    instructions.drain(len - 5..);
-                                    Instruction::AddTo(x)
-                                }
-                                &[.., JumpRight(_), Move(n)] => {
-                                    let len = instructions.len();
-                                    instructions.drain(len - 2..);
-                                    Instruction::MoveUntil(n)
-                                }
-                                _ => Instruction::JumpLeft(pair_address),
-                            }
-                        }
-                        None => return Err(UnbalancedBrackets(']', curr_address)),
-                    }
-                }
-                _ => continue,
-            };
-            instructions.push(instr);
-        }
-
-        if let Some(unpaired_bracket) = bracket_stack.pop() {
-            return Err(UnbalancedBrackets('[', unpaired_bracket));
-        }
-
-        Ok(Program {
-            program_counter: 0,
-            pointer: 0,
-            instructions,
-            memory: [0; 30_000],
-            #[cfg(feature = "profile")]
-            profile: Profile::default(),
-        })
-    }
-
-    fn run(&mut self) -> std::io::Result<()> {
-        let mut stdout = std::io::stdout().lock();
-        let mut stdin = std::io::stdin().lock();
-        'program: loop {
-            use Instruction::*;
-
-            #[cfg(feature = "profile")]
-            {
-                match self.instructions[self.program_counter] {
-                    Add(_) => self.profile.add += 1,
-                    Output => self.profile.out += 1,
-                    Input => self.profile.inp += 1,
-                    Move(_) => self.profile.mov += 1,
-                    JumpRight(_) => self.profile.jr += 1,
-                    JumpLeft(pair) => {
-                        self.profile.jl += 1;
-                        *self
-                            .profile
-                            .loops
-                            .entry(pair..self.program_counter + 1)
-                            .or_default() += 1;
-                    }
-                    Clear => self.profile.clear += 1,
-                    AddTo(_) => self.profile.addto += 1,
-                    MoveUntil(_) => self.profile.movuntil += 1,
-                }
-            }
-
-            match self.instructions[self.program_counter] {
-                Add(n) => self.memory[self.pointer] = self.memory[self.pointer].wrapping_add(n),
-                Output => {
-                    let value = self.memory[self.pointer];
-                    // Writing a non-UTF-8 byte sequence on Windows error out.
-                    if !cfg!(target_os = "windows") || value < 128 {
-                        stdout.write_all(&[value])?;
-                        stdout.flush()?;
-                    }
-                }
-                Input => loop {
-                    let err = stdin.read_exact(&mut self.memory[self.pointer..self.pointer + 1]);
-                    match err.as_ref().map_err(|e| e.kind()) {
-                        Err(std::io::ErrorKind::UnexpectedEof) => {
-                            self.memory[self.pointer] = 0;
-                        }
-                        _ => err?,
-                    }
-                    if cfg!(target_os = "windows") && self.memory[self.pointer] == b'\r' {
-                        continue;
-                    }
-                    break;
-                },
-                Move(n) => {
-                    let len = self.memory.len() as isize;
-                    let n = (len + n % len) as usize;
-                    self.pointer = (self.pointer + n) % len as usize;
-                }
-                JumpRight(pair_address) => {
-                    if self.memory[self.pointer] == 0 {
-                        self.program_counter = pair_address;
-                    }
-                }
-                JumpLeft(pair_address) => {
-                    if self.memory[self.pointer] != 0 {
-                        self.program_counter = pair_address;
-                    }
-                }
-                Clear => self.memory[self.pointer] = 0,
-                AddTo(n) => {
-                    let len = self.memory.len() as isize;
-                    let n = (len + n % len) as usize;
-                    let to = (self.pointer + n) % len as usize;
-
-                    self.memory[to] = self.memory[to].wrapping_add(self.memory[self.pointer]);
-                    self.memory[self.pointer] = 0
-                }
-                MoveUntil(n) => {
-                    let len = self.memory.len() as isize;
-                    let n = (len + n % len) as usize;
-                    loop {
-                        if self.memory[self.pointer] == 0 {
-                            break;
-                        }
-
-                        self.pointer = (self.pointer + n) % len as usize;
-                    }
-                }
-            }
-            self.program_counter += 1;
-
-            if self.instructions.len() == self.program_counter {
-                break 'program;
-            }
-        }
-        Ok(())
-    }
-}
+use bf_core::{Boundary, Cell, CellWidth, Config};
+use bf_optimized::{Dispatch, Program};
 
 fn main() -> ExitCode {
-    let mut args = std::env::args();
-    if args.len() != 2 {
+    let (config, args) = match Config::from_args(std::env::args().skip(1)) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(1);
+        }
+    };
+    let (threaded, args): (Vec<_>, Vec<_>) = args.into_iter().partition(|arg| arg == "--threaded");
+    let (superinstructions, args): (Vec<_>, Vec<_>) = args
+        .into_iter()
+        .partition(|arg| arg == "--superinstructions");
+    let (emit, args): (Vec<_>, Vec<_>) =
+        args.into_iter().partition(|arg| arg.starts_with("--emit="));
+    let dispatch = match threaded.is_empty() {
+        true => Dispatch::Match,
+        false => Dispatch::Threaded,
+    };
+    let superinstructions = !superinstructions.is_empty();
+    if args.len() != 1 {
         eprintln!("expected a single file path as argument");
         return ExitCode::from(1);
     }
 
-    let file_name = args.nth(1).unwrap();
-    let source = match std::fs::read(&file_name) {
+    let file_name = &args[0];
+    let source = match std::fs::read(file_name) {
         Ok(x) => x,
         Err(err) => {
             eprintln!("Error reading '{}': {}", file_name, err);
@@ -236,103 +36,71 @@ fn main() -> ExitCode {
         }
     };
 
-    let mut program = match Program::new(&source) {
+    if let Some(emit) = emit.last() {
+        return emit_c(emit, file_name, &source, &config);
+    }
+
+    match config.cell_width {
+        CellWidth::U8 => run::<u8>(file_name, &source, &config, dispatch, superinstructions),
+        CellWidth::U16 => run::<u16>(file_name, &source, &config, dispatch, superinstructions),
+        CellWidth::U32 => run::<u32>(file_name, &source, &config, dispatch, superinstructions),
+    }
+}
+
+fn run<T: Cell>(
+    file_name: &str,
+    source: &[u8],
+    config: &Config,
+    dispatch: Dispatch,
+    superinstructions: bool,
+) -> ExitCode {
+    let mut program = match Program::<T>::new(source, config, dispatch, superinstructions) {
         Ok(x) => x,
-        Err(UnbalancedBrackets(c, address)) => {
-            eprintln!(
-                "Error parsing file: didn't found pair for `{}` at instruction index {}",
-                c, address
-            );
+        Err(err) => {
+            eprint!("{}", err.report(file_name, source));
             return ExitCode::from(3);
         }
     };
 
+    if config.stats {
+        eprint!("{}", program.stats());
+    }
+
+    let mut exit_code = ExitCode::from(0);
     if let Err(err) = program.run() {
-        eprintln!("IO error: {}", err);
+        eprint!("{}", err.report(file_name, source));
+        exit_code = ExitCode::from(5);
     }
 
     #[cfg(feature = "profile")]
-    {
-        let profile = std::mem::take(&mut program.profile);
-        println!("profile:");
-        println!(" +: {}", profile.add);
-        println!(" >: {}", profile.mov);
-        println!(" [: {}", profile.jr);
-        println!(" ]: {}", profile.jl);
-        println!(" .: {}", profile.out);
-        println!(" ,: {}", profile.inp);
-        println!(" x: {}", profile.clear);
-        println!("+>: {}", profile.addto);
-        println!(">>: {}", profile.movuntil);
-        println!("loops:");
-
-        let to_string = |range: std::ops::Range<usize>| -> String {
-            program.instructions[range]
-                .iter()
-                .map(|x| match x {
-                    Instruction::Add(n) => {
-                        if *n >= 128 {
-                            format!("-{}", n.wrapping_neg())
-                        } else {
-                            format!("+{}", n)
-                        }
-                    }
-                    Instruction::Move(n) => {
-                        if *n < 0 {
-                            format!("<{}", -n)
-                        } else {
-                            format!(">{}", n)
-                        }
-                    }
-                    Instruction::Input => ",".to_string(),
-                    Instruction::Output => ".".to_string(),
-                    Instruction::JumpRight(_) => "[".to_string(),
-                    Instruction::JumpLeft(_) => "]".to_string(),
-                    Instruction::Clear => "x".to_string(),
-                    Instruction::AddTo(n) => {
-                        if *n < 0 {
-                            format!("+<{}", -n)
-                        } else {
-                            format!("+>{}", n)
-                        }
-                    }
-                    Instruction::MoveUntil(n) => {
-                        if *n < 0 {
-                            format!("<<{}", -n)
-                        } else {
-                            format!(">>{}", n)
-                        }
-                    }
-                })
-                .fold(String::new(), |a, b| a + &b)
-        };
-
-        let mut loops: Vec<_> = profile
-            .loops
-            .into_iter()
-            .map(|(range, count)| (to_string(range), count))
-            .collect();
+    program.print_profile();
 
-        // dedup identical code
+    exit_code
+}
 
-        loops.sort_by(|a, b| a.0.cmp(&b.0));
+/// Print the program transpiled to C, instead of running it.
+fn emit_c(emit: &str, file_name: &str, source: &[u8], config: &Config) -> ExitCode {
+    if emit != "--emit=c" {
+        eprintln!("unknown {}, expected --emit=c", emit);
+        return ExitCode::from(1);
+    }
+    if config.fuel.is_some() || config.timeout.is_some() {
+        eprintln!("--fuel and --timeout are not supported by --emit=c");
+        return ExitCode::from(1);
+    }
+    if !matches!(config.boundary, Boundary::Wrap | Boundary::Error) {
+        eprintln!("only --boundary wrap and error are supported by --emit=c");
+        return ExitCode::from(1);
+    }
 
-        for i in 1..loops.len() {
-            if loops[i - 1].0 == loops[i].0 {
-                loops[i].1 += loops[i - 1].1;
-                loops[i - 1].1 = 0; // mark to remove
-            }
+    match bf_optimized::to_c(source, config) {
+        Ok(c) => {
+            print!("{}", c);
+            ExitCode::from(0)
         }
-
-        loops.retain(|x| x.1 > 0);
-
-        // sort by count
-        loops.sort_by_key(|x| x.1);
-
-        for (code, count) in loops.into_iter().rev().take(20) {
-            println!("{:10}: {}", count, code);
+        Err(err) => {
+            eprint!("{}", err.report(file_name, source));
+            ExitCode::from(3)
         }
     }
-
-    ExitCode::from(0)
 }
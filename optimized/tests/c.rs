@@ -0,0 +1,72 @@
+//! Compile the programs transpiled to C with `cc`, and compare their output
+//! and errors with the ones of the simple `interpreter`.
+
+use std::process::Command;
+
+use bf_core::{Boundary, CellWidth, Config, Eof, RuntimeError};
+use bf_testing::{Backend, Outcome};
+
+/// The programs transpiled to C, and compiled with `cc`.
+struct C;
+impl Backend for C {
+    fn run(&self, source: &[u8], config: &Config, input: &[u8]) -> Outcome {
+        let c_path = bf_testing::temp_path("program.c");
+        let exe_path = bf_testing::temp_path("program");
+        std::fs::write(&c_path, bf_optimized::to_c(source, config).unwrap()).unwrap();
+
+        let status = Command::new("cc")
+            .arg("-O1")
+            .arg("-o")
+            .arg(&exe_path)
+            .arg(&c_path)
+            .status()
+            .unwrap();
+        assert!(
+            status.success(),
+            "compiling {}",
+            String::from_utf8_lossy(source)
+        );
+
+        let outcome = bf_testing::run_command(&mut Command::new(&exe_path), input);
+        std::fs::remove_file(&c_path).unwrap();
+        std::fs::remove_file(&exe_path).unwrap();
+        outcome
+    }
+
+    fn message(&self, source: &[u8], err: RuntimeError) -> String {
+        bf_testing::message(source, err, true)
+    }
+}
+
+/// Return true if `cc` can be run, since the tests are skipped without it.
+fn has_cc() -> bool {
+    let found = Command::new("cc").arg("--version").output().is_ok();
+    if !found {
+        eprintln!("skipped, `cc` was not found");
+    }
+    found
+}
+
+#[test]
+fn programs() {
+    if !has_cc() {
+        return;
+    }
+    bf_testing::programs(&C);
+}
+
+/// Random programs, that end or fail in a limited number of steps, on tapes
+/// small enough for them to leave often.
+#[test]
+fn random() {
+    if !has_cc() {
+        return;
+    }
+    bf_testing::random(&C, 100, |random| Config {
+        tape_size: 3 + random.below(5) as usize,
+        cell_width: *random.choose(&[CellWidth::U8, CellWidth::U16, CellWidth::U32]),
+        boundary: *random.choose(&[Boundary::Wrap, Boundary::Error]),
+        eof: *random.choose(&[Eof::Zero, Eof::MinusOne, Eof::Unchanged]),
+        ..Config::default()
+    });
+}
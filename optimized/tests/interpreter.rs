@@ -0,0 +1,145 @@
+//! Compare the output and the errors of the optimized interpreter with the
+//! ones of the simple `interpreter`, that runs the unoptimized instructions.
+
+use bf_core::{Boundary, Cell, CellWidth, Config};
+use bf_optimized::{Dispatch, Program};
+use bf_testing::{Backend, Outcome};
+
+/// The optimized interpreter, with a dispatch, and with or without the
+/// superinstructions.
+struct Optimized(Dispatch, bool);
+impl Backend for Optimized {
+    fn run(&self, source: &[u8], config: &Config, input: &[u8]) -> Outcome {
+        fn run<T: Cell>(
+            source: &[u8],
+            config: &Config,
+            optimized: &Optimized,
+            input: &[u8],
+        ) -> Outcome {
+            let mut output = Vec::new();
+            let result = Program::<T>::new(source, config, optimized.0, optimized.1)
+                .unwrap()
+                .run_with_io(input, &mut output);
+            (output, result.err().map(|err| format!("{:?}", err)))
+        }
+        match config.cell_width {
+            CellWidth::U8 => run::<u8>(source, config, self, input),
+            CellWidth::U16 => run::<u16>(source, config, self, input),
+            CellWidth::U32 => run::<u32>(source, config, self, input),
+        }
+    }
+}
+
+/// Each way to run the optimized instructions.
+const OPTIMIZED: [Optimized; 4] = [
+    Optimized(Dispatch::Match, false),
+    Optimized(Dispatch::Match, true),
+    Optimized(Dispatch::Threaded, false),
+    Optimized(Dispatch::Threaded, true),
+];
+
+fn compare(source: &[u8], config: &Config, input: &[u8]) {
+    for optimized in &OPTIMIZED {
+        bf_testing::compare(optimized, source, config, input);
+    }
+}
+
+/// Programs without instructions, that end immediately.
+#[test]
+fn empty_program() {
+    for source in [&b""[..], b"no instructions\n"] {
+        for boundary in [Boundary::Wrap, Boundary::Error, Boundary::Grow] {
+            let config = Config {
+                boundary,
+                ..Config::default()
+            };
+            compare(source, &config, b"");
+            let (output, result) = bf_testing::run_interpreter(source, &config, b"");
+            assert!(output.is_empty() && result.is_ok());
+        }
+    }
+}
+
+/// Programs whose offsets wrap around a small tape, to the same cell. The
+/// ones that never end run out of fuel.
+#[test]
+fn wrapping_offsets() {
+    let programs: [(&[u8], usize, Option<u64>); 3] = [
+        (b"+[>>>-<<<]++++++++[>++++++++<-]>+.", 3, None),
+        (b"+[->>>+<<<]++++++++[>++++++++<-]>+.", 3, Some(10_000)),
+        (b"++[->+>>>++<<<<]>.", 3, None),
+    ];
+    for (source, tape_size, fuel) in programs {
+        for prefix_steps in [0, 1 << 20] {
+            let config = Config {
+                tape_size,
+                boundary: Boundary::Wrap,
+                fuel,
+                prefix_steps,
+                ..Config::default()
+            };
+            compare(source, &config, b"");
+        }
+    }
+}
+
+/// A `MulAdd` to a cell out of the tape, from a zero cell, doesn't fail, like
+/// the loop it replaces.
+#[test]
+fn mul_add_out_of_tape() {
+    for boundary in [Boundary::Error, Boundary::Grow, Boundary::Guard] {
+        for prefix_steps in [0, 1 << 20] {
+            let config = Config {
+                tape_size: 5,
+                boundary,
+                prefix_steps,
+                ..Config::default()
+            };
+            for input in [b"\0", b"\x01"] {
+                compare(b">>,[->>>+<<<]+++++++[>+++++++++<-]>++.", &config, input);
+            }
+        }
+    }
+}
+
+/// Merged moves that leave the tape and come back, or whose accesses cancel
+/// out, fail at the same position as the unoptimized ones.
+#[test]
+fn merged_moves_out_of_tape() {
+    let programs: [&[u8]; 7] = [
+        b".<-+",
+        b"-++.+>+<-<>[-]<>-.>+[+]->+-,--+[-<+>]+-",
+        b"+.<>.",
+        b"+.>>>>>>>>+-<<<<<<<<.",
+        b",<[-]",
+        b"+[>>>>>>>>+<<<<<<<<-]+.",
+        b"+.[-<<+>>]>>>>>>>>[-]<<<<<<<<.",
+    ];
+    for boundary in [Boundary::Error, Boundary::Grow, Boundary::Guard] {
+        for prefix_steps in [0, 1 << 20] {
+            let config = Config {
+                tape_size: 7,
+                boundary,
+                prefix_steps,
+                ..Config::default()
+            };
+            for source in programs {
+                compare(source, &config, b"\x01");
+            }
+        }
+    }
+}
+
+/// Random programs, that end or fail in a limited number of steps.
+#[test]
+fn random() {
+    let configs = bf_testing::configs(&[
+        Boundary::Wrap,
+        Boundary::Error,
+        Boundary::Grow,
+        Boundary::Guard,
+    ]);
+    for optimized in &OPTIMIZED {
+        bf_testing::random(optimized, 2000, |random| random.choose(&configs).clone());
+    }
+}
@@ -1,4 +1,34 @@
-use std::io::{Read, Write};
+use std::io::{IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// How many bytes are buffered before they are written to stdout.
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// The output of the program not yet written to stdout.
+static OUTPUT: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+/// Flush after each byte, set by `bf_unbuffered`.
+static UNBUFFERED: AtomicBool = AtomicBool::new(false);
+/// Flush after each newline, if stdout is a terminal.
+static INTERACTIVE: OnceLock<bool> = OnceLock::new();
+
+fn flush(output: &mut Vec<u8>) {
+    let mut stdout = std::io::stdout().lock();
+
+    let result = stdout.write_all(output).and_then(|_| stdout.flush());
+    output.clear();
+
+    if let Err(err) = result {
+        eprintln!("IO error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Write each output byte immediately, instead of buffering the output.
+#[no_mangle]
+pub extern "sysv64" fn bf_unbuffered() {
+    UNBUFFERED.store(true, Ordering::Relaxed);
+}
 
 #[no_mangle]
 pub extern "sysv64" fn bf_write(value: u8) {
@@ -7,18 +37,41 @@ pub extern "sysv64" fn bf_write(value: u8) {
         return;
     }
 
-    let mut stdout = std::io::stdout().lock();
+    let mut output = OUTPUT.lock().unwrap();
+    output.push(value);
 
-    let result = stdout.write_all(&[value]).and_then(|_| stdout.flush());
+    let interactive = || *INTERACTIVE.get_or_init(|| std::io::stdout().is_terminal());
+    if UNBUFFERED.load(Ordering::Relaxed)
+        || output.len() >= BUFFER_SIZE
+        || (value == b'\n' && interactive())
+    {
+        flush(&mut output);
+    }
+}
 
-    if let Err(err) = result {
-        eprintln!("IO error: {}", err);
-        std::process::exit(1);
+/// Write the `len` bytes at `buf`, the output of the part of the program run
+/// at compile time.
+#[no_mangle]
+pub unsafe extern "sysv64" fn bf_write_all(buf: *const u8, len: usize) {
+    for &value in std::slice::from_raw_parts(buf, len) {
+        bf_write(value);
     }
 }
 
+/// What `,` does to the current cell when the input reached its end. Must
+/// match `bf_core::Eof`.
+#[repr(C)]
+pub enum Eof {
+    Zero,
+    MinusOne,
+    Unchanged,
+}
+
+/// Read a byte into the cell at `buf`, of `cell_bytes` bytes.
 #[no_mangle]
-pub unsafe extern "sysv64" fn bf_read(buf: *mut u8) {
+pub unsafe extern "sysv64" fn bf_read(buf: *mut u8, cell_bytes: usize, eof: Eof) {
+    flush(&mut OUTPUT.lock().unwrap());
+
     let mut stdin = std::io::stdin().lock();
     loop {
         let mut value = 0;
@@ -29,7 +82,12 @@ pub unsafe extern "sysv64" fn bf_read(buf: *mut u8) {
                 eprintln!("IO error: {}", err);
                 std::process::exit(1);
             }
-            value = 0;
+            match eof {
+                Eof::Zero => std::ptr::write_bytes(buf, 0, cell_bytes),
+                Eof::MinusOne => std::ptr::write_bytes(buf, 0xff, cell_bytes),
+                Eof::Unchanged => {}
+            }
+            break;
         }
 
         // ignore CR from Window's CRLF
@@ -37,6 +95,8 @@ pub unsafe extern "sysv64" fn bf_read(buf: *mut u8) {
             continue;
         }
 
+        // the cell is little endian
+        std::ptr::write_bytes(buf, 0, cell_bytes);
         *buf = value;
         break;
     }
@@ -44,5 +104,51 @@ pub unsafe extern "sysv64" fn bf_read(buf: *mut u8) {
 
 #[no_mangle]
 pub unsafe extern "sysv64" fn bf_exit() {
+    flush(&mut OUTPUT.lock().unwrap());
     std::process::exit(0);
 }
+
+#[no_mangle]
+pub extern "sysv64" fn bf_pointer_underflow(line: u32, column: u32) {
+    flush(&mut OUTPUT.lock().unwrap());
+    eprintln!("error: pointer moved left of cell 0 at {}:{}", line, column);
+    std::process::exit(5);
+}
+
+#[no_mangle]
+pub extern "sysv64" fn bf_pointer_overflow(line: u32, column: u32) {
+    flush(&mut OUTPUT.lock().unwrap());
+    eprintln!(
+        "error: pointer moved right of the last cell at {}:{}",
+        line, column
+    );
+    std::process::exit(5);
+}
+
+/// The address and length of a tape, returned in `rax` and `rdx`.
+#[repr(C)]
+pub struct TapeSlice {
+    ptr: *mut u8,
+    len: usize,
+}
+
+/// Allocate a zeroed tape of `len` cells, that can be grown by
+/// `bf_grow_tape`.
+#[no_mangle]
+pub extern "sysv64" fn bf_alloc_tape(len: usize) -> *mut u8 {
+    Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8
+}
+
+/// Grow the tape at `ptr`, of length `len`, so that `index` is a valid cell.
+#[no_mangle]
+pub unsafe extern "sysv64" fn bf_grow_tape(ptr: *mut u8, len: usize, index: usize) -> TapeSlice {
+    let mut tape = Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)).into_vec();
+    tape.resize((index + 1).max(len * 2), 0);
+    let mut tape = tape.into_boxed_slice();
+    let slice = TapeSlice {
+        ptr: tape.as_mut_ptr(),
+        len: tape.len(),
+    };
+    std::mem::forget(tape);
+    slice
+}
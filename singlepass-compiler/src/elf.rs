@@ -0,0 +1,439 @@
+//! Write a static x86-64 Linux executable, that doesn't need to be linked: the
+//! functions of `bf_lib` are replaced by a small runtime, written in assembly,
+//! that makes the `read`, `write` and `exit` system calls directly.
+
+use dynasmrt::{dynasm, x64::X64Relocation, DynasmApi, DynasmLabelApi, VecAssembler};
+use object::{
+    elf::{
+        ELFOSABI_SYSV, EM_X86_64, ET_EXEC, PF_R, PF_W, PF_X, PT_LOAD, SHF_ALLOC, SHF_EXECINSTR,
+        SHF_WRITE, SHT_NOBITS, SHT_PROGBITS,
+    },
+    write::elf::{FileHeader, ProgramHeader, SectionHeader, Writer},
+};
+
+use bf_core::Prefix;
+
+/// The address where the file is loaded.
+const BASE: u64 = 0x400000;
+const PAGE_SIZE: u64 = 0x1000;
+
+/// How many bytes are buffered before they are written to stdout.
+const BUFFER_SIZE: usize = 8 * 1024;
+
+// The layout of the zero initialized data of the runtime.
+/// The length of the buffered output, a u64.
+const LEN: i32 = 0;
+/// If each byte is flushed, set by `bf_unbuffered`, a u8.
+const UNBUFFERED: i32 = 8;
+/// If each line is flushed, when stdout is a terminal, a u8.
+const INTERACTIVE: i32 = 9;
+/// The buffered output.
+const BUFFER: i32 = 16;
+const DATA_SIZE: u64 = BUFFER as u64 + BUFFER_SIZE as u64;
+
+// Linux system calls.
+const SYS_READ: i32 = 0;
+const SYS_WRITE: i32 = 1;
+const SYS_MMAP: i32 = 9;
+const SYS_IOCTL: i32 = 16;
+const SYS_MREMAP: i32 = 25;
+const SYS_EXIT_GROUP: i32 = 231;
+const EINTR: i32 = -4;
+const TCGETS: i32 = 0x5401;
+
+/// Assemble the runtime. `data` is the address of its zero initialized data.
+/// Each function of `bf_lib` is defined at the global label of the same name,
+/// and the entry point, `_start`, falls through into the code that follows
+/// the runtime.
+fn runtime(data: u32) -> VecAssembler<X64Relocation> {
+    let data = data as i32;
+    let underflow = b"error: pointer moved left of cell 0 at ";
+    let overflow = b"error: pointer moved right of the last cell at ";
+    let io_error = b"IO error\n";
+    let out_of_memory = b"error: out of memory\n";
+
+    let mut code: VecAssembler<X64Relocation> = VecAssembler::new(0);
+
+    dynasm! { code
+        ; .arch x64
+
+        // Write the buffered output to stdout. Only clobbers the registers
+        // that a call can clobber.
+        ; ->flush:
+        ; mov r8d, data
+        ; lea rsi, [r8 + BUFFER]
+        ; mov rdx, [r8 + LEN]
+        ; flush_loop:
+        ; test rdx, rdx
+        ; jz >flushed
+        ; mov edi, 1
+        ; mov eax, SYS_WRITE
+        ; syscall
+        ; cmp rax, EINTR
+        ; je <flush_loop
+        ; test rax, rax
+        ; jle ->io_error
+        ; add rsi, rax
+        ; sub rdx, rax
+        ; jmp <flush_loop
+        ; flushed:
+        ; mov QWORD [r8 + LEN], 0
+        ; ret
+
+        ; ->bf_unbuffered:
+        ; mov r8d, data
+        ; mov BYTE [r8 + UNBUFFERED], 1
+        ; ret
+
+        // dil: the byte to write
+        ; ->bf_write:
+        ; mov r8d, data
+        ; mov rax, [r8 + LEN]
+        ; mov [r8 + rax + BUFFER], dil
+        ; inc rax
+        ; mov [r8 + LEN], rax
+        ; cmp BYTE [r8 + UNBUFFERED], 0
+        ; jne ->flush
+        ; cmp rax, BUFFER_SIZE as i32
+        ; jae ->flush
+        ; cmp dil, b'\n' as i8
+        ; jne >done
+        ; cmp BYTE [r8 + INTERACTIVE], 0
+        ; jne ->flush
+        ; done:
+        ; ret
+
+        // rdi: the address of the bytes, rsi: their length
+        ; ->bf_write_all:
+        ; push rbx
+        ; push r15
+        ; mov rbx, rdi
+        ; mov r15, rsi
+        ; write_loop:
+        ; test r15, r15
+        ; jz >done
+        ; movzx edi, BYTE [rbx]
+        ; call ->bf_write
+        ; inc rbx
+        ; dec r15
+        ; jmp <write_loop
+        ; done:
+        ; pop r15
+        ; pop rbx
+        ; ret
+
+        // rdi: the address of the cell, esi: its size, edx: the `Eof`
+        ; ->bf_read:
+        ; push rdi
+        ; push rsi
+        ; push rdx
+        ; call ->flush
+        ; sub rsp, 8
+        ; read_loop:
+        ; xor edi, edi
+        ; mov rsi, rsp
+        ; mov edx, 1
+        ; mov eax, SYS_READ
+        ; syscall
+        ; cmp rax, EINTR
+        ; je <read_loop
+        ; test rax, rax
+        ; js ->io_error
+        ; mov r9, rax
+        ; movzx ecx, BYTE [rsp]
+        ; add rsp, 8
+        ; pop rdx
+        ; pop rsi
+        ; pop rdi
+        ; test r9, r9
+        ; jnz >store
+        // the end of the input: apply the `Eof`
+        ; cmp edx, 2
+        ; je >done
+        ; xor ecx, ecx
+        ; cmp edx, 1
+        ; jne >store
+        ; mov ecx, -1
+        // store ecx in the cell, little endian
+        ; store:
+        ; cmp esi, 1
+        ; je >byte
+        ; cmp esi, 2
+        ; je >word
+        ; mov [rdi], ecx
+        ; ret
+        ; word:
+        ; mov [rdi], cx
+        ; ret
+        ; byte:
+        ; mov [rdi], cl
+        ; done:
+        ; ret
+
+        ; ->bf_exit:
+        ; call ->flush
+        ; xor edi, edi
+        ; mov eax, SYS_EXIT_GROUP
+        ; syscall
+
+        // edi: the line, esi: the column
+        ; ->bf_pointer_underflow:
+        ; lea rax, [->underflow]
+        ; mov ecx, underflow.len() as i32
+        ; jmp ->trap
+        ; ->bf_pointer_overflow:
+        ; lea rax, [->overflow]
+        ; mov ecx, overflow.len() as i32
+        // rax: the message, ecx: its length
+        ; ->trap:
+        ; push rsi
+        ; push rdi
+        ; push rcx
+        ; push rax
+        ; call ->flush
+        ; pop rsi
+        ; pop rdx
+        ; call ->write_stderr
+        ; pop rdi
+        ; call ->write_decimal
+        ; lea rsi, [->colon]
+        ; mov edx, 1
+        ; call ->write_stderr
+        ; pop rdi
+        ; call ->write_decimal
+        ; lea rsi, [->newline]
+        ; mov edx, 1
+        ; call ->write_stderr
+        ; mov edi, 5
+        ; mov eax, SYS_EXIT_GROUP
+        ; syscall
+
+        ; ->io_error:
+        ; lea rsi, [->io_error_message]
+        ; mov edx, io_error.len() as i32
+        ; call ->write_stderr
+        ; mov edi, 1
+        ; mov eax, SYS_EXIT_GROUP
+        ; syscall
+
+        ; ->out_of_memory:
+        ; lea rsi, [->out_of_memory_message]
+        ; mov edx, out_of_memory.len() as i32
+        ; call ->write_stderr
+        ; mov edi, 1
+        ; mov eax, SYS_EXIT_GROUP
+        ; syscall
+
+        // rsi: the address of the bytes, rdx: their length
+        ; ->write_stderr:
+        ; mov edi, 2
+        ; mov eax, SYS_WRITE
+        ; syscall
+        ; ret
+
+        // edi: the number to write to stderr
+        ; ->write_decimal:
+        ; sub rsp, 16
+        ; mov eax, edi
+        ; lea rsi, [rsp + 16]
+        ; mov ecx, 10
+        ; digit_loop:
+        ; xor edx, edx
+        ; div ecx
+        ; add dl, b'0' as i8
+        ; dec rsi
+        ; mov [rsi], dl
+        ; test eax, eax
+        ; jnz <digit_loop
+        ; lea rdx, [rsp + 16]
+        ; sub rdx, rsi
+        ; call ->write_stderr
+        ; add rsp, 16
+        ; ret
+
+        // rdi: the size of the tape, returns its address in rax
+        ; ->bf_alloc_tape:
+        ; mov rsi, rdi
+        ; xor edi, edi
+        ; mov edx, 3 // PROT_READ | PROT_WRITE
+        ; mov r10d, 0x22 // MAP_PRIVATE | MAP_ANONYMOUS
+        ; mov r8, -1
+        ; xor r9d, r9d
+        ; mov eax, SYS_MMAP
+        ; syscall
+        ; cmp rax, -4095
+        ; jae ->out_of_memory
+        ; ret
+
+        // rdi: the address of the tape, rsi: its size, rdx: the index to
+        // grow to. Returns the new address and size in rax and rdx.
+        ; ->bf_grow_tape:
+        ; lea rax, [rdx + 1]
+        ; lea rcx, [rsi + rsi]
+        ; cmp rax, rcx
+        ; cmovb rax, rcx
+        // the mapping is page aligned, and the new pages are zeroed
+        ; add rsi, PAGE_SIZE as i32 - 1
+        ; and rsi, -(PAGE_SIZE as i32)
+        ; add rax, PAGE_SIZE as i32 - 1
+        ; and rax, -(PAGE_SIZE as i32)
+        ; mov rdx, rax
+        ; push rdx
+        ; mov r10d, 1 // MREMAP_MAYMOVE
+        ; mov eax, SYS_MREMAP
+        ; syscall
+        ; pop rdx
+        ; cmp rax, -4095
+        ; jae ->out_of_memory
+        ; ret
+
+        ; ->underflow:
+        ; .bytes underflow
+        ; ->overflow:
+        ; .bytes overflow
+        ; ->colon:
+        ; .bytes b":"
+        ; ->newline:
+        ; .bytes b"\n"
+        ; ->io_error_message:
+        ; .bytes io_error
+        ; ->out_of_memory_message:
+        ; .bytes out_of_memory
+
+        // flush each line if stdout is a terminal, whose TCGETS succeeds
+        ; ->_start:
+        ; sub rsp, 64
+        ; mov edi, 1
+        ; mov esi, TCGETS
+        ; mov rdx, rsp
+        ; mov eax, SYS_IOCTL
+        ; syscall
+        ; add rsp, 64
+        ; mov r8d, data
+        ; test rax, rax
+        ; sete BYTE [r8 + INTERACTIVE]
+    };
+
+    code
+}
+
+/// Write the executable of the program `code`, whose calls to `bf_lib` and
+/// references to the prefix are at `relocations`.
+pub fn executable(code: &[u8], relocations: &[(usize, &'static str)], prefix: &Prefix) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut writer = Writer::new(object::Endianness::Little, true, &mut out);
+
+    let text_name = writer.add_section_name(b".text");
+    writer.reserve_section_index();
+    let bss_name = writer.add_section_name(b".bss");
+    writer.reserve_section_index();
+
+    writer.reserve_file_header();
+    writer.reserve_program_headers(2);
+
+    writer.reserve_shstrtab_section_index();
+    writer.reserve_shstrtab();
+    writer.reserve_section_headers();
+
+    // the runtime depends on the address of its data, that is placed after the
+    // code, but its size doesn't.
+    let runtime_len = runtime(0).finalize().unwrap().len();
+    let text_len = runtime_len + code.len() + prefix.tape.len() + prefix.output.len();
+    let text_offset = writer.reserve(text_len, 16);
+
+    let text_address = BASE + text_offset as u64;
+    let code_address = text_address + runtime_len as u64;
+    let tape_address = code_address + code.len() as u64;
+    let output_address = tape_address + prefix.tape.len() as u64;
+    let data_address = (BASE + writer.reserved_len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    let runtime = runtime(data_address as u32);
+    let symbol = |name: &'static str| match name {
+        "bf_prefix_tape" => tape_address,
+        "bf_prefix_output" => output_address,
+        _ => text_address + runtime.labels().resolve_global(name).unwrap().0 as u64,
+    };
+
+    let mut code = code.to_vec();
+    for &(offset, name) in relocations {
+        let next = code_address + offset as u64 + 4;
+        let relative = symbol(name).wrapping_sub(next) as i32;
+        code[offset..offset + 4].copy_from_slice(&relative.to_le_bytes());
+    }
+    let entry = symbol("_start");
+    let runtime = runtime.finalize().unwrap();
+    assert_eq!(runtime.len(), runtime_len);
+
+    writer
+        .write_file_header(&FileHeader {
+            os_abi: ELFOSABI_SYSV,
+            abi_version: 0,
+            e_type: ET_EXEC,
+            e_machine: EM_X86_64,
+            e_entry: entry,
+            e_flags: 0,
+        })
+        .unwrap();
+
+    // map the whole file, for simplicity, and the data after it
+    writer.write_align_program_headers();
+    writer.write_program_header(&ProgramHeader {
+        p_type: PT_LOAD,
+        p_flags: PF_R | PF_X,
+        p_offset: 0,
+        p_vaddr: BASE,
+        p_paddr: BASE,
+        p_filesz: writer.reserved_len() as u64,
+        p_memsz: writer.reserved_len() as u64,
+        p_align: PAGE_SIZE,
+    });
+    writer.write_program_header(&ProgramHeader {
+        p_type: PT_LOAD,
+        p_flags: PF_R | PF_W,
+        p_offset: 0,
+        p_vaddr: data_address,
+        p_paddr: data_address,
+        p_filesz: 0,
+        p_memsz: DATA_SIZE,
+        p_align: PAGE_SIZE,
+    });
+
+    writer.write_shstrtab();
+
+    writer.write_null_section_header();
+    writer.write_section_header(&SectionHeader {
+        name: Some(text_name),
+        sh_type: SHT_PROGBITS,
+        sh_flags: (SHF_ALLOC | SHF_EXECINSTR) as u64,
+        sh_addr: text_address,
+        sh_offset: text_offset as u64,
+        sh_size: text_len as u64,
+        sh_link: 0,
+        sh_info: 0,
+        sh_addralign: 16,
+        sh_entsize: 0,
+    });
+    writer.write_section_header(&SectionHeader {
+        name: Some(bss_name),
+        sh_type: SHT_NOBITS,
+        sh_flags: (SHF_ALLOC | SHF_WRITE) as u64,
+        sh_addr: data_address,
+        sh_offset: 0,
+        sh_size: DATA_SIZE,
+        sh_link: 0,
+        sh_info: 0,
+        sh_addralign: 16,
+        sh_entsize: 0,
+    });
+    writer.write_shstrtab_section_header();
+
+    writer.write_align(16);
+    writer.write(&runtime);
+    writer.write(&code);
+    writer.write(&prefix.tape);
+    writer.write(&prefix.output);
+
+    assert_eq!(writer.reserved_len(), writer.len());
+
+    out
+}
@@ -1,5 +1,8 @@
 use std::process::ExitCode;
 
+use bf_core::{
+    Boundary, CellWidth, Config, Instruction, Prefix, SourceLocation, UnbalancedBrackets,
+};
 use dynasmrt::{dynasm, x64::X64Relocation, DynasmApi, DynasmLabelApi, VecAssembler};
 
 use object::{
@@ -7,128 +10,217 @@ use object::{
     SymbolFlags,
 };
 
-struct UnbalancedBrackets(char, usize);
+mod elf;
 
 struct Program {
     code: Vec<u8>,
-    write_relocations: Vec<usize>,
-    read_relocations: Vec<usize>,
-    exit_relocation: usize,
+    /// The offset of each call to a function of `bf_lib`, and the name of the
+    /// function.
+    relocations: Vec<(usize, &'static str)>,
+    /// The state left by the part of the program run at compile time, whose
+    /// tape and output are stored in the read-only data.
+    prefix: Prefix,
 }
 impl Program {
-    fn new(source: &[u8]) -> Result<Program, UnbalancedBrackets> {
+    fn new(source: &[u8], config: &Config) -> Result<Program, UnbalancedBrackets> {
+        let ir = bf_core::parse(source)?;
+        let prefix = Prefix::evaluate(&ir, config);
+        let cell_width = config.cell_width;
+        // the pointer and the tape size are in bytes, not in cells.
+        let bytes = cell_width.bytes() as i32;
+        let tape_size = config.tape_size as i32 * bytes;
+
         let mut code: VecAssembler<X64Relocation> = VecAssembler::new(0);
 
+        let mut relocations = Vec::new();
+
         // r12 will be the adress of `memory`
-        // r13 will be the value of `pointer`
-        // r13 is set to 0
+        // r13 will be the value of `pointer`, times the cell width
+        // r14 will be the length of `memory`
+        // r13 is set to the pointer left by the prefix
         dynasm! { code
             ; .arch x64
             ; push rbp
             ; mov rbp, rsp
-            ; xor r13, r13
-
-            // allocate 30_0000 bytes on stack for the memory
-            ; sub rsp, 30_000
-            ; mov r12, rsp
-
-            // zero the memory
-            ; xor eax, eax
-            ; mov r11, rbp
-            ; loop_:
-            ; add r11, -8
-            ; mov QWORD [r11], rax
-            ; cmp r11, r12
-            ; jne <loop_
+            ; mov r13, (prefix.pointer * cell_width.bytes()) as i32
         };
 
-        let mut write_relocations = Vec::new();
-        let mut read_relocations = Vec::new();
+        if config.unbuffered {
+            dynasm! { code
+                ; .arch x64
+                ; call DWORD 0
+                ;; relocations.push((code.offset().0 - 4, "bf_unbuffered"))
+            };
+        }
+
+        // the tape is allocated on the heap, since it may be too large for the
+        // stack, or grow.
+        dynasm! { code
+            ; .arch x64
+            ; mov edi, tape_size
+            ; call DWORD 0
+            ;; relocations.push((code.offset().0 - 4, "bf_alloc_tape"))
+            ; mov r12, rax
+            ; mov r14d, tape_size
+        };
+
+        if !prefix.tape.is_empty() {
+            dynasm! { code
+                ; .arch x64
+                ; lea rsi, [rip + 0]
+                ;; relocations.push((code.offset().0 - 4, "bf_prefix_tape"))
+                ; mov rdi, r12
+                ; mov ecx, prefix.tape.len() as i32
+                ; rep movsb
+            };
+        }
+
+        if !prefix.output.is_empty() {
+            dynasm! { code
+                ; .arch x64
+                ; lea rdi, [rip + 0]
+                ;; relocations.push((code.offset().0 - 4, "bf_prefix_output"))
+                ; mov rsi, QWORD prefix.output.len() as i64
+                ; call DWORD 0
+                ;; relocations.push((code.offset().0 - 4, "bf_write_all"))
+            };
+        }
+
+        // skip the instructions run by the prefix
+        let resume_label = code.new_dynamic_label();
+        dynasm! { code
+            ; .arch x64
+            ; jmp =>resume_label
+        };
 
         let mut bracket_stack = Vec::new();
 
-        for b in source {
-            match b {
-                b'+' => dynasm! { code
+        let len = ir.instructions.len();
+        for (index, (instr, position)) in ir.instructions.into_iter().zip(ir.positions).enumerate()
+        {
+            if index == prefix.resume {
+                dynasm! { code
                     ; .arch x64
-                    ; add BYTE [r12 + r13], 1
-                },
-                b'-' => dynasm! { code
-                    ; .arch x64
-                    ; add BYTE [r12 + r13], -1
-                },
-                b'.' => dynasm! { code
+                    ; =>resume_label
+                };
+            }
+            match instr {
+                Instruction::Add(0, n) => emit_add(&mut code, cell_width, n),
+                Instruction::Output(0) => dynasm! { code
                     ; .arch x64
-                    ; mov rdi, [r12 + r13] // cell value
+                    ; movzx edi, BYTE [r12 + r13] // cell value
                     ; call DWORD 0
-                    ;; write_relocations.push(code.offset().0 - 4)
+                    ;; relocations.push((code.offset().0 - 4, "bf_write"))
                 },
-                b',' => dynasm! { code
+                Instruction::Input(0) => dynasm! { code
                     ; .arch x64
                     ; lea rdi, [r12 + r13] // cell address
+                    ; mov esi, bytes
+                    ; mov edx, config.eof as i32
                     ; call DWORD 0
-                    ;; read_relocations.push(code.offset().0 - 4)
+                    ;; relocations.push((code.offset().0 - 4, "bf_read"))
                 },
 
-                b'<' => dynasm! { code
-                    ; .arch x64
-                    ; sub r13, 1
-                    ; mov eax, 29999
-                    ; cmovb r13, rax
+                Instruction::Move(-1) => match config.boundary {
+                    Boundary::Wrap => dynasm! { code
+                        ; .arch x64
+                        ; sub r13, bytes
+                        ; mov eax, tape_size - bytes
+                        ; cmovb r13, rax
+                    },
+                    Boundary::Error | Boundary::Grow | Boundary::Guard => dynasm! { code
+                        ; .arch x64
+                        ; sub r13, bytes
+                        ; jnc >ok
+                        ;; emit_trap(&mut code, &mut relocations, "bf_pointer_underflow", source, position)
+                        ; ok:
+                    },
                 },
-                b'>' => dynasm! { code
-                    ; .arch x64
-                    ; add r13, 1
-                    ; xor eax, eax
-                    ; cmp r13, 30000
-                    ; cmove r13, rax
+                Instruction::Move(1) => match config.boundary {
+                    Boundary::Wrap => dynasm! { code
+                        ; .arch x64
+                        ; add r13, bytes
+                        ; xor eax, eax
+                        ; cmp r13, tape_size
+                        ; cmove r13, rax
+                    },
+                    Boundary::Error | Boundary::Guard => dynasm! { code
+                        ; .arch x64
+                        ; add r13, bytes
+                        ; cmp r13, tape_size
+                        ; jb >ok
+                        ;; emit_trap(&mut code, &mut relocations, "bf_pointer_overflow", source, position)
+                        ; ok:
+                    },
+                    Boundary::Grow => dynasm! { code
+                        ; .arch x64
+                        ; add r13, bytes
+                        ; cmp r13, r14
+                        ; jb >ok
+                        ; mov rdi, r12
+                        ; mov rsi, r14
+                        ; lea rdx, [r13 + bytes - 1]
+                        ; call DWORD 0
+                        ;; relocations.push((code.offset().0 - 4, "bf_grow_tape"))
+                        ; mov r12, rax
+                        ; mov r14, rdx
+                        ; ok:
+                    },
                 },
-                b'[' => {
+                Instruction::JumpRight(_) => {
                     let start_label = code.new_dynamic_label();
                     let end_label = code.new_dynamic_label();
+                    emit_cmp_zero(&mut code, cell_width);
                     dynasm! { code
                         ; .arch x64
-                        ; cmp BYTE [r12+r13], 0
                         ; je =>end_label
                         ; =>start_label
                     };
 
                     bracket_stack.push((start_label, end_label));
                 }
-                b']' => {
-                    let (start_label, end_label) = match bracket_stack.pop() {
-                        Some(x) => x,
-                        None => return Err(UnbalancedBrackets(']', code.offset().0)),
-                    };
+                Instruction::JumpLeft(_) => {
+                    let (start_label, end_label) = bracket_stack.pop().unwrap();
 
+                    emit_cmp_zero(&mut code, cell_width);
                     dynasm! { code
                         ; .arch x64
-                        ; cmp BYTE [r12 + r13], 0
                         ; jne =>start_label
                         ; => end_label
                     };
                 }
-                _ => continue,
+                // `bf_core::parse` only emit moves of a single cell and
+                // instructions on the current cell, and don't emit optimized
+                // instructions.
+                Instruction::Move(_)
+                | Instruction::Add(..)
+                | Instruction::Output(_)
+                | Instruction::Input(_)
+                | Instruction::Set(..)
+                | Instruction::Clear(_)
+                | Instruction::MulAdd { .. }
+                | Instruction::MoveUntil(_)
+                | Instruction::Check(_) => unreachable!(),
             }
         }
 
-        if !bracket_stack.is_empty() {
-            return Err(UnbalancedBrackets(']', code.offset().0));
+        if prefix.resume == len {
+            dynasm! { code
+                ; .arch x64
+                ; =>resume_label
+            };
         }
 
-        let exit_relocation;
-
         dynasm! { code
             ; .arch x64
             ; call DWORD 0
-            ;; exit_relocation = code.offset().0 - 4
+            ;; relocations.push((code.offset().0 - 4, "bf_exit"))
         }
 
         Ok(Program {
             code: code.finalize().unwrap(),
-            write_relocations,
-            read_relocations,
-            exit_relocation,
+            relocations,
+            prefix,
         })
     }
 
@@ -148,7 +240,7 @@ impl Program {
             object::Endianness::Little,
         );
 
-        let mut add_symbol = |name: &[u8]| {
+        let add_symbol = |obj: &mut object::write::Object, name: &[u8]| {
             obj.add_symbol(Symbol {
                 name: name.to_vec(),
                 value: 0,
@@ -161,15 +253,39 @@ impl Program {
             })
         };
 
-        let start = add_symbol(entry_name);
-        let bf_write = add_symbol(b"bf_write");
-        let bf_read = add_symbol(b"bf_read");
-        let bf_exit = add_symbol(b"bf_exit");
+        let start = add_symbol(&mut obj, entry_name);
 
         let text = obj.section_id(object::write::StandardSection::Text);
         obj.add_symbol_data(start, text, &self.code, 16);
 
-        let mut add_call_reloc = |offset, symbol| {
+        let mut symbols = std::collections::HashMap::new();
+
+        let rodata = obj.section_id(object::write::StandardSection::ReadOnlyData);
+        let data = [
+            ("bf_prefix_tape", &self.prefix.tape),
+            ("bf_prefix_output", &self.prefix.output),
+        ];
+        for (name, data) in data {
+            if data.is_empty() {
+                continue;
+            }
+            let symbol = obj.add_symbol(Symbol {
+                name: name.as_bytes().to_vec(),
+                value: 0,
+                size: 0,
+                kind: object::SymbolKind::Data,
+                scope: object::SymbolScope::Compilation,
+                weak: false,
+                section: object::write::SymbolSection::Undefined,
+                flags: SymbolFlags::None,
+            });
+            obj.add_symbol_data(symbol, rodata, data, 1);
+            symbols.insert(name, symbol);
+        }
+        for &(offset, name) in self.relocations.iter() {
+            let symbol = *symbols
+                .entry(name)
+                .or_insert_with(|| add_symbol(&mut obj, name.as_bytes()));
             obj.add_relocation(
                 text,
                 Relocation {
@@ -182,15 +298,7 @@ impl Program {
                 },
             )
             .unwrap();
-        };
-
-        for offset in self.read_relocations.iter().copied() {
-            add_call_reloc(offset, bf_read);
         }
-        for offset in self.write_relocations.iter().copied() {
-            add_call_reloc(offset, bf_write);
-        }
-        add_call_reloc(self.exit_relocation, bf_exit);
 
         let mut out = Vec::new();
         obj.emit(&mut out).unwrap();
@@ -199,10 +307,76 @@ impl Program {
     }
 }
 
+/// Emit code that adds `n` to the current cell.
+fn emit_add(code: &mut VecAssembler<X64Relocation>, cell_width: CellWidth, n: u32) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch x64
+            ; add BYTE [r12 + r13], n as i8
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch x64
+            ; add WORD [r12 + r13], n as i16
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch x64
+            ; add DWORD [r12 + r13], n as i32
+        },
+    }
+}
+
+/// Emit code that compares the current cell with zero.
+fn emit_cmp_zero(code: &mut VecAssembler<X64Relocation>, cell_width: CellWidth) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch x64
+            ; cmp BYTE [r12 + r13], 0
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch x64
+            ; cmp WORD [r12 + r13], 0
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch x64
+            ; cmp DWORD [r12 + r13], 0
+        },
+    }
+}
+
+/// Emit a call to `trap`, a function of `bf_lib` that reports the error at
+/// `position` and exits.
+fn emit_trap(
+    code: &mut VecAssembler<X64Relocation>,
+    relocations: &mut Vec<(usize, &'static str)>,
+    trap: &'static str,
+    source: &[u8],
+    position: usize,
+) {
+    let location = SourceLocation::new(source, position);
+    dynasm! { code
+        ; .arch x64
+        ; mov edi, location.line as i32
+        ; mov esi, location.column as i32
+        ; call DWORD 0
+        ;; relocations.push((code.offset().0 - 4, trap))
+    }
+}
+
 fn main() -> ExitCode {
-    let mut args = std::env::args();
+    let (config, args) = match Config::from_args(std::env::args().skip(1)) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(1);
+        }
+    };
+    if config.fuel.is_some() || config.timeout.is_some() {
+        eprintln!("--fuel and --timeout are not supported by compiled programs");
+        return ExitCode::from(1);
+    }
+    let mut args = args.into_iter();
 
-    let file_name = args.nth(1).unwrap();
+    let file_name = args.next().unwrap();
     let source = match std::fs::read(&file_name) {
         Ok(x) => x,
         Err(err) => {
@@ -211,13 +385,10 @@ fn main() -> ExitCode {
         }
     };
 
-    let program = match Program::new(&source) {
+    let program = match Program::new(&source, &config) {
         Ok(x) => x,
-        Err(UnbalancedBrackets(c, address)) => {
-            eprintln!(
-                "Error parsing file: didn't found pair for `{}` at instruction index {}",
-                c, address
-            );
+        Err(err) => {
+            eprint!("{}", err.report(&file_name, &source));
             return ExitCode::from(3);
         }
     };
@@ -231,11 +402,21 @@ fn main() -> ExitCode {
             .to_string()
     });
     match option.unwrap().as_str() {
-        "-o" => {
+        "-c" => {
             let output_name = std::path::Path::new(&output_name).with_extension("o");
             let obj = program.to_elf_object();
             std::fs::write(output_name, obj).unwrap();
         }
+        "-o" => {
+            let exe = elf::executable(&program.code, &program.relocations, &program.prefix);
+            std::fs::write(&output_name, exe).unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let permissions = std::fs::Permissions::from_mode(0o755);
+                std::fs::set_permissions(&output_name, permissions).unwrap();
+            }
+        }
         arg => panic!("unknown arg {arg}"),
     }
 
@@ -0,0 +1,103 @@
+//! Compile programs to static executables with `-o`, run them, and compare
+//! their output and errors with the ones of the simple `interpreter`.
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::process::Command;
+
+use bf_core::{Boundary, CellWidth, Config, Eof, RuntimeError};
+use bf_testing::{Backend, Outcome};
+
+/// Compile `source` with the options `args`, and return the path of the
+/// executable.
+fn compile(source: &[u8], args: &[impl AsRef<OsStr>]) -> PathBuf {
+    let source_path = bf_testing::temp_path("program.bf");
+    let exe_path = bf_testing::temp_path("program");
+    std::fs::write(&source_path, source).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_singlepass-compiler"))
+        .args(args)
+        .arg(&source_path)
+        .arg("-o")
+        .arg(&exe_path)
+        .status()
+        .unwrap();
+    assert!(
+        status.success(),
+        "compiling {}",
+        String::from_utf8_lossy(source)
+    );
+    std::fs::remove_file(&source_path).unwrap();
+    exe_path
+}
+
+/// Compile `source` with the options `args`, run the executable with `input`,
+/// and return its output and its error.
+fn run(source: &[u8], args: &[impl AsRef<OsStr>], input: &[u8]) -> Outcome {
+    let exe_path = compile(source, args);
+    let outcome = bf_testing::run_command(&mut Command::new(&exe_path), input);
+    std::fs::remove_file(&exe_path).unwrap();
+    outcome
+}
+
+/// The executables, compiled with the options of the configuration.
+struct Executable;
+impl Backend for Executable {
+    fn run(&self, source: &[u8], config: &Config, input: &[u8]) -> Outcome {
+        run(source, &bf_testing::args(config), input)
+    }
+
+    fn message(&self, source: &[u8], err: RuntimeError) -> String {
+        bf_testing::message(source, err, true)
+    }
+}
+
+#[test]
+fn programs() {
+    bf_testing::programs(&Executable);
+}
+
+/// Random programs, that end or fail in a limited number of steps, on tapes
+/// small enough for them to leave often.
+#[test]
+fn random() {
+    bf_testing::random(&Executable, 100, |random| Config {
+        tape_size: 3 + random.below(5) as usize,
+        cell_width: *random.choose(&[CellWidth::U8, CellWidth::U16, CellWidth::U32]),
+        boundary: *random.choose(&[Boundary::Wrap, Boundary::Error, Boundary::Grow]),
+        eof: *random.choose(&[Eof::Zero, Eof::MinusOne, Eof::Unchanged]),
+        prefix_steps: *random.choose(&[0, 7, 1 << 20]),
+        ..Config::default()
+    });
+}
+
+/// A tape larger than the stack, and one of almost `i32::MAX` bytes, the
+/// largest one.
+#[test]
+fn large_tape() {
+    let source = b"++++++++[>++++++++<-]>+.>>>>>>>>,.";
+    for (tape_size, cell_width) in [("100000000", "8"), ("536870911", "32")] {
+        let outcome = run(
+            source,
+            &[
+                "--tape-size",
+                tape_size,
+                "--cell-width",
+                cell_width,
+                "--boundary",
+                "error",
+            ],
+            b"z",
+        );
+        assert_eq!(outcome, (b"Az".to_vec(), None));
+    }
+
+    let outcome = run(
+        b"+[>+]",
+        &["--tape-size", "100000000", "--boundary", "error"],
+        b"",
+    );
+    let message = "error: pointer moved right of the last cell at 1:3\n";
+    assert_eq!(outcome, (Vec::new(), Some(message.to_string())));
+}
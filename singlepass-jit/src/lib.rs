@@ -0,0 +1,471 @@
+//! A brainfuck JIT compiler, that translates each instruction of
+//! `bf_core::parse` to x86-64 machine code, using `dynasmrt`.
+
+use std::io::{IsTerminal, Read, Write};
+use std::time::Duration;
+
+use bf_core::{
+    Boundary, Budget, Cell, CellWidth, Config, Eof, GuardedTape, Instruction, Output, Prefix,
+    RuntimeError, UnbalancedBrackets,
+};
+use dynasmrt::mmap::MutableBuffer;
+use dynasmrt::{dynasm, x64::X64Relocation, DynasmApi, DynasmLabelApi, VecAssembler};
+
+pub struct Program {
+    code: Vec<u8>,
+    memory: Vec<u8>,
+    tape_size: usize,
+    eof: Eof,
+    unbuffered: bool,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+    prefix: Prefix,
+    guard: bool,
+}
+impl Program {
+    pub fn new(source: &[u8], config: &Config) -> Result<Program, UnbalancedBrackets> {
+        let config = &bf_core::round_to_pages(config);
+        let ir = bf_core::parse(source)?;
+        let prefix = Prefix::evaluate(&ir, config);
+        // the moves that can be left to the guard pages
+        let guard = config.boundary == Boundary::Guard;
+        let guarded = bf_core::in_bounds(&ir, config);
+        let cell_width = config.cell_width;
+        // the pointer and the tape size are in bytes, not in cells.
+        let bytes = cell_width.bytes() as i32;
+        let tape_size = config.tape_size as i32 * bytes;
+
+        let mut code: VecAssembler<X64Relocation> = VecAssembler::new(0);
+
+        // r12 will be the adress of `memory`
+        // r13 will be the value of `pointer`, times the cell width
+        // r14 will be the length of `memory`
+        // r15 will be the address of the `Context`
+        // r12, r14 and r15 are got from arguments 1, 2 and 3
+        // r13 is set to the pointer left by the prefix
+        dynasm! { code
+            ; .arch x64
+            ; push rbp
+            ; mov rbp, rsp
+            ; push r12
+            ; push r13
+            ; push r14
+            ; push r15
+            ; mov r12, rdi
+            ; mov r13, (prefix.pointer * cell_width.bytes()) as i32
+            ; mov r14, rsi
+            ; mov r15, rdx
+        };
+
+        // skip the instructions run by the prefix
+        let resume_label = code.new_dynamic_label();
+        dynasm! { code
+            ; .arch x64
+            ; jmp =>resume_label
+        };
+
+        let read_address = match cell_width {
+            CellWidth::U8 => read::<u8> as *const (),
+            CellWidth::U16 => read::<u16> as *const (),
+            CellWidth::U32 => read::<u32> as *const (),
+        };
+
+        // only count the steps when they are limited
+        let limited = config.fuel.is_some() || config.timeout.is_some();
+
+        let mut bracket_stack = Vec::new();
+
+        let len = ir.instructions.len();
+        for (index, (instr, position)) in ir.instructions.into_iter().zip(ir.positions).enumerate()
+        {
+            if index == prefix.resume {
+                dynasm! { code
+                    ; .arch x64
+                    ; =>resume_label
+                };
+            }
+            match instr {
+                Instruction::Add(0, n) => emit_add(&mut code, cell_width, n),
+                Instruction::Output(0) => {
+                    dynasm! { code
+                        ; .arch x64
+                        ; mov rax, QWORD write as *const () as i64
+                        ; mov rdi, r15
+                        ; movzx esi, BYTE [r12 + r13] // cell value
+                        ; call rax
+                        ; cmp rax, 0
+                        ; jne ->exit
+                    }
+                }
+                Instruction::Input(0) => {
+                    dynasm! { code
+                        ; .arch x64
+                        // fault here, and not in `read`, out of a guarded tape
+                        ; cmp BYTE [r12 + r13], 0
+                        ; mov rax, QWORD read_address as i64
+                        ; mov rdi, r15
+                        ; lea rsi, [r12 + r13] // cell address
+                        ; call rax
+                        ; cmp rax, 0
+                        ; jne ->exit
+                    }
+                }
+                Instruction::Move(-1) if guard && guarded[index] => dynasm! { code
+                    ; .arch x64
+                    ; sub r13, bytes
+                },
+                Instruction::Move(1) if guard && guarded[index] => dynasm! { code
+                    ; .arch x64
+                    ; add r13, bytes
+                },
+                Instruction::Move(-1) => match config.boundary {
+                    Boundary::Wrap => dynasm! { code
+                        ; .arch x64
+                        ; sub r13, bytes
+                        ; mov eax, tape_size - bytes
+                        ; cmovb r13, rax
+                    },
+                    Boundary::Error | Boundary::Grow | Boundary::Guard => dynasm! { code
+                        ; .arch x64
+                        ; sub r13, bytes
+                        ; jnc >ok
+                        ;; emit_trap(&mut code, bf_core::pointer_underflow, position)
+                        ; ok:
+                    },
+                },
+                Instruction::Move(1) => match config.boundary {
+                    Boundary::Wrap => dynasm! { code
+                        ; .arch x64
+                        ; add r13, bytes
+                        ; xor eax, eax
+                        ; cmp r13, tape_size
+                        ; cmove r13, rax
+                    },
+                    Boundary::Error | Boundary::Guard => dynasm! { code
+                        ; .arch x64
+                        ; add r13, bytes
+                        ; cmp r13, tape_size
+                        ; jb >ok
+                        ;; emit_trap(&mut code, bf_core::pointer_overflow, position)
+                        ; ok:
+                    },
+                    Boundary::Grow => dynasm! { code
+                        ; .arch x64
+                        ; add r13, bytes
+                        ; cmp r13, r14
+                        ; jb >ok
+                        ; lea rsi, [r13 + bytes - 1]
+                        ;; emit_grow(&mut code)
+                        ; ok:
+                    },
+                },
+                Instruction::JumpRight(_) => {
+                    let start_label = code.new_dynamic_label();
+                    let end_label = code.new_dynamic_label();
+                    emit_cmp_zero(&mut code, cell_width);
+                    dynasm! { code
+                        ; .arch x64
+                        ; je =>end_label
+                        ; =>start_label
+                    };
+
+                    bracket_stack.push((start_label, end_label));
+                }
+                Instruction::JumpLeft(_) => {
+                    let (start_label, end_label) = bracket_stack.pop().unwrap();
+
+                    if limited {
+                        emit_step(&mut code);
+                    }
+                    emit_cmp_zero(&mut code, cell_width);
+                    dynasm! { code
+                        ; .arch x64
+                        ; jne =>start_label
+                        ; => end_label
+                    };
+                }
+                // `bf_core::parse` only emit moves of a single cell and
+                // instructions on the current cell, and don't emit optimized
+                // instructions.
+                Instruction::Move(_)
+                | Instruction::Add(..)
+                | Instruction::Output(_)
+                | Instruction::Input(_)
+                | Instruction::Set(..)
+                | Instruction::Clear(_)
+                | Instruction::MulAdd { .. }
+                | Instruction::MoveUntil(_)
+                | Instruction::Check(_) => unreachable!(),
+            }
+        }
+
+        if prefix.resume == len {
+            dynasm! { code
+                ; .arch x64
+                ; =>resume_label
+            };
+        }
+
+        // when we push to the stack, we need to remeber
+        // to pop them in the opossite order.
+        dynasm! { code
+            ; .arch x64
+            ; xor rax, rax
+            ; ->exit:
+            ; pop r15
+            ; pop r14
+            ; pop r13
+            ; pop r12
+            ; pop rbp
+            ; ret
+        }
+
+        Ok(Program {
+            code: code.finalize().unwrap(),
+            memory: vec![0; config.tape_size * cell_width.bytes()],
+            tape_size: config.tape_size * cell_width.bytes(),
+            eof: config.eof,
+            unbuffered: config.unbuffered,
+            fuel: config.fuel,
+            timeout: config.timeout,
+            prefix,
+            guard,
+        })
+    }
+
+    /// Run the program, reading from stdin and writing to stdout.
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        // flush each line when the user may be reading it
+        let flush_on_newline = std::io::stdout().is_terminal();
+        self.run_inner(
+            &mut std::io::stdin().lock(),
+            &mut std::io::stdout().lock(),
+            flush_on_newline,
+        )
+    }
+
+    /// Run the program, reading from `input` and writing to `output`.
+    pub fn run_with_io(
+        &mut self,
+        mut input: impl Read,
+        mut output: impl Write,
+    ) -> Result<(), RuntimeError> {
+        self.run_inner(&mut input, &mut output, false)
+    }
+
+    fn run_inner(
+        &mut self,
+        input: &mut dyn Read,
+        output: &mut dyn Write,
+        flush_on_newline: bool,
+    ) -> Result<(), RuntimeError> {
+        // start each run from the tape left by the prefix
+        let mut guarded = match self.guard {
+            true => Some(GuardedTape::new(self.tape_size)?),
+            false => None,
+        };
+        match &mut guarded {
+            Some(tape) => {
+                tape.as_mut_slice()[..self.prefix.tape.len()].copy_from_slice(&self.prefix.tape)
+            }
+            None => {
+                self.memory.clear();
+                self.memory.extend_from_slice(&self.prefix.tape);
+                self.memory.resize(self.tape_size, 0);
+            }
+        }
+
+        let mut buffer = MutableBuffer::new(self.code.len()).unwrap();
+        buffer.set_len(self.code.len());
+
+        buffer.copy_from_slice(&self.code);
+
+        let buffer = buffer.make_exec().unwrap();
+
+        unsafe {
+            let code_fn: unsafe extern "sysv64" fn(
+                *mut u8,
+                usize,
+                *mut Context,
+            ) -> *mut RuntimeError = std::mem::transmute(buffer.as_ptr());
+
+            let memory = self.memory.as_mut_ptr();
+            let memory_len = self.memory.len();
+            let mut context = Context {
+                memory: &mut self.memory,
+                budget: Budget::new(self.fuel, self.timeout, None),
+                input,
+                output: Output::new(output, self.unbuffered, flush_on_newline),
+                eof: self.eof,
+            };
+
+            let mut error = std::ptr::null_mut();
+            for &value in &self.prefix.output {
+                error = write(&mut context, value);
+                if !error.is_null() {
+                    break;
+                }
+            }
+            if error.is_null() {
+                error = match &mut guarded {
+                    Some(tape) => tape.call(buffer.as_ptr(), &mut context),
+                    None => code_fn(memory, memory_len, &mut context),
+                };
+            }
+
+            // flush the output even if the program failed
+            let flushed = context.output.flush();
+
+            if !error.is_null() {
+                return Err(*Box::from_raw(error));
+            }
+            flushed?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Emit code that adds `n` to the current cell.
+fn emit_add(code: &mut VecAssembler<X64Relocation>, cell_width: CellWidth, n: u32) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch x64
+            ; add BYTE [r12 + r13], n as i8
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch x64
+            ; add WORD [r12 + r13], n as i16
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch x64
+            ; add DWORD [r12 + r13], n as i32
+        },
+    }
+}
+
+/// Emit code that compares the current cell with zero.
+fn emit_cmp_zero(code: &mut VecAssembler<X64Relocation>, cell_width: CellWidth) {
+    match cell_width {
+        CellWidth::U8 => dynasm! { code
+            ; .arch x64
+            ; cmp BYTE [r12 + r13], 0
+        },
+        CellWidth::U16 => dynasm! { code
+            ; .arch x64
+            ; cmp WORD [r12 + r13], 0
+        },
+        CellWidth::U32 => dynasm! { code
+            ; .arch x64
+            ; cmp DWORD [r12 + r13], 0
+        },
+    }
+}
+
+/// Emit a call to `trap`, and return the error it creates.
+fn emit_trap(
+    code: &mut VecAssembler<X64Relocation>,
+    trap: extern "sysv64" fn(usize) -> *mut RuntimeError,
+    position: usize,
+) {
+    dynasm! { code
+        ; .arch x64
+        ; mov rdi, QWORD position as i64
+        ; mov rax, QWORD trap as *const () as i64
+        ; call rax
+        ; jmp ->exit
+    }
+}
+
+/// Emit code that consumes a step of the `Budget` of the program, and exits
+/// with its error when there is none left.
+fn emit_step(code: &mut VecAssembler<X64Relocation>) {
+    dynasm! { code
+        ; .arch x64
+        ; sub QWORD [r15 + BUDGET_OFFSET], 1
+        ; jnc >ok
+        ; lea rdi, [r15 + BUDGET_OFFSET]
+        ; mov rax, QWORD bf_core::refill_budget as *const () as i64
+        ; call rax
+        ; cmp rax, 0
+        ; jne ->exit
+        ; ok:
+    }
+}
+
+/// Emit a call to `bf_core::grow_tape`, to make the byte at index `rsi`
+/// valid.
+fn emit_grow(code: &mut VecAssembler<X64Relocation>) {
+    dynasm! { code
+        ; .arch x64
+        ; mov rdi, [r15] // context.memory
+        ; mov rax, QWORD bf_core::grow_tape as *const () as i64
+        ; call rax
+        ; mov r12, rax
+        ; mov r14, rdx
+    }
+}
+
+/// The offset of `Context::budget`, and of the steps left at its start.
+const BUDGET_OFFSET: i32 = std::mem::offset_of!(Context<'static>, budget) as i32;
+
+/// The state of a running program, that the generated code passes to the
+/// callbacks.
+#[repr(C)]
+struct Context<'a> {
+    /// Read by the generated code to grow the tape, so it must be the first
+    /// field.
+    memory: *mut Vec<u8>,
+    /// Its steps are decremented by the generated code on each back-edge.
+    budget: Budget,
+    input: &'a mut dyn Read,
+    output: Output<&'a mut dyn Write>,
+    eof: Eof,
+}
+
+unsafe extern "sysv64" fn write(context: *mut Context, value: u8) -> *mut RuntimeError {
+    let context = &mut *context;
+
+    // Writing a non-UTF-8 byte sequence on Windows error out.
+    if cfg!(target_os = "windows") && value >= 128 {
+        return std::ptr::null_mut();
+    }
+
+    match context.output.write(value) {
+        Err(err) => Box::into_raw(Box::new(err.into())),
+        _ => std::ptr::null_mut(),
+    }
+}
+
+/// Read a byte into `cell`, that may be unaligned. On the end of the input,
+/// `context.eof` is applied to the cell instead.
+unsafe extern "sysv64" fn read<T: Cell>(context: *mut Context, cell: *mut T) -> *mut RuntimeError {
+    let context = &mut *context;
+    if let Err(err) = context.output.flush() {
+        return Box::into_raw(Box::new(err.into()));
+    }
+
+    loop {
+        let mut value = 0;
+        let err = context.input.read_exact(std::slice::from_mut(&mut value));
+
+        if let Err(err) = err {
+            if err.kind() != std::io::ErrorKind::UnexpectedEof {
+                return Box::into_raw(Box::new(err.into()));
+            }
+            let mut cell_value = cell.read_unaligned();
+            context.eof.apply(&mut cell_value);
+            cell.write_unaligned(cell_value);
+            return std::ptr::null_mut();
+        }
+
+        // ignore CR from Window's CRLF
+        if cfg!(target_os = "windows") && value == b'\r' {
+            continue;
+        }
+
+        cell.write_unaligned(T::from_byte(value));
+
+        return std::ptr::null_mut();
+    }
+}
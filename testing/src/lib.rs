@@ -0,0 +1,319 @@
+//! Helpers shared by the tests of the backends, that compare their output and
+//! their errors with the ones of the simple `interpreter`, that runs the
+//! unoptimized instructions.
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bf_core::{Boundary, Cell, CellWidth, Config, Eof, RuntimeError, SourceLocation};
+
+/// The output of a program, and the message of the error that stopped it, if
+/// any.
+pub type Outcome = (Vec<u8>, Option<String>);
+
+/// A backend under test.
+pub trait Backend {
+    /// If the tape of `Boundary::Guard` is a `GuardedTape`, that is rounded up
+    /// to whole pages, and whose faults are reported as `TapeOutOfBounds`,
+    /// without a position.
+    const GUARDED: bool = false;
+
+    /// Run `source` with `input`, and return its output and its error.
+    fn run(&self, source: &[u8], config: &Config, input: &[u8]) -> Outcome;
+
+    /// Return the message of `err`, as the backend reports it. By default, it
+    /// is its `Debug` format.
+    fn message(&self, _source: &[u8], err: RuntimeError) -> String {
+        format!("{:?}", err)
+    }
+}
+
+/// Run `source` with the simple interpreter, and return its output and its
+/// result.
+pub fn run_interpreter(
+    source: &[u8],
+    config: &Config,
+    input: &[u8],
+) -> (Vec<u8>, Result<(), RuntimeError>) {
+    fn run<T: Cell>(
+        source: &[u8],
+        config: &Config,
+        input: &[u8],
+    ) -> (Vec<u8>, Result<(), RuntimeError>) {
+        let mut output = Vec::new();
+        let result = bf_interpreter::Program::<T>::new(source, config)
+            .unwrap()
+            .run_with_io(input, &mut output);
+        (output, result)
+    }
+    match config.cell_width {
+        CellWidth::U8 => run::<u8>(source, config, input),
+        CellWidth::U16 => run::<u16>(source, config, input),
+        CellWidth::U32 => run::<u32>(source, config, input),
+    }
+}
+
+/// Return the configuration the interpreter runs with, to compare it with
+/// `backend` running with `config`.
+fn reference<B: Backend>(config: &Config) -> Config {
+    match B::GUARDED {
+        true => bf_core::round_to_pages(config),
+        false => config.clone(),
+    }
+}
+
+/// Run `source` with `backend` and with the interpreter, and check that they
+/// have the same output and the same error.
+pub fn compare<B: Backend>(backend: &B, source: &[u8], config: &Config, input: &[u8]) {
+    let (output, result) = run_interpreter(source, &reference::<B>(config), input);
+    let expected = (output, result.err().map(|err| backend.message(source, err)));
+    let mut actual = backend.run(source, config, input);
+    if B::GUARDED && config.boundary == Boundary::Guard && expected.1.is_some() {
+        assert_eq!(actual.1.as_deref(), Some("TapeOutOfBounds"));
+        actual.1.clone_from(&expected.1);
+    }
+    assert_eq!(
+        actual,
+        expected,
+        "{} with {:?}",
+        String::from_utf8_lossy(source),
+        config
+    );
+}
+
+/// The example programs of the repository, with their name and an input.
+pub const PROGRAMS: [(&str, &[u8], &[u8]); 3] = [
+    ("1-to-5", include_bytes!("../../programs/1-to-5.bf"), b""),
+    (
+        "cat",
+        include_bytes!("../../programs/cat.bf"),
+        b"hello\nworld\n",
+    ),
+    (
+        "factor",
+        include_bytes!("../../programs/factor.bf"),
+        b"123456\n",
+    ),
+];
+
+/// Compare `backend` with the interpreter on the example programs, with each
+/// cell width.
+pub fn programs<B: Backend>(backend: &B) {
+    for (name, source, input) in PROGRAMS {
+        for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+            // the interpreter takes too long to wrap around 32-bit cells
+            if name == "factor" && cell_width == CellWidth::U32 {
+                continue;
+            }
+            let config = Config {
+                cell_width,
+                ..Config::default()
+            };
+            compare(backend, source, &config, input);
+        }
+    }
+}
+
+/// The configurations with `boundaries` and each cell width, on tapes small
+/// enough for the programs to leave them often, and with `prefix_steps` set
+/// to run a part of the programs at compile time, all of them, or none.
+pub fn configs(boundaries: &[Boundary]) -> Vec<Config> {
+    let mut configs = Vec::new();
+    for tape_size in 3..=7 {
+        for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+            for &boundary in boundaries {
+                for prefix_steps in [0, 7, 1 << 20] {
+                    configs.push(Config {
+                        tape_size,
+                        cell_width,
+                        boundary,
+                        prefix_steps,
+                        ..Config::default()
+                    });
+                }
+            }
+        }
+    }
+    configs
+}
+
+/// A xorshift generator, with a fixed seed so that the tests are
+/// reproducible.
+pub struct Random(u64);
+impl Default for Random {
+    fn default() -> Random {
+        Random(0x2545_f491_4f6c_dd1d)
+    }
+}
+impl Random {
+    /// Return a number below `n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+
+    /// Return one of `items`.
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len() as u64) as usize]
+    }
+
+    /// Return a program with balanced brackets, of up to 60 instructions.
+    pub fn program(&mut self) -> Vec<u8> {
+        let mut source = Vec::new();
+        let mut depth = 0;
+        for _ in 0..self.below(60) {
+            // the loops replaced by the optimizer, sometimes
+            if self.below(10) == 0 {
+                let loops: [&[u8]; 6] = [
+                    b"[-]",
+                    b"[->+<]",
+                    b"[-<<+>>]",
+                    b"[-<+>>-<]",
+                    b"[<]",
+                    b"[>>]",
+                ];
+                let snippet = *self.choose(&loops);
+                source.extend_from_slice(snippet);
+                continue;
+            }
+            let c = *self.choose(b"+-<>.,[]");
+            match c {
+                b'[' => depth += 1,
+                b']' if depth == 0 => continue,
+                b']' => depth -= 1,
+                _ => {}
+            }
+            source.push(c);
+        }
+        source.extend(std::iter::repeat_n(b']', depth));
+        source
+    }
+}
+
+/// Compare `backend` with the interpreter on `count` random programs, that end
+/// or fail in a limited number of steps, each one with a configuration
+/// returned by `config`.
+pub fn random<B: Backend>(
+    backend: &B,
+    count: usize,
+    mut config: impl FnMut(&mut Random) -> Config,
+) {
+    let mut random = Random::default();
+    let mut tested = 0;
+    while tested < count {
+        let source = random.program();
+        let config = config(&mut random);
+        let input = b"\x03\x01\x04\x01\x05";
+
+        // skip the programs that don't end
+        let fuel = Config {
+            fuel: Some(100_000),
+            ..reference::<B>(&config)
+        };
+        let (_, result) = run_interpreter(&source, &fuel, input);
+        if matches!(result, Err(RuntimeError::OutOfFuel)) {
+            continue;
+        }
+
+        compare(backend, &source, &config, input);
+        tested += 1;
+    }
+}
+
+/// Return the message that the compiled programs print for `err`, with the
+/// location in `source` of the instruction that caused it if `location`.
+pub fn message(source: &[u8], err: RuntimeError, location: bool) -> String {
+    let (message, position) = match err {
+        RuntimeError::PointerUnderflow(position) => ("pointer moved left of cell 0", position),
+        RuntimeError::PointerOverflow(position) => {
+            ("pointer moved right of the last cell", position)
+        }
+        err => panic!("unexpected {:?}", err),
+    };
+    if !location {
+        return format!("error: {}\n", message);
+    }
+    let location = SourceLocation::new(source, position);
+    format!(
+        "error: {} at {}:{}\n",
+        message, location.line, location.column
+    )
+}
+
+/// Return the command line options of the tape, the cells, the end of input
+/// and the prefix of `config`.
+pub fn args(config: &Config) -> Vec<String> {
+    let cell_width = match config.cell_width {
+        CellWidth::U8 => "8",
+        CellWidth::U16 => "16",
+        CellWidth::U32 => "32",
+    };
+    let boundary = match config.boundary {
+        Boundary::Wrap => "wrap",
+        Boundary::Error => "error",
+        Boundary::Grow => "grow",
+        Boundary::Guard => "guard",
+    };
+    let eof = match config.eof {
+        Eof::Zero => "zero",
+        Eof::MinusOne => "minus-one",
+        Eof::Unchanged => "unchanged",
+    };
+    [
+        "--tape-size",
+        &config.tape_size.to_string(),
+        "--cell-width",
+        cell_width,
+        "--boundary",
+        boundary,
+        "--eof",
+        eof,
+        "--prefix-steps",
+        &config.prefix_steps.to_string(),
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect()
+}
+
+/// Return a new path named after `name`, in a temporary directory of the test
+/// process.
+pub fn temp_path(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("bf-testing-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(format!(
+        "{}-{}",
+        COUNT.fetch_add(1, Ordering::Relaxed),
+        name
+    ))
+}
+
+/// Run `command` with `input`, and return its output and the error it printed,
+/// if it exited with 5, the exit code of the runtime errors.
+pub fn run_command(command: &mut Command, input: &[u8]) -> Outcome {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // the program may end without reading all its input
+    let _ = child.stdin.take().unwrap().write_all(input);
+    let output = child.wait_with_output().unwrap();
+
+    let error = match output.status.code() {
+        Some(0) => None,
+        Some(5) => Some(String::from_utf8(output.stderr).unwrap()),
+        code => panic!(
+            "unexpected exit code {:?}: {}",
+            code,
+            String::from_utf8_lossy(&output.stderr)
+        ),
+    };
+    (output.stdout, error)
+}
@@ -0,0 +1,509 @@
+//! Compile brainfuck programs to WebAssembly modules, that can run in browsers
+//! and other wasm runtimes.
+//!
+//! The tape is stored in the linear memory of the module, which imports the
+//! functions that do the IO and report the errors from the `env` module:
+//!
+//! - `bf_write(value: i32)`: write the byte `value`.
+//! - `bf_read() -> i32`: read a byte, or return -1 at the end of the input.
+//! - `bf_pointer_underflow(position: i32)` and
+//!   `bf_pointer_overflow(position: i32)`: the pointer moved out of the tape,
+//!   by the instruction at the byte `position` of the source. The program
+//!   stops when they return.
+//!
+//! The module exports its `memory`, and the function `run`, that runs the
+//! program. It can only be called once, since the program starts from the
+//! tape left by the part of it run at compile time. With `Boundary::Grow` it
+//! traps if the memory can't grow.
+
+use bf_core::{Boundary, CellWidth, Config, Eof, Instruction, Prefix, UnbalancedBrackets};
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, DataSection, EntityType, ExportKind, ExportSection,
+    Function, FunctionSection, ImportSection, Instruction as Wasm, MemArg, MemorySection,
+    MemoryType, Module, TypeSection, ValType,
+};
+
+/// The size of the pages of the linear memory.
+const PAGE_SIZE: u32 = 1 << 16;
+
+/// The largest tape supported, in bytes, so that the index of any cell near
+/// it fits in a `i32`.
+pub const MAX_TAPE_SIZE: usize = 1 << 30;
+
+// the imported functions, followed by `run`.
+const WRITE: u32 = 0;
+const READ: u32 = 1;
+const POINTER_UNDERFLOW: u32 = 2;
+const POINTER_OVERFLOW: u32 = 3;
+const RUN: u32 = 4;
+
+// the locals of `run`.
+/// The index of the current cell, in bytes from the start of the tape.
+const POINTER: u32 = 0;
+/// The length of the tape in bytes, when it can grow.
+const LEN: u32 = 1;
+/// If the loops that contain the instruction where the program resumes are
+/// being entered for the first time, see `compile`.
+const RESUMING: u32 = 2;
+const TMP: u32 = 3;
+const TMP2: u32 = 4;
+
+/// Compile the program to a WebAssembly module.
+///
+/// The program can't have a `Budget`, and `Boundary::Guard` is not supported.
+/// The tape must not be larger than `MAX_TAPE_SIZE` bytes.
+pub fn compile(source: &[u8], config: &Config) -> Result<Vec<u8>, UnbalancedBrackets> {
+    assert!(config.fuel.is_none() && config.timeout.is_none() && !config.cancellable);
+    assert!(config.boundary != Boundary::Guard);
+    assert!(config.tape_size * config.cell_width.bytes() <= MAX_TAPE_SIZE);
+
+    let ir = bf_core::parse(source)?;
+    let (ir, _) = bf_core::optimize(&ir, config);
+    let prefix = Prefix::evaluate(&ir, config);
+    let in_bounds = bf_core::in_bounds(&ir, config);
+
+    // the output of the prefix is stored at the start of the memory, followed
+    // by the tape, aligned for the cells.
+    let tape_start = (prefix.output.len() as u32 + 3) & !3;
+    let mut codegen = Codegen::new(config, tape_start);
+
+    codegen.write_prefix_output(prefix.output.len() as u32);
+
+    let bytes = config.cell_width.bytes();
+    codegen.ins(Wasm::I32Const((prefix.pointer * bytes) as i32));
+    codegen.ins(Wasm::LocalSet(POINTER));
+    if config.boundary == Boundary::Grow {
+        codegen.set_len();
+    }
+
+    // The instruction at `resume` may be inside loops, which structured
+    // control flow can't jump into. Instead, these loops are entered without
+    // checking the current cell, and the instructions before `resume` in them
+    // are skipped while `RESUMING` is set. The instructions outside of them
+    // are never run again, and are not compiled.
+    let mut enclosing = Vec::new();
+    for (index, instr) in ir.instructions[..prefix.resume].iter().enumerate() {
+        match instr {
+            Instruction::JumpRight(_) => enclosing.push(index),
+            Instruction::JumpLeft(_) => {
+                enclosing.pop();
+            }
+            _ => {}
+        }
+    }
+    let first = enclosing.first().copied().unwrap_or(prefix.resume);
+    if !enclosing.is_empty() {
+        codegen.ins(Wasm::I32Const(1));
+        codegen.ins(Wasm::LocalSet(RESUMING));
+    }
+
+    let instructions = ir.instructions.iter().zip(&ir.positions).zip(&in_bounds);
+    for (index, ((&instr, &position), &in_bounds)) in instructions.enumerate().skip(first) {
+        if index == prefix.resume && !enclosing.is_empty() {
+            // end the skipped instructions of the innermost loop
+            codegen.ins(Wasm::End);
+            codegen.ins(Wasm::I32Const(0));
+            codegen.ins(Wasm::LocalSet(RESUMING));
+        }
+
+        if index < prefix.resume && enclosing.contains(&index) {
+            if index != first {
+                codegen.ins(Wasm::End);
+            }
+            codegen.ins(Wasm::Block(BlockType::Empty));
+            codegen.ins(Wasm::LocalGet(RESUMING));
+            codegen.ins(Wasm::I32Eqz);
+            codegen.ins(Wasm::If(BlockType::Empty));
+            codegen.exit_if_zero(1);
+            codegen.ins(Wasm::End);
+            codegen.ins(Wasm::Loop(BlockType::Empty));
+
+            codegen.ins(Wasm::LocalGet(RESUMING));
+            codegen.ins(Wasm::I32Eqz);
+            codegen.ins(Wasm::If(BlockType::Empty));
+            continue;
+        }
+
+        codegen.instruction(instr, position, in_bounds);
+    }
+
+    let mut types = TypeSection::new();
+    types.function([ValType::I32], []);
+    types.function([], [ValType::I32]);
+    types.function([], []);
+
+    let mut imports = ImportSection::new();
+    let functions = [
+        ("bf_write", 0),
+        ("bf_read", 1),
+        ("bf_pointer_underflow", 0),
+        ("bf_pointer_overflow", 0),
+    ];
+    for (name, ty) in functions {
+        imports.import("env", name, EntityType::Function(ty));
+    }
+
+    let mut functions = FunctionSection::new();
+    functions.function(2);
+
+    let memory_size = tape_start as u64 + (config.tape_size * bytes) as u64;
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType {
+        minimum: memory_size.div_ceil(PAGE_SIZE as u64),
+        maximum: None,
+        memory64: false,
+        shared: false,
+    });
+
+    let mut exports = ExportSection::new();
+    exports.export("memory", ExportKind::Memory, 0);
+    exports.export("run", ExportKind::Func, RUN);
+
+    let mut code = CodeSection::new();
+    codegen.ins(Wasm::End);
+    code.function(&codegen.func);
+
+    let mut data = DataSection::new();
+    data.active(0, &ConstExpr::i32_const(0), prefix.output);
+    data.active(0, &ConstExpr::i32_const(tape_start as i32), prefix.tape);
+
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&imports)
+        .section(&functions)
+        .section(&memories)
+        .section(&exports)
+        .section(&code)
+        .section(&data);
+
+    Ok(module.finish())
+}
+
+/// The body of `run`, being generated.
+struct Codegen {
+    func: Function,
+    boundary: Boundary,
+    cell_width: CellWidth,
+    eof: Eof,
+    /// The address of the first cell.
+    tape_start: u32,
+    /// The size of the tape in cells.
+    tape_cells: isize,
+}
+impl Codegen {
+    fn new(config: &Config, tape_start: u32) -> Codegen {
+        Codegen {
+            func: Function::new([(5, ValType::I32)]),
+            boundary: config.boundary,
+            cell_width: config.cell_width,
+            eof: config.eof,
+            tape_start,
+            tape_cells: config.tape_size as isize,
+        }
+    }
+
+    fn ins(&mut self, instruction: Wasm) {
+        self.func.instruction(&instruction);
+    }
+
+    fn bytes(&self) -> i32 {
+        self.cell_width.bytes() as i32
+    }
+
+    /// The size of the tape in bytes.
+    fn tape_size(&self) -> i32 {
+        self.tape_cells as i32 * self.bytes()
+    }
+
+    fn instruction(&mut self, instr: Instruction, position: usize, in_bounds: bool) {
+        match instr {
+            Instruction::Add(offset, n) => {
+                let mem_arg = self.cell(offset, position, in_bounds);
+                self.ins(Wasm::LocalTee(TMP2));
+                self.ins(Wasm::LocalGet(TMP2));
+                self.load(mem_arg);
+                self.ins(Wasm::I32Const(n as i32));
+                self.ins(Wasm::I32Add);
+                self.store(mem_arg);
+            }
+            Instruction::Move(n) => {
+                self.offset_pointer(n, position, in_bounds);
+                self.ins(Wasm::LocalSet(POINTER));
+            }
+            Instruction::Output(offset) => {
+                // the lower byte of the little-endian cell
+                let mem_arg = self.cell(offset, position, in_bounds);
+                self.ins(Wasm::I32Load8U(MemArg {
+                    align: 0,
+                    ..mem_arg
+                }));
+                self.ins(Wasm::Call(WRITE));
+            }
+            Instruction::Input(offset) => {
+                let mem_arg = self.cell(offset, position, in_bounds);
+                self.ins(Wasm::LocalSet(TMP2));
+
+                self.ins(Wasm::Call(READ));
+                self.ins(Wasm::LocalTee(TMP));
+                self.ins(Wasm::I32Const(-1));
+                let eof = match self.eof {
+                    Eof::Zero => Some(0),
+                    Eof::MinusOne => Some(-1),
+                    Eof::Unchanged => None,
+                };
+                match eof {
+                    Some(value) => {
+                        self.ins(Wasm::I32Eq);
+                        self.ins(Wasm::If(BlockType::Empty));
+                        self.ins(Wasm::LocalGet(TMP2));
+                        self.ins(Wasm::I32Const(value));
+                        self.store(mem_arg);
+                        self.ins(Wasm::Else);
+                    }
+                    None => {
+                        self.ins(Wasm::I32Ne);
+                        self.ins(Wasm::If(BlockType::Empty));
+                    }
+                }
+                self.ins(Wasm::LocalGet(TMP2));
+                self.ins(Wasm::LocalGet(TMP));
+                self.store(mem_arg);
+                self.ins(Wasm::End);
+            }
+            Instruction::JumpRight(_) => {
+                self.ins(Wasm::Block(BlockType::Empty));
+                self.exit_if_zero(0);
+                self.ins(Wasm::Loop(BlockType::Empty));
+            }
+            Instruction::JumpLeft(_) => {
+                self.current_cell();
+                self.ins(Wasm::BrIf(0));
+                self.ins(Wasm::End);
+                self.ins(Wasm::End);
+            }
+            Instruction::Set(offset, n) => {
+                let mem_arg = self.cell(offset, position, in_bounds);
+                self.ins(Wasm::I32Const(n as i32));
+                self.store(mem_arg);
+            }
+            Instruction::Clear(offset) => {
+                let mem_arg = self.cell(offset, position, in_bounds);
+                self.ins(Wasm::I32Const(0));
+                self.store(mem_arg);
+            }
+            Instruction::MulAdd { offset, factor } => {
+                // the loop replaced by the instruction doesn't access the
+                // cell at `offset` if the current cell is zero, and it may
+                // be out of the tape.
+                if !in_bounds {
+                    self.current_cell();
+                    self.ins(Wasm::If(BlockType::Empty));
+                }
+                let mem_arg = self.cell(offset, position, in_bounds);
+                self.ins(Wasm::LocalTee(TMP2));
+                self.ins(Wasm::LocalGet(TMP2));
+                self.load(mem_arg);
+                self.current_cell();
+                self.ins(Wasm::I32Const(factor as i32));
+                self.ins(Wasm::I32Mul);
+                self.ins(Wasm::I32Add);
+                self.store(mem_arg);
+                if !in_bounds {
+                    self.ins(Wasm::End);
+                }
+            }
+            Instruction::MoveUntil(n) => {
+                self.ins(Wasm::Block(BlockType::Empty));
+                self.ins(Wasm::Loop(BlockType::Empty));
+                self.exit_if_zero(1);
+                self.offset_pointer(n, position, false);
+                self.ins(Wasm::LocalSet(POINTER));
+                self.ins(Wasm::Br(0));
+                self.ins(Wasm::End);
+                self.ins(Wasm::End);
+            }
+            Instruction::Check(offset) => {
+                if !in_bounds {
+                    self.offset_pointer(offset, position, in_bounds);
+                    self.ins(Wasm::Drop);
+                }
+            }
+        }
+    }
+
+    /// Write the `len` bytes at the start of the memory.
+    fn write_prefix_output(&mut self, len: u32) {
+        if len == 0 {
+            return;
+        }
+        self.ins(Wasm::Loop(BlockType::Empty));
+        self.ins(Wasm::LocalGet(TMP));
+        self.ins(Wasm::I32Load8U(mem_arg(0, 1)));
+        self.ins(Wasm::Call(WRITE));
+        self.ins(Wasm::LocalGet(TMP));
+        self.ins(Wasm::I32Const(1));
+        self.ins(Wasm::I32Add);
+        self.ins(Wasm::LocalTee(TMP));
+        self.ins(Wasm::I32Const(len as i32));
+        self.ins(Wasm::I32LtU);
+        self.ins(Wasm::BrIf(0));
+        self.ins(Wasm::End);
+    }
+
+    /// Set `LEN` to the length of the memory after the start of the tape.
+    fn set_len(&mut self) {
+        self.ins(Wasm::MemorySize(0));
+        self.ins(Wasm::I32Const(PAGE_SIZE.trailing_zeros() as i32));
+        self.ins(Wasm::I32Shl);
+        self.ins(Wasm::I32Const(self.tape_start as i32));
+        self.ins(Wasm::I32Sub);
+        self.ins(Wasm::LocalSet(LEN));
+    }
+
+    /// Push the value of the current cell.
+    fn current_cell(&mut self) {
+        self.ins(Wasm::LocalGet(POINTER));
+        self.load(mem_arg(self.tape_start, self.bytes()));
+    }
+
+    /// Exit the block at `depth` if the current cell is zero.
+    fn exit_if_zero(&mut self, depth: u32) {
+        self.current_cell();
+        self.ins(Wasm::I32Eqz);
+        self.ins(Wasm::BrIf(depth));
+    }
+
+    fn load(&mut self, mem_arg: MemArg) {
+        self.ins(match self.cell_width {
+            CellWidth::U8 => Wasm::I32Load8U(mem_arg),
+            CellWidth::U16 => Wasm::I32Load16U(mem_arg),
+            CellWidth::U32 => Wasm::I32Load(mem_arg),
+        });
+    }
+
+    fn store(&mut self, mem_arg: MemArg) {
+        self.ins(match self.cell_width {
+            CellWidth::U8 => Wasm::I32Store8(mem_arg),
+            CellWidth::U16 => Wasm::I32Store16(mem_arg),
+            CellWidth::U32 => Wasm::I32Store(mem_arg),
+        });
+    }
+
+    /// Push the index of the cell at `offset` from the pointer, and return the
+    /// `MemArg` to load or store it.
+    fn cell(&mut self, offset: isize, position: usize, in_bounds: bool) -> MemArg {
+        let offset_bytes = offset as i32 * self.bytes();
+        if in_bounds && offset_bytes >= 0 {
+            // add the offset in the instruction
+            self.ins(Wasm::LocalGet(POINTER));
+            return mem_arg(self.tape_start + offset_bytes as u32, self.bytes());
+        }
+        self.offset_pointer(offset, position, in_bounds);
+        mem_arg(self.tape_start, self.bytes())
+    }
+
+    /// Push the index of the cell at `n` cells from the pointer, in bytes,
+    /// handling moves out of the tape according to `self.boundary`, unless
+    /// they are known to be `in_bounds`.
+    fn offset_pointer(&mut self, n: isize, position: usize, in_bounds: bool) {
+        let bytes = self.bytes();
+        let tape_size = self.tape_size();
+
+        self.ins(Wasm::LocalGet(POINTER));
+        if n == 0 {
+            return;
+        }
+        if in_bounds {
+            self.ins(Wasm::I32Const(n as i32 * bytes));
+            self.ins(Wasm::I32Add);
+            return;
+        }
+
+        if self.boundary == Boundary::Wrap {
+            let n = n.rem_euclid(self.tape_cells) as i32 * bytes;
+            if n == 0 {
+                return;
+            }
+            // select(to, to - tape_size, to < tape_size)
+            self.ins(Wasm::I32Const(n));
+            self.ins(Wasm::I32Add);
+            self.ins(Wasm::LocalTee(TMP));
+            self.ins(Wasm::LocalGet(TMP));
+            self.ins(Wasm::I32Const(tape_size));
+            self.ins(Wasm::I32Sub);
+            self.ins(Wasm::LocalGet(TMP));
+            self.ins(Wasm::I32Const(tape_size));
+            self.ins(Wasm::I32LtU);
+            self.ins(Wasm::Select);
+            return;
+        }
+
+        self.ins(Wasm::I32Const(n as i32 * bytes));
+        self.ins(Wasm::I32Add);
+        self.ins(Wasm::LocalTee(TMP));
+        if n < 0 {
+            self.ins(Wasm::I32Const(0));
+            self.ins(Wasm::I32LtS);
+            self.ins(Wasm::If(BlockType::Empty));
+            self.trap(POINTER_UNDERFLOW, position);
+        } else if self.boundary == Boundary::Error {
+            self.ins(Wasm::I32Const(tape_size));
+            self.ins(Wasm::I32GeU);
+            self.ins(Wasm::If(BlockType::Empty));
+            self.trap(POINTER_OVERFLOW, position);
+        } else {
+            self.ins(Wasm::LocalGet(LEN));
+            self.ins(Wasm::I32GeU);
+            self.ins(Wasm::If(BlockType::Empty));
+            self.grow();
+        }
+        self.ins(Wasm::End);
+        self.ins(Wasm::LocalGet(TMP));
+    }
+
+    /// Call the import `function` with `position`, and stop the program.
+    fn trap(&mut self, function: u32, position: usize) {
+        self.ins(Wasm::I32Const(position as i32));
+        self.ins(Wasm::Call(function));
+        self.ins(Wasm::Return);
+    }
+
+    /// Grow the memory until the cell at `TMP` is in it, at least doubling it.
+    fn grow(&mut self) {
+        // the pages needed, minus the current ones
+        self.ins(Wasm::LocalGet(TMP));
+        let end = self.tape_start as i32 + self.bytes() + PAGE_SIZE as i32 - 1;
+        self.ins(Wasm::I32Const(end));
+        self.ins(Wasm::I32Add);
+        self.ins(Wasm::I32Const(PAGE_SIZE.trailing_zeros() as i32));
+        self.ins(Wasm::I32ShrU);
+        self.ins(Wasm::MemorySize(0));
+        self.ins(Wasm::I32Sub);
+        self.ins(Wasm::LocalTee(TMP2));
+
+        // select(needed, current, needed > current)
+        self.ins(Wasm::MemorySize(0));
+        self.ins(Wasm::LocalGet(TMP2));
+        self.ins(Wasm::MemorySize(0));
+        self.ins(Wasm::I32GtU);
+        self.ins(Wasm::Select);
+
+        self.ins(Wasm::MemoryGrow(0));
+        self.ins(Wasm::I32Const(-1));
+        self.ins(Wasm::I32Eq);
+        self.ins(Wasm::If(BlockType::Empty));
+        self.ins(Wasm::Unreachable);
+        self.ins(Wasm::End);
+
+        self.set_len();
+    }
+}
+
+fn mem_arg(offset: u32, bytes: i32) -> MemArg {
+    MemArg {
+        offset: offset as u64,
+        align: bytes.trailing_zeros(),
+        memory_index: 0,
+    }
+}
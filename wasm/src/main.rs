@@ -0,0 +1,82 @@
+use std::path::Path;
+use std::process::ExitCode;
+
+use bf_core::{Boundary, Config};
+
+fn main() -> ExitCode {
+    let (config, args) = match Config::from_args(std::env::args().skip(1)) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::from(1);
+        }
+    };
+    let mut args = args.into_iter();
+
+    let mut output = None;
+    let mut file_name = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => match args.next() {
+                Some(path) => output = Some(path),
+                None => {
+                    eprintln!("expected a path after {}", arg);
+                    return ExitCode::from(1);
+                }
+            },
+            _ => file_name = Some(arg),
+        }
+    }
+
+    let file_name = match file_name {
+        Some(x) => x,
+        None => {
+            eprintln!("expected a file path as argument");
+            return ExitCode::from(1);
+        }
+    };
+
+    if config.fuel.is_some() || config.timeout.is_some() {
+        eprintln!("--fuel and --timeout are not supported by WebAssembly modules");
+        return ExitCode::from(1);
+    }
+    if config.boundary == Boundary::Guard {
+        eprintln!("--boundary guard is not supported by WebAssembly modules");
+        return ExitCode::from(1);
+    }
+    if config.tape_size * config.cell_width.bytes() > bf_wasm::MAX_TAPE_SIZE {
+        eprintln!(
+            "the tape can't be larger than {} bytes in a WebAssembly module",
+            bf_wasm::MAX_TAPE_SIZE
+        );
+        return ExitCode::from(1);
+    }
+
+    let source = match std::fs::read(&file_name) {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("Error reading '{}': {}", file_name, err);
+            return ExitCode::from(2);
+        }
+    };
+
+    let module = match bf_wasm::compile(&source, &config) {
+        Ok(x) => x,
+        Err(err) => {
+            eprint!("{}", err.report(&file_name, &source));
+            return ExitCode::from(3);
+        }
+    };
+
+    // default to the file name with the `.wasm` extension
+    let output = output.unwrap_or_else(|| {
+        let path = Path::new(&file_name).with_extension("wasm");
+        path.to_string_lossy().into_owned()
+    });
+    if let Err(err) = std::fs::write(&output, module) {
+        eprintln!("error writing '{}': {}", output, err);
+        return ExitCode::from(4);
+    }
+
+    ExitCode::from(0)
+}
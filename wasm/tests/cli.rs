@@ -0,0 +1,19 @@
+//! The errors of the command line.
+
+use std::process::Command;
+
+#[test]
+fn missing_path() {
+    for option in ["-o", "--output"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_bf-wasm"))
+            .arg("program.bf")
+            .arg(option)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap(),
+            format!("expected a path after {}\n", option)
+        );
+    }
+}
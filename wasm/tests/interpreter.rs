@@ -0,0 +1,165 @@
+//! Run the compiled modules with wasmi, and compare their output and their
+//! errors with the ones of the simple `interpreter`.
+
+use bf_core::{Boundary, CellWidth, Config, Eof, RuntimeError};
+use bf_testing::{Backend, Outcome};
+use wasmi::{Caller, Engine, Linker, Module, Store};
+
+/// The state of the host of a module.
+#[derive(Default)]
+struct Host {
+    input: Vec<u8>,
+    read: usize,
+    output: Vec<u8>,
+    error: Option<String>,
+}
+
+/// The modules, run with wasmi.
+struct Wasm;
+impl Backend for Wasm {
+    fn run(&self, source: &[u8], config: &Config, input: &[u8]) -> Outcome {
+        let wasm = bf_wasm::compile(source, config).unwrap();
+
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm[..]).unwrap();
+        let host = Host {
+            input: input.to_vec(),
+            ..Host::default()
+        };
+        let mut store = Store::new(&engine, host);
+
+        let mut linker = <Linker<Host>>::new(&engine);
+        linker
+            .func_wrap("env", "bf_write", |mut caller: Caller<Host>, value: i32| {
+                caller.data_mut().output.push(value as u8);
+            })
+            .unwrap();
+        linker
+            .func_wrap("env", "bf_read", |mut caller: Caller<Host>| -> i32 {
+                let host = caller.data_mut();
+                match host.input.get(host.read) {
+                    Some(&value) => {
+                        host.read += 1;
+                        value as i32
+                    }
+                    None => -1,
+                }
+            })
+            .unwrap();
+        let traps = [
+            (
+                "bf_pointer_underflow",
+                RuntimeError::PointerUnderflow as fn(_) -> _,
+            ),
+            ("bf_pointer_overflow", RuntimeError::PointerOverflow),
+        ];
+        for (name, error) in traps {
+            linker
+                .func_wrap(
+                    "env",
+                    name,
+                    move |mut caller: Caller<Host>, position: i32| {
+                        caller.data_mut().error = Some(format!("{:?}", error(position as usize)));
+                    },
+                )
+                .unwrap();
+        }
+
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        let run = instance.get_typed_func::<(), ()>(&store, "run").unwrap();
+        run.call(&mut store, ()).unwrap();
+
+        let host = store.into_data();
+        (host.output, host.error)
+    }
+}
+
+/// The configurations of the programs below, with `prefix_steps` set to run a
+/// part of the program at compile time, all of it, or none.
+fn configs() -> Vec<Config> {
+    let mut configs = Vec::new();
+    for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+        for boundary in [Boundary::Wrap, Boundary::Error, Boundary::Grow] {
+            for prefix_steps in [0, 7, 1 << 20] {
+                configs.push(Config {
+                    tape_size: 16,
+                    cell_width,
+                    boundary,
+                    prefix_steps,
+                    ..Config::default()
+                });
+            }
+        }
+    }
+    configs
+}
+
+#[test]
+fn programs() {
+    bf_testing::programs(&Wasm);
+}
+
+#[test]
+fn eof() {
+    for config in configs() {
+        for eof in [Eof::Zero, Eof::MinusOne, Eof::Unchanged] {
+            // the interpreter takes too long to move a 32-bit -1 with a loop
+            if eof == Eof::MinusOne && config.cell_width == CellWidth::U32 {
+                continue;
+            }
+            let config = Config {
+                eof,
+                ..config.clone()
+            };
+            bf_testing::compare(&Wasm, b"+++>,.<,.>,[-<+>]<.", &config, b"a");
+        }
+    }
+}
+
+#[test]
+fn boundaries() {
+    let programs: [&[u8]; 8] = [
+        b"<+.",
+        b">>>>>>>>>>>>>,[->>>>+<<<<]+++++++[>+++++++++<-]>++.",
+        b"+>+>+[<]>.",
+        b"+>+>+<<[>]<.",
+        b">+[-<<+>>]<<.",
+        b">>>>>>>>>>>>>>>>>>>>+[-<+>]<.",
+        b"++++++++[>+++++++++<-]>[>>>>>>>>>>>>>>>>>>>]<<<<<<<<<<.",
+        b"+++[->>>>>>>>>>>>>>>>>>>>+<<<<<<<<<<<<<<<<<<<<]>>>>>>>>>>>>>>>>>>>>[<]>.",
+    ];
+    for config in configs() {
+        for source in programs {
+            bf_testing::compare(&Wasm, source, &config, b"");
+        }
+    }
+}
+
+/// Programs that read their input inside loops, where the code resumes after
+/// the part run at compile time.
+#[test]
+fn resume() {
+    let programs: [&[u8]; 5] = [
+        b"++++++++[>++++++++<-]>+[.,]",
+        b"++[>+++[>,.<-]<-]",
+        b"++[>,[>+<-]>[-<+>]<.<-]",
+        b"++[>++[>+>,.<<-]>[-]<<-]>>>.",
+        b"++>+++[<[>>++<<-]>[>+<-],.>>[<+<+>>-]<<]",
+    ];
+    for config in configs() {
+        for source in programs {
+            bf_testing::compare(&Wasm, source, &config, b"abcdefghijklmnopqrstuvwxyz");
+        }
+    }
+}
+
+/// Random programs, that end or fail in a limited number of steps.
+#[test]
+fn random() {
+    let configs = bf_testing::configs(&[Boundary::Wrap, Boundary::Error, Boundary::Grow]);
+    bf_testing::random(&Wasm, 300, |random| random.choose(&configs).clone());
+}